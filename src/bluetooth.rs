@@ -1,15 +1,30 @@
 use async_std::os::unix::net::UnixStream;
 use std::os::unix::net::UnixStream as StdUnixStream;
 use std::{
-    io::{Read, Write},
-    str, time,
+    collections::BTreeMap,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+    time,
 };
 
-use crate::platform;
+use crate::{
+    coalesce::CoalescingWriter,
+    eir::Appearance,
+    framing::Framer,
+    platform,
+    sdp::{Profile, SdpRecord, SdpService, Uuid},
+};
 
 /// The bluetooth socket.
 ///
-/// Can be used with `mio::Poll`.
+/// Can be used with `mio::Poll` if the `mio` feature is enabled.
+///
+/// The socket is `Send` and `Sync`: it is the single owner of its file descriptor, which is
+/// closed when the socket is dropped. Like with `TcpStream`, `&BtSocket` can be shared between
+/// threads, e.g. to `shutdown` the connection while another thread reads. The descriptor returned
+/// by `get_fd` stays owned by the socket, `get_stream_std` and `get_stream` return duplicates.
 #[derive(Debug)]
 pub struct BtSocket(platform::BtSocket);
 
@@ -19,70 +34,594 @@ impl BtSocket {
         Ok(From::from(platform::BtSocket::new(protocol)?))
     }
 
-    /// Connect to the RFCOMM service on remote device with address `addr`. Channel will be
-    /// determined through SDP protocol.
+    /// Create an RFCOMM socket and connect it to the serial port service of the remote device
+    /// with address `addr`, like `TcpStream::connect`. The channel is determined through SDP.
     ///
     /// This function can block for some seconds.
-    pub fn connect(&mut self, addr: BtAddr) -> Result<(), BtError> {
-        // Create temporary `mio` event loop
-        let evtloop = mio::Poll::new().unwrap();
-        let token = mio::Token(0);
-        let mut events = mio::Events::with_capacity(2);
-
-        // Request a socket connection
-        let mut connect = self.0.connect(addr);
-
-        loop {
-            match connect.advance()? {
-                BtAsync::WaitFor(evented, interest) => {
-                    let mut event_received = false;
-                    while !event_received {
-                        // Register this, single, event source
-                        evtloop
-                            .register(evented, token, interest, mio::PollOpt::oneshot())
-                            .unwrap();
-
-                        // Wait for it to transition to the requested state
-                        evtloop.poll(&mut events, None).unwrap();
-
-                        for event in events.iter() {
-                            if event.token() == token {
-                                event_received = true;
-                                evtloop.deregister(evented).unwrap();
-                            }
-                        }
-                    }
-                }
+    pub fn connect_rfcomm(addr: BtAddr) -> Result<BtSocket, BtError> {
+        let mut socket = BtSocket::new(BtProtocol::RFCOMM)?;
+        socket.connect(addr)?;
+        Ok(socket)
+    }
 
-                BtAsync::Done => {
-                    return Ok(());
-                }
-            }
+    /// Like `connect_rfcomm`, but the connection is shut down and closed when the returned guard
+    /// is dropped, also on error paths.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_scoped(addr: BtAddr) -> Result<ConnectGuard, BtError> {
+        Ok(ConnectGuard(Some(BtSocket::connect_rfcomm(addr)?)))
+    }
+
+    /// Select the transport subsequent connects use, `BtTransport::BrEdr` by default.
+    ///
+    /// Fails for RFCOMM sockets with an LE transport, as RFCOMM is only available over BR/EDR.
+    pub fn set_transport(&mut self, transport: BtTransport) -> Result<(), BtError> {
+        self.0.set_transport(transport)
+    }
+
+    /// Takes ownership of an existing socket, e.g. passed in through socket activation, after
+    /// checking that `fd` is an RFCOMM stream socket.
+    ///
+    /// If the check fails, an error is returned and `fd` stays owned by the caller.
+    pub fn from_fd(fd: std::os::unix::io::RawFd) -> Result<BtSocket, BtError> {
+        Ok(BtSocket(platform::BtSocket::from_fd(fd)?))
+    }
+
+    /// Like `from_fd`, for a socket that is already connected to `channel` of the device `addr`,
+    /// e.g. handed over from another process. The socket is ready for use without connecting:
+    /// `peer_addr` returns `addr` and `channel`, and `reconnect(addr)` connects to `channel`
+    /// again.
+    ///
+    /// Fails if `channel` is not within `1..=30`. On errors, `fd` stays owned by the caller.
+    pub fn from_connected_fd(
+        fd: std::os::unix::io::RawFd,
+        addr: BtAddr,
+        channel: u8,
+    ) -> Result<BtSocket, BtError> {
+        Ok(BtSocket(platform::BtSocket::from_connected_fd(
+            fd, addr, channel,
+        )?))
+    }
+
+    /// Like `connect`, but if the connect fails with a transient error (see
+    /// `BtError::is_retryable`), like a refused connection or a device that is down, it is retried
+    /// after waiting for `backoff`, up to `attempts` times in total. Returns the error of the last
+    /// attempt if all of them fail.
+    ///
+    /// Every retry uses a new underlying socket, so the file descriptor changes.
+    ///
+    /// This function can block for some seconds per attempt.
+    pub fn connect_retry(
+        &mut self,
+        addr: BtAddr,
+        attempts: usize,
+        backoff: time::Duration,
+    ) -> Result<(), BtError> {
+        self.0.connect_retry(addr, attempts, backoff)
+    }
+
+    /// Like `connect`, but first pages the device with the paging parameters the scan found
+    /// `device` with, which makes the page procedure faster and more reliable. If the parameters
+    /// are older than 30 seconds or paging with them fails, the device is paged normally.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_with_paging(&mut self, device: &BtDevice) -> Result<(), BtError> {
+        self.0.connect_with_paging(device.addr, device.paging)
+    }
+
+    /// Connect to the service on remote device `addr`, which is either a `BtAddr` or a
+    /// `BtSocketAddr`. With a channel, this is `connect_channel`. Otherwise, the parameters of the
+    /// socket's `BtProtocol` are used: for RFCOMM without an explicit channel, the channel will be
+    /// determined through SDP protocol, unless an earlier search found it (see
+    /// `set_sdp_cache_ttl`).
+    ///
+    /// Failures of the SDP search are reported with context: `"SDP connection failed"` if the SDP
+    /// server of the device couldn't be reached, `"SDP search timed out"` if the search took more
+    /// than 20 seconds. Errors without such context stem from the connection to the service.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect<A: Into<BtSocketAddr>>(&mut self, addr: A) -> Result<(), BtError> {
+        let addr = addr.into();
+        match addr.channel {
+            Some(channel) => self.connect_channel(addr.addr, channel),
+            None => self.connect_blocking(addr.addr),
         }
     }
 
-    /// Connect to the RFCOMM service on remote device with address `addr`. Channel will be
+    /// Like `connect`, but tries each local adapter that is up in turn until the connection
+    /// succeeds, starting with the default adapter. Useful when adapters (like USB dongles) come
+    /// and go. Returns the error of the last attempt if all of them fail.
+    ///
+    /// A new socket is created for each attempt after the first, with the same settings.
+    ///
+    /// This function can block for some seconds per adapter.
+    pub fn connect_any_adapter(&mut self, addr: BtAddr) -> Result<(), BtError> {
+        self.0.connect_any_adapter(addr)
+    }
+
+    /// Same as `connect`. The connection process is driven by plain `poll()` calls, so this is
+    /// available without the `mio` feature as well.
+    pub fn connect_blocking(&mut self, addr: BtAddr) -> Result<(), BtError> {
+        self.connect_timed(addr).map(|_| ())
+    }
+
+    /// Scans for `scan_timeout` and connects to the first device found whose name is `name`, like
+    /// `connect`. Fails with `BtError::Desc("device not found")` if there is no such device.
+    ///
+    /// This function blocks for `scan_timeout` plus the time the connection takes.
+    pub fn connect_by_name(
+        &mut self,
+        name: &str,
+        scan_timeout: time::Duration,
+    ) -> Result<(), BtError> {
+        let device = scan_devices(scan_timeout)?
+            .into_iter()
+            .find(|device| device.name == name)
+            .ok_or_else(|| BtError::Desc("device not found".to_string()))?;
+        self.connect(device.addr)
+    }
+
+    /// Connect to RFCOMM channel `channel` on the remote device with address `addr`, skipping the
+    /// SDP search. Fails if the socket wasn't created for RFCOMM or `channel` is not within
+    /// `1..=30`.
+    ///
+    /// The channel only applies to this connect, the socket's `BtProtocol` is left unchanged.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_channel(&mut self, addr: BtAddr, channel: u8) -> Result<(), BtError> {
+        self.0
+            .connect_channel(addr, channel)?
+            .complete()
+            .map(|_| ())
+    }
+
+    /// Like `connect_channel`, but fails with `ETIMEDOUT` if the connection isn't established
+    /// within `timeout`, e.g. because the device is asleep. The pending connection attempt is
+    /// aborted by replacing the socket with a new one with the same settings (so the file
+    /// descriptor changes).
+    ///
+    /// This function blocks for at most `timeout`.
+    pub fn connect_channel_timeout(
+        &mut self,
+        addr: BtAddr,
+        channel: u8,
+        timeout: time::Duration,
+    ) -> Result<(), BtError> {
+        self.0.connect_channel_timeout(addr, channel, timeout)
+    }
+
+    /// Connect to a previously stored `BtEndpoint`, see `connect_channel`.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_endpoint(&mut self, endpoint: &BtEndpoint) -> Result<(), BtError> {
+        self.connect_channel(endpoint.addr, endpoint.channel)
+    }
+
+    /// Like `connect`, but the SDP search looks for the RFCOMM channel of `profile` instead of the
+    /// serial port, e.g. `Profile::HandsFree`. As with `connect`, a channel set on the socket's
+    /// `BtProtocol` is used without a search.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_profile(&mut self, addr: BtAddr, profile: Profile) -> Result<(), BtError> {
+        self.0.connect_profile(addr, profile).complete().map(|_| ())
+    }
+
+    /// Like `connect`, but additionally reports how long the SDP search and the actual connection
+    /// setup took.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_timed(&mut self, addr: BtAddr) -> Result<ConnectMetrics, BtError> {
+        self.0.connect(addr).complete()
+    }
+
+    /// Like `connect`, but if the SDP search for the RFCOMM channel fails, the connection is
+    /// attempted on `fallback_channel` instead. Useful for devices with a broken SDP server that
+    /// listen on a well-known channel. Fails if `fallback_channel` is not within `1..=30`.
+    ///
+    /// This function can block for some seconds.
+    pub fn connect_with_fallback(
+        &mut self,
+        addr: BtAddr,
+        fallback_channel: u8,
+    ) -> Result<(), BtError> {
+        self.0
+            .connect_with_fallback(addr, fallback_channel)?
+            .complete()
+            .map(|_| ())
+    }
+
+    /// Connect to the service on remote device with address `addr` using the parameters of the
+    /// socket's `BtProtocol`. For RFCOMM without an explicit channel, the channel will be
     /// determined through SDP protocol.
     ///
     /// This function will return immediately and can therefor not indicate most kinds of failures.
     /// Once the connection actually has been established or an error has been determined the socket
     /// will become writable however. It is highly recommended to combine this call with the usage
     /// of `mio` (or some higher level event loop) to get proper non-blocking behaviour.
-    pub fn connect_async(&mut self, addr: BtAddr) -> BtSocketConnect {
+    #[cfg(feature = "mio")]
+    pub fn connect_async(&mut self, addr: BtAddr) -> BtSocketConnect<'_> {
         BtSocketConnect(self.0.connect(addr))
     }
 
+    /// Like `connect`, but waits for the connection with the `async-io` reactor instead of
+    /// blocking, so it can be used from any async runtime.
+    ///
+    /// Dropping the future cancels the connection attempt without leaking resources: a running
+    /// SDP search is closed, and a pending `connect()` is aborted by replacing the socket with a
+    /// new one with the same settings (so the file descriptor changes). This makes the future
+    /// safe to use with timeouts or `select!`.
+    pub async fn connect_future(&mut self, addr: BtAddr) -> Result<(), BtError> {
+        self.0.connect_future(addr).await
+    }
+
+    /// Returns the number of bytes received since the socket was created, before any line ending
+    /// translation. This includes the reads of its buffered wrappers and of the halves of
+    /// `into_buffered_split`, but not those of streams like `get_stream_std`. A `reconnect`
+    /// starts over at zero.
+    pub fn bytes_read(&self) -> u64 {
+        self.0.bytes_read()
+    }
+
+    /// Returns the number of bytes sent since the socket was created, like `bytes_read`. Data
+    /// buffered by a wrapper is counted once it is written to the socket.
+    pub fn bytes_written(&self) -> u64 {
+        self.0.bytes_written()
+    }
+
+    /// Returns a stream on a duplicate of the socket's file descriptor, which stays valid after
+    /// the socket is dropped. The connection is only closed once both are dropped (or on
+    /// `shutdown`).
+    ///
+    /// Panics if the descriptor can't be duplicated, e.g. because the process ran out of them,
+    /// see `try_get_stream_std` for a fallible version.
     pub fn get_stream_std(&self) -> StdUnixStream {
         self.0.get_stream_std()
     }
 
+    /// Like `get_stream_std`, but returns an error if the descriptor can't be duplicated.
+    pub fn try_get_stream_std(&self) -> Result<StdUnixStream, BtError> {
+        self.0.try_get_stream_std()
+    }
+
+    /// Like `get_stream_std`, but returns an `async_std` stream.
     pub fn get_stream(&self) -> UnixStream {
         self.0.get_stream()
     }
 
+    /// Like `try_get_stream_std`, but returns an `async_std` stream.
+    pub fn try_get_stream(&self) -> Result<UnixStream, BtError> {
+        self.0.try_get_stream_std().map(UnixStream::from)
+    }
+
+    /// Returns the raw file descriptor of the socket, prefer the `AsFd` implementation which ties
+    /// the descriptor's lifetime to the socket.
     pub fn get_fd(&self) -> i32 {
         self.0.fd
     }
+
+    /// Resolves once the remote device has closed the connection (or the link was lost), without
+    /// consuming any received data.
+    pub async fn closed(&self) -> Result<(), BtError> {
+        self.0.closed().await
+    }
+
+    /// Checks without blocking whether the connection is still usable, without consuming any
+    /// received data or the pending socket error.
+    ///
+    /// Returns `false` once the remote device closed the connection or the link was lost, e.g.
+    /// because the system was suspended. Such a socket can't be used anymore, call `reconnect`
+    /// (or create a new socket) to recover. `closed` waits for the same condition.
+    pub fn is_alive(&self) -> Result<bool, BtError> {
+        self.0.is_alive()
+    }
+
+    /// Replaces the socket with a new one, keeping its settings, and connects it to `addr` like
+    /// `connect`. Use this to recover from a lost link (see `is_alive`), e.g. after the system
+    /// resumed from suspend.
+    ///
+    /// The new socket has the same transport, RFCOMM role, line mode, buffer sizes, priority (see
+    /// `set_low_latency`), blocking mode and close-on-exec flag. This applies to every other
+    /// function that replaces the socket as well.
+    ///
+    /// The file descriptor changes, event loop registrations of the old one have to be renewed.
+    ///
+    /// This function can block for some seconds.
+    pub fn reconnect<A: Into<BtSocketAddr>>(&mut self, addr: A) -> Result<(), BtError> {
+        self.0.renew()?;
+        self.connect(addr)
+    }
+
+    /// Like `reconnect`, but connects to the device and channel of the last successful connect
+    /// again, e.g. to recover a wedged link of a socket that is referenced elsewhere. The current
+    /// connection is shut down first, also for the streams obtained from the socket (like
+    /// `get_stream_std`).
+    ///
+    /// Fails if the socket was never connected. The file descriptor changes, event loop
+    /// registrations of the old one have to be renewed.
+    ///
+    /// This function can block for some seconds.
+    pub fn shutdown_and_reconnect(&mut self) -> Result<(), BtError> {
+        self.0.shutdown_and_reconnect()
+    }
+
+    /// Sets the size of the kernel receive buffer (`SO_RCVBUF`).
+    ///
+    /// The kernel doubles the requested value to account for its bookkeeping overhead and clamps it
+    /// to the system limits, use `recv_buffer_size` to find out the effective size.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(), BtError> {
+        self.0.set_recv_buffer_size(size)
+    }
+
+    /// Returns the size of the kernel receive buffer (`SO_RCVBUF`).
+    pub fn recv_buffer_size(&self) -> Result<usize, BtError> {
+        self.0.recv_buffer_size()
+    }
+
+    /// Sets the size of the kernel send buffer (`SO_SNDBUF`).
+    ///
+    /// Like with `set_recv_buffer_size`, the kernel may double or clamp the requested value.
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(), BtError> {
+        self.0.set_send_buffer_size(size)
+    }
+
+    /// Returns the size of the kernel send buffer (`SO_SNDBUF`).
+    pub fn send_buffer_size(&self) -> Result<usize, BtError> {
+        self.0.send_buffer_size()
+    }
+
+    /// Minimizes the delay of sent data, e.g. for interactive sessions, at the expense of other
+    /// connections of the adapter. Off by default.
+    ///
+    /// There is no equivalent of `TCP_NODELAY`: RFCOMM doesn't hold back small writes, every
+    /// `write` is passed to the controller right away. What delays data is the queue of packets
+    /// waiting for the controller, shared by all connections. This option sets the socket's
+    /// `SO_PRIORITY` to 6 (the highest one without `CAP_NET_ADMIN`), which makes the kernel send
+    /// its packets before those of connections with a lower priority.
+    ///
+    /// Data held by wrappers like `buffered` or `coalescing` is only sent once they flush, use the
+    /// socket itself for the lowest latency. A smaller send buffer (see `set_send_buffer_size`)
+    /// limits how much data can queue up in front of new writes.
+    pub fn set_low_latency(&self, enabled: bool) -> Result<(), BtError> {
+        self.0.set_low_latency(enabled)
+    }
+
+    /// Returns whether the socket is in low latency mode, see `set_low_latency`.
+    pub fn low_latency(&self) -> Result<bool, BtError> {
+        self.0.low_latency()
+    }
+
+    /// Writes the entire `buf`, waiting for the socket to become writable as often as necessary.
+    ///
+    /// Fails with `BtError::Timeout` if not all data could be sent within `timeout`, the error
+    /// carries the number of bytes that were sent nevertheless.
+    pub fn write_all_timeout(
+        &mut self,
+        buf: &[u8],
+        timeout: time::Duration,
+    ) -> Result<(), BtError> {
+        self.0.write_all_timeout(buf, timeout)
+    }
+
+    /// Reads until `framer` reports a complete frame and returns it, including a length prefix or
+    /// delimiter, see `LengthPrefixed` and `Delimiter`.
+    ///
+    /// Data received beyond the frame is kept for the next `read_frame` or `read` call, like all
+    /// data received so far if the framer fails or reports a frame longer than the data.
+    pub fn read_frame<F: Framer + ?Sized>(&mut self, framer: &mut F) -> Result<Vec<u8>, BtError> {
+        self.0.read_frame(framer)
+    }
+
+    /// Forces the local side into the given role of the baseband link, which some devices need
+    /// for stable connections. Only available for RFCOMM sockets, `BtRole::Any` (no preference)
+    /// by default. Must be set before connecting or listening.
+    pub fn set_rfcomm_role(&mut self, role: BtRole) -> Result<(), BtError> {
+        self.0.set_rfcomm_role(role)
+    }
+
+    /// Selects whether reads translate line endings, `BtLineMode::Raw` (no translation) by
+    /// default. Useful for devices that frame lines with `\r\n`, like the ones used through
+    /// `/dev/rfcomm0` and termios.
+    pub fn set_mode(&mut self, mode: BtLineMode) {
+        self.0.set_mode(mode)
+    }
+
+    /// Like `Read::read_exact`, but fails with a `BtError`: `BtError::UnexpectedEof` if the
+    /// connection is closed before `buf` is filled.
+    pub fn read_exact_bt(&mut self, buf: &mut [u8]) -> Result<(), BtError> {
+        let mut transferred = 0;
+        while transferred < buf.len() {
+            match self.read(&mut buf[transferred..]) {
+                Ok(0) => return Err(BtError::UnexpectedEof { transferred }),
+                Ok(len) => transferred += len,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `Write::write_all`, but fails with a `BtError`.
+    pub fn write_all_bt(&mut self, buf: &[u8]) -> Result<(), BtError> {
+        Ok(self.write_all(buf)?)
+    }
+
+    /// Writes the whole message `buf` with all-or-nothing semantics: if the connection fails
+    /// after only a part of the message was accepted, the error is returned as
+    /// `BtError::PartialWrite`, since the peer received a truncated message. Errors before
+    /// anything was written are returned as they are.
+    ///
+    /// Meant for small control messages of message oriented protocols, where a short write is a
+    /// bug rather than a condition to retry.
+    pub fn write_message(&mut self, buf: &[u8]) -> Result<(), BtError> {
+        let mut transferred = 0;
+        while transferred < buf.len() {
+            let error = match self.write(&buf[transferred..]) {
+                Ok(0) => std::io::Error::from(std::io::ErrorKind::WriteZero).into(),
+                Ok(len) => {
+                    transferred += len;
+                    continue;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => BtError::from(e),
+            };
+            return Err(if transferred == 0 {
+                error
+            } else {
+                BtError::PartialWrite {
+                    transferred,
+                    error: Box::new(error),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the connected device (`getpeername()`). The channel is only set
+    /// for RFCOMM sockets.
+    pub fn peer_addr(&self) -> Result<BtSocketAddr, BtError> {
+        self.0.peer_addr()
+    }
+
+    /// Returns the address of the local adapter the socket is bound to and, for RFCOMM sockets,
+    /// its channel (`getsockname()`).
+    pub fn local_addr(&self) -> Result<BtSocketAddr, BtError> {
+        self.0.local_addr()
+    }
+
+    /// Returns the class of the connected device, e.g. to check that it is the expected kind of
+    /// device.
+    ///
+    /// The class is looked up in the inquiry cache of the default adapter, so this fails unless
+    /// the device was found by a recent scan (see `scan_devices`) or is discoverable: if the
+    /// cache is older than 30 seconds, the kernel refreshes it by a short inquiry first, see
+    /// `inquiry_cache`. A device that isn't in the cache is reported as such.
+    pub fn peer_class(&self) -> Result<DeviceClass, BtError> {
+        self.0.peer_class()
+    }
+
+    /// Returns the flow control state of the connection, for diagnosing stalled transfers.
+    ///
+    /// The RFCOMM credits are only exposed through debugfs, they are `None` unless the process
+    /// can read `/sys/kernel/debug/bluetooth/rfcomm_dlc` (usually requires root).
+    pub fn flow_info(&self) -> Result<FlowInfo, BtError> {
+        self.0.flow_info()
+    }
+
+    /// Returns a handle to interrupt blocking reads of this socket from other threads, e.g. to
+    /// shut down a reader thread cleanly.
+    ///
+    /// Once a handle was requested, every read first waits until either the socket is readable
+    /// or the handle's `interrupt()` is called. In the latter case, the read fails with an
+    /// `std::io::Error` of kind `Other` wrapping `BtError::Interrupted`. If no read is in
+    /// progress, the next one is interrupted. `finish` is interrupted the same way.
+    pub fn interrupt_handle(&self) -> Result<BtInterruptHandle, BtError> {
+        Ok(BtInterruptHandle(self.0.interrupt_handle()?))
+    }
+
+    /// Turns the socket into a `Stream` yielding the received data in chunks of at most
+    /// `chunk_size` bytes as they arrive, e.g. to feed it into a decoding pipeline. The stream ends
+    /// on EOF or after the first error. Panics if `chunk_size` is zero.
+    pub fn into_stream(self, chunk_size: usize) -> BtSocketStream {
+        assert!(chunk_size > 0, "Chunk size must not be zero");
+        let (socket, error) = match BtSocketPollable::new(self) {
+            Ok(socket) => (Some(socket), None),
+            Err(e) => (None, Some(e)),
+        };
+        BtSocketStream {
+            socket,
+            error,
+            buf: vec![0; chunk_size],
+        }
+    }
+
+    /// Wraps the socket into a `CoalescingWriter` that batches writes until `threshold` bytes
+    /// are buffered or the oldest buffered byte waited for `delay`, reducing the number of small
+    /// frames sent over slow links.
+    pub fn coalescing(self, threshold: usize, delay: time::Duration) -> CoalescingWriter<BtSocket> {
+        CoalescingWriter::new(self, threshold, delay)
+    }
+
+    /// Wraps the socket into a `BufRead` + `Write` with a read buffer of `read_cap` bytes and a
+    /// write buffer of `write_cap` bytes, for protocols exchanging many small messages.
+    pub fn buffered(self, read_cap: usize, write_cap: usize) -> BufferedBtSocket {
+        BufferedBtSocket {
+            reader: BufReader::with_capacity(read_cap, self),
+            write_buf: Vec::with_capacity(write_cap),
+        }
+    }
+
+    /// Splits the socket into a buffered read half with a buffer of `read_cap` bytes and a
+    /// buffered write half with a buffer of `write_cap` bytes, e.g. to read lines in one task and
+    /// write in another without sharing a lock.
+    ///
+    /// Each half owns its own duplicate of the file descriptor, the connection is closed once
+    /// both are dropped. The data is passed unchanged, regardless of `set_mode`. Panics if the
+    /// descriptor can't be duplicated, like `get_stream_std`.
+    pub fn into_buffered_split(
+        self,
+        read_cap: usize,
+        write_cap: usize,
+    ) -> (BufReader<BtStreamHalf>, BufWriter<BtStreamHalf>) {
+        (
+            BufReader::with_capacity(read_cap, BtStreamHalf(self.0.stream_half())),
+            BufWriter::with_capacity(write_cap, BtStreamHalf(self.0.stream_half())),
+        )
+    }
+
+    /// Sends up to `len` bytes of `file`, starting at its current position, with `sendfile()`.
+    /// This avoids copying the data through user space, e.g. for firmware uploads. If
+    /// `sendfile()` isn't supported for the socket, the data is copied through a buffer instead.
+    ///
+    /// Returns the number of bytes sent, which is less than `len` if the end of the file was
+    /// reached.
+    pub fn send_file(&mut self, file: &std::fs::File, len: usize) -> Result<usize, BtError> {
+        self.0.send_file(file, len)
+    }
+
+    /// Switches the socket to non-blocking mode and registers it with tokio's reactor, for
+    /// implementing readiness-based protocols directly on top of tokio.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn into_async_fd(self) -> std::io::Result<tokio::io::unix::AsyncFd<BtSocketFd>> {
+        self.0.stream.set_nonblocking(true)?;
+        tokio::io::unix::AsyncFd::new(BtSocketFd(self))
+    }
+
+    /// Sets whether the socket is closed when executing another program (`FD_CLOEXEC`). Sockets
+    /// are created with the flag set, clear it to pass the socket on to a child process.
+    pub fn set_cloexec(&self, cloexec: bool) -> Result<(), BtError> {
+        self.0.set_cloexec(cloexec)
+    }
+
+    /// Shuts down the read, write, or both halves of the connection.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> Result<(), BtError> {
+        self.0.shutdown(how)
+    }
+
+    /// Closes the connection gracefully: waits until all written data has been transmitted,
+    /// shuts down the write half so the peer reads EOF, then closes the socket.
+    ///
+    /// This function blocks as long as the peer doesn't accept the queued data. The wait can be
+    /// aborted through a `BtInterruptHandle`, which makes it fail with `BtError::Interrupted`.
+    /// See `finish_timeout` to limit it instead.
+    pub fn finish(self) -> Result<(), BtError> {
+        self.0.finish()
+    }
+
+    /// Like `finish`, but fails with `ETIMEDOUT` if the queued data hasn't been transmitted
+    /// within `timeout`. The socket is closed either way.
+    pub fn finish_timeout(self, timeout: time::Duration) -> Result<(), BtError> {
+        self.0.finish_timeout(timeout)
+    }
+
+    /// Returns and clears the pending error of the socket (`SO_ERROR`), if any.
+    ///
+    /// When driving a non-blocking connect with an own event loop, call this once the socket
+    /// became writable: `None` means the connection was established successfully.
+    pub fn take_error(&self) -> Result<Option<BtError>, BtError> {
+        self.0.take_error()
+    }
 }
 
 impl From<platform::BtSocket> for BtSocket {
@@ -91,6 +630,40 @@ impl From<platform::BtSocket> for BtSocket {
     }
 }
 
+impl std::os::unix::io::AsFd for BtSocket {
+    fn as_fd(&self) -> std::os::unix::io::BorrowedFd<'_> {
+        std::os::unix::io::AsFd::as_fd(&self.0.stream)
+    }
+}
+
+impl std::os::unix::io::AsRawFd for BtSocket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.0.fd
+    }
+}
+
+impl From<BtSocket> for std::os::unix::io::OwnedFd {
+    fn from(socket: BtSocket) -> std::os::unix::io::OwnedFd {
+        socket.0.stream.into()
+    }
+}
+
+/// Takes ownership of an RFCOMM stream socket like `BtSocket::from_fd`. Unlike there, `fd` is
+/// closed if the check fails.
+impl std::convert::TryFrom<std::os::unix::io::OwnedFd> for BtSocket {
+    type Error = BtError;
+
+    fn try_from(fd: std::os::unix::io::OwnedFd) -> Result<BtSocket, BtError> {
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        let socket = BtSocket::from_fd(fd.as_raw_fd())?;
+        // The socket owns the descriptor now
+        let _ = fd.into_raw_fd();
+        Ok(socket)
+    }
+}
+
+#[cfg(feature = "mio")]
 impl mio::Evented for BtSocket {
     fn register(
         &self,
@@ -117,6 +690,35 @@ impl mio::Evented for BtSocket {
     }
 }
 
+/// Registration with mio 0.8 and later, for use with current tokio/mio based runtimes.
+#[cfg(feature = "mio_08")]
+impl mio_08::event::Source for BtSocket {
+    fn register(
+        &mut self,
+        registry: &mio_08::Registry,
+        token: mio_08::Token,
+        interests: mio_08::Interest,
+    ) -> std::io::Result<()> {
+        mio_08::unix::SourceFd(&self.get_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio_08::Registry,
+        token: mio_08::Token,
+        interests: mio_08::Interest,
+    ) -> std::io::Result<()> {
+        mio_08::unix::SourceFd(&self.get_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio_08::Registry) -> std::io::Result<()> {
+        mio_08::unix::SourceFd(&self.get_fd()).deregister(registry)
+    }
+}
+
+/// For L2CAP sockets, every `read` returns exactly one packet. If the packet doesn't fit into
+/// the buffer, the read fails with `ErrorKind::InvalidInput` (the packet is lost). RFCOMM sockets
+/// are byte streams.
 impl Read for BtSocket {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.0.read(buf)
@@ -133,175 +735,1576 @@ impl Write for BtSocket {
     }
 }
 
-/// What needs to happen to advance to the next state an asynchronous process
-#[allow(missing_debug_implementations)] // `&mio::Evented` doesn't do `Debug`
-pub enum BtAsync<'a> {
-    /// Caller needs to wait for the given `Evented` object to reach the given `Ready` state
-    WaitFor(&'a dyn mio::Evented, mio::Ready),
+/// One half of a `BtSocket` split with `BtSocket::into_buffered_split`, which keeps counting the
+/// transferred bytes together with the other half.
+#[derive(Debug)]
+pub struct BtStreamHalf(platform::BtStreamHalf);
 
-    /// Asynchronous transaction has completed
-    Done,
-}
+impl BtStreamHalf {
+    /// Returns the stream on the duplicate of the socket's file descriptor.
+    pub fn get_ref(&self) -> &StdUnixStream {
+        self.0.get_ref()
+    }
 
-/// Manages the bluetooth connection process when used from an asynchronous client.
-#[derive(Debug)]
-pub struct BtSocketConnect<'a>(platform::BtSocketConnect<'a>);
+    /// Returns the number of bytes received through either half, see `BtSocket::bytes_read`.
+    pub fn bytes_read(&self) -> u64 {
+        self.0.bytes_read()
+    }
 
-impl<'a> BtSocketConnect<'a> {
-    /// Advance the connection process to the next state
-    ///
-    /// Usage: When receiving a new `BtSocketConnect` instance call this function to get the
-    /// connection process started, then wait for the condition requested in `BtAsync` to apply
-    /// (by polling for it in a `mio.Poll` instance in general). Once the condition is met, invoke
-    /// this function again to advance to the next connect step. Repeat this process until you reach
-    /// `BtAsync::Done`, then discard this object and enjoy your established connection.
-    pub fn advance(&mut self) -> Result<BtAsync, BtError> {
-        self.0.advance()
+    /// Returns the number of bytes sent through either half, see `BtSocket::bytes_written`.
+    pub fn bytes_written(&self) -> u64 {
+        self.0.bytes_written()
     }
 }
 
-/// Finds a vector of Bluetooth devices in range.
-///
-/// This function blocks for some seconds.
-pub fn scan_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
-    platform::scan_devices(timeout)
+impl Read for BtStreamHalf {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
 }
 
-/// Represents an error which occurred in this library.
+impl Write for BtStreamHalf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A connected `BtSocket` that is shut down when dropped, see `BtSocket::connect_scoped`.
+///
+/// Errors during the shutdown are logged (with the `log` feature).
 #[derive(Debug)]
-pub enum BtError {
-    /// No specific information is known.
-    Unknown,
+pub struct ConnectGuard(Option<BtSocket>);
 
-    /// On Unix platforms: the error code and an explanation for this error code.
-    Errno(u32, String),
+impl ConnectGuard {
+    /// Returns the socket without shutting it down.
+    pub fn into_inner(mut self) -> BtSocket {
+        self.0.take().unwrap()
+    }
+}
 
-    /// This error only has a description.
-    Desc(String),
+impl std::ops::Deref for ConnectGuard {
+    type Target = BtSocket;
 
-    /// `std::io::Error`
-    IoError(std::io::Error),
+    fn deref(&self) -> &BtSocket {
+        self.0.as_ref().unwrap()
+    }
 }
 
-#[allow(deprecated)]
-impl std::fmt::Display for BtError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:}", std::error::Error::description(self))
+impl std::ops::DerefMut for ConnectGuard {
+    fn deref_mut(&mut self) -> &mut BtSocket {
+        self.0.as_mut().unwrap()
     }
 }
 
-impl std::error::Error for BtError {
+impl Drop for ConnectGuard {
+    fn drop(&mut self) {
+        if let Some(socket) = self.0.take() {
+            match socket.shutdown(std::net::Shutdown::Both) {
+                // The remote device closed the connection already
+                Err(BtError::IoError(e)) if e.kind() == std::io::ErrorKind::NotConnected => {}
+                Err(e) => {
+                    bt_log!(warn, "Shutting down connection failed: {}", e);
+                }
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// A `BtSocket` with read and write buffers, see `BtSocket::buffered`.
+///
+/// Buffered data is written when the buffer is full, on `flush` and when the socket is dropped.
+#[derive(Debug)]
+pub struct BufferedBtSocket {
+    reader: BufReader<BtSocket>,
+    write_buf: Vec<u8>,
+}
+
+impl BufferedBtSocket {
+    /// Returns the underlying socket.
+    pub fn get_ref(&self) -> &BtSocket {
+        self.reader.get_ref()
+    }
+
+    /// Returns the underlying socket. Reading from or writing to it directly bypasses the buffers.
+    pub fn get_mut(&mut self) -> &mut BtSocket {
+        self.reader.get_mut()
+    }
+
+    /// Write all buffered data to the socket
+    fn flush_buf(&mut self) -> std::io::Result<()> {
+        let socket = self.reader.get_mut();
+        let mut written = 0;
+        let result = loop {
+            if written == self.write_buf.len() {
+                break Ok(());
+            }
+            match socket.write(&self.write_buf[written..]) {
+                Ok(0) => break Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.write_buf.drain(..written);
+        result
+    }
+}
+
+impl Read for BufferedBtSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl BufRead for BufferedBtSocket {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+impl Write for BufferedBtSocket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.write_buf.len() + buf.len() > self.write_buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.write_buf.capacity() {
+            self.reader.get_mut().write(buf)
+        } else {
+            self.write_buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_buf()?;
+        self.reader.get_mut().flush()
+    }
+}
+
+impl Drop for BufferedBtSocket {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+/// A `BtSocket` owned by a tokio `AsyncFd`, see `BtSocket::into_async_fd`.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct BtSocketFd(BtSocket);
+
+#[cfg(feature = "tokio")]
+impl BtSocketFd {
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> BtSocket {
+        self.0
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::ops::Deref for BtSocketFd {
+    type Target = BtSocket;
+
+    fn deref(&self) -> &BtSocket {
+        &self.0
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::ops::DerefMut for BtSocketFd {
+    fn deref_mut(&mut self) -> &mut BtSocket {
+        &mut self.0
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::os::unix::io::AsRawFd for BtSocketFd {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.0.get_fd()
+    }
+}
+
+/// Interrupts blocking reads of a `BtSocket`, see `BtSocket::interrupt_handle`.
+#[derive(Debug, Clone)]
+pub struct BtInterruptHandle(platform::BtInterruptHandle);
+
+impl BtInterruptHandle {
+    /// Makes the current, or otherwise the next, blocking read or `finish` of the socket fail
+    /// with `BtError::Interrupted`.
+    pub fn interrupt(&self) -> Result<(), BtError> {
+        self.0.interrupt()
+    }
+}
+
+/// A connected bluetooth socket exposing the `poll_read`/`poll_write` building blocks for
+/// hand-written futures and custom executors.
+///
+/// The socket is registered with the `async-io` reactor, which wakes the task passed in the
+/// `Context` once the socket becomes readable or writable again.
+#[derive(Debug)]
+pub struct BtSocketPollable(platform::BtSocketPollable);
+
+impl BtSocketPollable {
+    /// Switch `socket` to non-blocking mode and register it with the reactor.
+    pub fn new(socket: BtSocket) -> Result<BtSocketPollable, BtError> {
+        Ok(BtSocketPollable(platform::BtSocketPollable::new(socket.0)?))
+    }
+
+    /// Attempt to read into `buf`. Returns `Poll::Pending` and schedules a wakeup of the task
+    /// if no data is available yet.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        self.0.poll_read(cx, buf)
+    }
+
+    /// Attempt to write `buf`. Returns `Poll::Pending` and schedules a wakeup of the task if the
+    /// send buffer is full.
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.0.poll_write(cx, buf)
+    }
+}
+
+/// The data received by a `BtSocket` as `Stream` of chunks, see `BtSocket::into_stream`.
+///
+/// The stream ends when the remote device closes the connection, or after yielding an error.
+#[derive(Debug)]
+pub struct BtSocketStream {
+    socket: Option<BtSocketPollable>,
+    /// Error registering the socket, yielded first
+    error: Option<BtError>,
+    buf: Vec<u8>,
+}
+
+impl futures_core::Stream for BtSocketStream {
+    type Item = Result<Vec<u8>, BtError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Some(Err(error)));
+        }
+        let this = &mut *self;
+        let socket = match &this.socket {
+            Some(socket) => socket,
+            None => return Poll::Ready(None),
+        };
+        match socket.poll_read(cx, &mut this.buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(0)) => {
+                this.socket = None;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Ok(len)) => Poll::Ready(Some(Ok(this.buf[..len].to_vec()))),
+            Poll::Ready(Err(e)) => {
+                this.socket = None;
+                Poll::Ready(Some(Err(e.into())))
+            }
+        }
+    }
+}
+
+/// A bluetooth socket listening for incoming connections.
+///
+/// Dropping the listener closes the listening socket, which releases its channel (or PSM) right
+/// away, so a restarted server can bind to it again. Connections accepted before stay open.
+#[derive(Debug)]
+pub struct BtListener(platform::BtListener);
+
+impl BtListener {
+    /// Bind to the channel (or PSM) given by `protocol` on all local adapters and listen for
+    /// incoming connections. For RFCOMM without an explicit channel, a free channel is chosen.
+    pub fn bind(protocol: BtProtocol) -> Result<BtListener, BtError> {
+        Ok(BtListener(platform::BtListener::bind(protocol)?))
+    }
+
+    /// Accept a new incoming connection, returning the connected socket and the address of the
+    /// remote device.
+    ///
+    /// This function blocks until a connection arrives.
+    pub fn accept(&self) -> Result<(BtSocket, BtAddr), BtError> {
+        let (socket, addr) = self.0.accept()?;
+        Ok((From::from(socket), addr))
+    }
+
+    /// Accept a new incoming connection, returning the connected socket and the address of the
+    /// remote device.
+    ///
+    /// The listening socket is registered with the async runtime's reactor, so waiting for a
+    /// connection does not block the current thread.
+    pub async fn accept_async(&self) -> Result<(BtSocket, BtAddr), BtError> {
+        let (socket, addr) = self.0.accept_async().await?;
+        Ok((From::from(socket), addr))
+    }
+}
+
+/// What needs to happen to advance to the next state an asynchronous process
+#[cfg(feature = "mio")]
+#[allow(missing_debug_implementations)] // `&mio::Evented` doesn't do `Debug`
+pub enum BtAsync<'a> {
+    /// Caller needs to wait for the given `Evented` object to reach the given `Ready` state
+    WaitFor(&'a dyn mio::Evented, mio::Ready),
+
+    /// Asynchronous transaction has completed
+    Done,
+}
+
+/// Manages the bluetooth connection process when used from an asynchronous client.
+///
+/// It is `Send`, but not `Sync`.
+#[cfg(feature = "mio")]
+#[derive(Debug)]
+pub struct BtSocketConnect<'a>(platform::BtSocketConnect<'a>);
+
+#[cfg(feature = "mio")]
+impl<'a> BtSocketConnect<'a> {
+    /// Advance the connection process to the next state
+    ///
+    /// Usage: When receiving a new `BtSocketConnect` instance call this function to get the
+    /// connection process started, then wait for the condition requested in `BtAsync` to apply
+    /// (by polling for it in a `mio.Poll` instance in general). Once the condition is met, invoke
+    /// this function again to advance to the next connect step. Repeat this process until you reach
+    /// `BtAsync::Done`, then discard this object and enjoy your established connection.
+    pub fn advance(&mut self) -> Result<BtAsync<'_>, BtError> {
+        self.0.advance()
+    }
+
+    /// Timings of the connection phases completed so far.
+    pub fn metrics(&self) -> ConnectMetrics {
+        self.0.metrics()
+    }
+}
+
+/// Time spent in the phases of establishing a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectMetrics {
+    /// Duration of the SDP search for the RFCOMM channel, zero if no search was necessary.
+    pub sdp_duration: time::Duration,
+    /// Duration from issuing `connect()` until the connection was established.
+    pub connect_duration: time::Duration,
+}
+
+/// Finds a vector of Bluetooth devices in range.
+///
+/// This function blocks for some seconds.
+pub fn scan_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
+    scan_devices_with(&ScanOptions::default().with_timeout(timeout))
+}
+
+/// Finds classic and LE devices in range through the discovery of the BlueZ management API,
+/// which also reports the EIR (or advertising) data of the devices, like their appearance.
+/// Useful for dual-mode devices that report a generic class of device.
+///
+/// Uses the default adapter and requires the `CAP_NET_ADMIN` capability. This function blocks
+/// for `timeout`.
+pub fn discover_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
+    platform::discover_devices(timeout)
+}
+
+/// Checks whether the device with address `addr` is reachable by sending it an L2CAP echo
+/// request, like the `l2ping` utility. Returns the round-trip time.
+///
+/// This establishes a baseband connection to the device, but no RFCOMM session. Creating the raw
+/// L2CAP socket usually requires the `CAP_NET_RAW` capability.
+pub fn l2ping(addr: BtAddr, timeout: time::Duration) -> Result<time::Duration, BtError> {
+    platform::l2ping(addr, timeout)
+}
+
+/// Reads the features supported by the device with address `addr`, e.g. whether it supports
+/// EDR or secure simple pairing.
+///
+/// The device must already be connected (e.g. by an open `BtSocket`), this function doesn't
+/// establish a connection. Fails with `ENOTCONN` otherwise. Only connections of the default
+/// local adapter are looked at, so a device connected through another adapter counts as not
+/// connected.
+pub fn remote_features(addr: BtAddr) -> Result<RemoteFeatures, BtError> {
+    platform::remote_features(addr)
+}
+
+/// Like `scan_devices`, but only reports devices whose address starts with the manufacturer
+/// identifier `oui`, see `BtAddr::matches_oui`.
+///
+/// This function blocks for some seconds.
+pub fn scan_devices_by_oui(
+    oui: [u8; 3],
+    timeout: time::Duration,
+) -> Result<Vec<BtDevice>, BtError> {
+    let mut devices = scan_devices(timeout)?;
+    devices.retain(|device| device.addr.matches_oui(oui));
+    Ok(devices)
+}
+
+/// Like `scan_devices`, but only finds devices listening to the inquiry access code with the given
+/// lower address part (LAP).
+///
+/// The three bytes are passed to the controller as they are, i.e. least significant byte first. The
+/// general inquiry access code used by `scan_devices` is `[0x33, 0x8b, 0x9e]`.
+pub fn scan_devices_with_lap(
+    timeout: time::Duration,
+    lap: [u8; 3],
+) -> Result<Vec<BtDevice>, BtError> {
+    scan_devices_with(&ScanOptions::default().with_timeout(timeout).with_lap(lap))
+}
+
+/// Scans until the device with address `addr` is found, e.g. to connect to it as soon as it is
+/// switched on, and returns it. Fails with `ETIMEDOUT` if it isn't found within `timeout`.
+///
+/// The scan runs in rounds: an inquiry of up to `poll_interval` (but at least 1.28 seconds) is
+/// started every `poll_interval`, or as soon as the previous one is over.
+///
+/// This function blocks until the device is found or `timeout` expired.
+pub fn wait_for_device(
+    addr: BtAddr,
+    timeout: time::Duration,
+    poll_interval: time::Duration,
+) -> Result<BtDevice, BtError> {
+    platform::wait_for_device(addr, timeout, poll_interval)
+}
+
+/// Returns the addresses of the devices in the inquiry cache of the default adapter, without
+/// starting a new inquiry. This is (almost) instant.
+///
+/// The cache is filled by scans. If it is empty or its content is older than 30 seconds, the
+/// kernel refreshes it first with the shortest possible inquiry, which takes 1.28 seconds.
+pub fn inquiry_cache() -> Result<Vec<BtAddr>, BtError> {
+    platform::inquiry_cache()
+}
+
+/// Clears the inquiry cache of the default adapter.
+///
+/// The kernel only flushes the cache as part of an inquiry, so this runs the shortest possible
+/// one (1.28 seconds). Afterwards, the cache only holds the devices that responded to it.
+pub fn clear_inquiry_cache() -> Result<(), BtError> {
+    platform::clear_inquiry_cache()
+}
+
+/// Finds Bluetooth devices in range, as configured by `options`. Every device is reported once,
+/// even if it responded several times.
+///
+/// This function blocks for the configured timeout.
+pub fn scan_devices_with(options: &ScanOptions) -> Result<Vec<BtDevice>, BtError> {
+    platform::scan_devices_with(options)
+}
+
+/// Like `scan_devices`, but calls `progress` periodically, e.g. to update a progress bar.
+///
+/// The scan runs as a series of inquiries of about 2.5 seconds whose results are combined, and
+/// `progress` is called after each of them. Every device is reported once.
+pub fn scan_devices_progress<F: FnMut(ScanProgress)>(
+    timeout: time::Duration,
+    mut progress: F,
+) -> Result<Vec<BtDevice>, BtError> {
+    platform::scan_devices_progress(timeout, &mut progress)
+}
+
+/// The progress of a scan, see `scan_devices_progress`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanProgress {
+    /// Time since the scan started.
+    pub elapsed: time::Duration,
+
+    /// The completed fraction of the scan, from 0 to 1.
+    pub fraction: f32,
+
+    /// Number of devices found so far.
+    pub devices_found: usize,
+}
+
+/// The parameters of a device scan.
+///
+/// ```
+/// # use bluetooth_serial_port_async::ScanOptions;
+/// # use std::time::Duration;
+/// let options = ScanOptions::default()
+///     .with_timeout(Duration::from_secs(5))
+///     .with_max_responses(16);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// How long the inquiry lasts, it is rounded to multiples of 1.28 seconds. Default: 10 seconds.
+    pub timeout: time::Duration,
+
+    /// The maximum number of devices to report, between 1 and 255: the controller reports at most
+    /// 255 devices per inquiry. Default: 255.
+    pub max_responses: usize,
+
+    /// Whether the controller's inquiry cache is flushed first, so only devices currently in range
+    /// are reported. Default: `true`.
+    pub flush_cache: bool,
+
+    /// The lower address part of the inquiry access code, see `scan_devices_with_lap`. Default:
+    /// `None`, the general inquiry access code.
+    pub lap: Option<[u8; 3]>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            timeout: time::Duration::from_secs(10),
+            max_responses: 255,
+            flush_cache: true,
+            lap: None,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Sets `timeout`.
+    pub fn with_timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets `max_responses`, clamped to the valid range of 1 to 255.
+    pub fn with_max_responses(mut self, max_responses: usize) -> Self {
+        self.max_responses = max_responses.clamp(1, 255);
+        self
+    }
+
+    /// Sets `flush_cache`.
+    pub fn with_flush_cache(mut self, flush_cache: bool) -> Self {
+        self.flush_cache = flush_cache;
+        self
+    }
+
+    /// Sets `lap`.
+    pub fn with_lap(mut self, lap: [u8; 3]) -> Self {
+        self.lap = Some(lap);
+        self
+    }
+}
+
+/// Looks up the services of the remote device with address `addr` whose service records contain
+/// `uuid` (e.g. the service class `Uuid::from_u16(0x1101)` for serial ports) through SDP.
+///
+/// This function blocks for some seconds.
+pub fn query_services(addr: BtAddr, uuid: Uuid) -> Result<Vec<SdpService>, BtError> {
+    platform::query_services(addr, uuid)
+}
+
+/// Looks up the services of the remote device with address `addr` that are reachable over RFCOMM
+/// through SDP, and returns the RFCOMM channels of each of their service classes (e.g.
+/// `Uuid::from_u16(0x1101)` for serial ports). A class offered on several channels has all of
+/// them, in ascending order.
+///
+/// This doesn't connect to any of the services. This function blocks for some seconds.
+pub fn rfcomm_services(addr: BtAddr) -> Result<BTreeMap<Uuid, Vec<u8>>, BtError> {
+    platform::rfcomm_services(addr)
+}
+
+/// Looks up all services the remote device with address `addr` offers, by searching for the
+/// root of the public browse group (`Uuid::from_u16(0x1002)`) through SDP. Services the device
+/// doesn't list in the public browse group are not found, use `query_services` for those.
+///
+/// This function blocks for some seconds.
+pub fn browse_services(addr: BtAddr) -> Result<Vec<SdpService>, BtError> {
+    platform::browse_services(addr)
+}
+
+/// Looks up the service records of the remote device with address `addr` that contain `uuid`,
+/// with all their attributes. Use this over `query_services` to access attributes of unusual
+/// profiles.
+///
+/// This function blocks for some seconds.
+pub fn query_records(addr: BtAddr, uuid: Uuid) -> Result<Vec<SdpRecord>, BtError> {
+    SdpRecord::parse_response(&platform::query_sdp_raw(addr, &[uuid], &[])?)
+}
+
+/// Performs an SDP ServiceSearchAttribute request for the service records of the remote device
+/// with address `addr` that contain all UUIDs in `search`, and returns the raw response: the data
+/// element sequence of the attribute lists of all matching records, reassembled if the response
+/// was split. Only the attributes with the ids in `attrs` are requested, or all attributes if
+/// `attrs` is empty.
+///
+/// Useful for parsing vendor specific attributes `SdpService` doesn't cover.
+///
+/// This function blocks for some seconds.
+pub fn query_sdp_raw(addr: BtAddr, search: &[Uuid], attrs: &[u16]) -> Result<Vec<u8>, BtError> {
+    platform::query_sdp_raw(addr, search, attrs)
+}
+
+/// Sets how long the RFCOMM channels found by the SDP searches of `BtSocket::connect` are reused
+/// for later connections to the same device, five minutes by default. `None` disables (and
+/// clears) the cache.
+///
+/// A channel is forgotten when connecting to it fails, so the next connect searches again.
+pub fn set_sdp_cache_ttl(ttl: Option<time::Duration>) {
+    platform::set_sdp_cache_ttl(ttl)
+}
+
+/// Forgets all RFCOMM channels found by earlier SDP searches, see `set_sdp_cache_ttl`.
+pub fn clear_sdp_cache() {
+    platform::clear_sdp_cache()
+}
+
+/// Returns the RFCOMM channels local sockets are bound to.
+///
+/// The channels are probed by trying to bind to each of them.
+pub fn bound_rfcomm_channels() -> Result<Vec<u8>, BtError> {
+    platform::bound_rfcomm_channels()
+}
+
+/// Returns the lowest RFCOMM channel no local socket is bound to.
+///
+/// Another process may take the channel before you bind to it, prefer `BtListener::bind` with
+/// `channel: None`, which lets the kernel pick a free channel atomically.
+pub fn free_rfcomm_channel() -> Result<u8, BtError> {
+    platform::free_rfcomm_channel()
+}
+
+/// Checks whether the SDP server of the local host (usually provided by `bluetoothd`) accepts
+/// connections, which is required to register service records.
+///
+/// Like libbluetooth, this connects to the server's local socket rather than to the SDP PSM of an
+/// adapter.
+pub fn sdp_available() -> Result<bool, BtError> {
+    platform::sdp_available()
+}
+
+/// Returns the local Bluetooth adapters.
+pub fn list_adapters() -> Result<Vec<Adapter>, BtError> {
+    platform::list_adapters()
+}
+
+/// Returns an iterator over the local Bluetooth adapters present right now, like
+/// `list_adapters`.
+pub fn adapters() -> Result<std::vec::IntoIter<Adapter>, BtError> {
+    Ok(list_adapters()?.into_iter())
+}
+
+/// Returns the default adapter, which is used for scans and connections.
+pub fn local_adapter() -> Result<Adapter, BtError> {
+    platform::local_adapter()
+}
+
+/// Checks whether `addr` is the address of one of the local adapters, for example to avoid
+/// connecting to a target that is actually the local controller.
+pub fn is_local_adapter(addr: BtAddr) -> Result<bool, BtError> {
+    Ok(list_adapters()?.iter().any(|adapter| adapter.addr == addr))
+}
+
+/// Sends a raw HCI command to the adapter with the device id `adapter_id` (see `Adapter::id`) and
+/// waits for its reply, e.g. for vendor specific commands (`ogf` 0x3F). Returns the return
+/// parameters of the "Command Complete" event, starting with the status byte for most commands.
+///
+/// This is an escape hatch for advanced users: commands that are only answered with a "Command
+/// Status" event time out, and the kernel and `bluetoothd` don't learn about the changes the
+/// command makes. Sending commands requires the `CAP_NET_RAW` capability.
+pub fn hci_send_command(
+    adapter_id: u16,
+    ogf: u8,
+    ocf: u16,
+    params: &[u8],
+) -> Result<Vec<u8>, BtError> {
+    platform::hci_send_command(adapter_id, ogf, ocf, params)
+}
+
+/// Sets how long the adapter with the device id `adapter_id` (see `Adapter::id`) pages a remote
+/// device before a connection attempt fails with `ETIMEDOUT`. Increase it for devices that are
+/// slow to respond, like battery powered devices waking from deep sleep.
+///
+/// The controller supports timeouts from 0.625 ms up to about 40.9 seconds, the usual default is
+/// 5.12 seconds. The setting applies to all connections of the adapter and is lost when it is
+/// reset. Sending the command requires the `CAP_NET_RAW` capability.
+pub fn set_page_timeout(adapter_id: u16, timeout: time::Duration) -> Result<(), BtError> {
+    platform::set_page_timeout(adapter_id, timeout)
+}
+
+/// Sets how often and how long the adapter with the device id `adapter_id` (see `Adapter::id`)
+/// listens for inquiries (discoverability) and pages (incoming connections). Longer and more
+/// frequent scans make the adapter faster to find and connect to, at the cost of power.
+///
+/// Intervals are rounded up to even multiples of 0.625 ms and must be between 11.25 ms and
+/// 2.56 s, windows must be at least 10.625 ms and at most the interval. The settings are lost
+/// when the adapter is reset. Sending the commands requires the `CAP_NET_RAW` capability.
+pub fn set_scan_parameters(adapter_id: u16, params: &ScanParams) -> Result<(), BtError> {
+    platform::set_scan_parameters(adapter_id, params)
+}
+
+/// Returns the inquiry and page scan parameters of the adapter with the device id `adapter_id`,
+/// see `set_scan_parameters`.
+pub fn scan_parameters(adapter_id: u16) -> Result<ScanParams, BtError> {
+    platform::scan_parameters(adapter_id)
+}
+
+/// The inquiry and page scan parameters of a local adapter, see `set_scan_parameters`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanParams {
+    /// Time between the starts of two inquiry scans.
+    pub inquiry_scan_interval: time::Duration,
+
+    /// How long each inquiry scan lasts.
+    pub inquiry_scan_window: time::Duration,
+
+    /// Time between the starts of two page scans.
+    pub page_scan_interval: time::Duration,
+
+    /// How long each page scan lasts.
+    pub page_scan_window: time::Duration,
+}
+
+/// Represents an error which occurred in this library.
+///
+/// Errors are `Send` and `Sync`, so they can be returned from other threads and converted into
+/// `std::io::Error` or boxed errors.
+#[derive(Debug)]
+pub enum BtError {
+    /// No specific information is known.
+    Unknown,
+
+    /// On Unix platforms: the error code and an explanation for this error code.
+    Errno(u32, String),
+
+    /// This error only has a description.
+    Desc(String),
+
+    /// `std::io::Error`
+    IoError(std::io::Error),
+
+    /// A blocking read or `finish` was interrupted through a `BtInterruptHandle`.
+    Interrupted,
+
+    /// The operation did not complete in time.
+    Timeout {
+        /// Number of bytes transferred before the time ran out.
+        transferred: usize,
+    },
+
+    /// The remote device closed the connection before all expected data was received.
+    UnexpectedEof {
+        /// Number of bytes received before the connection was closed.
+        transferred: usize,
+    },
+
+    /// Only a part of a message was written before the connection failed, see
+    /// `BtSocket::write_message`.
+    PartialWrite {
+        /// Number of bytes written before the failure.
+        transferred: usize,
+        /// The error that stopped the write.
+        error: Box<BtError>,
+    },
+
+    /// An error annotated with the operation that failed, see `BtError::context`.
+    WithContext {
+        /// Description of the failed operation.
+        context: String,
+        /// The original error.
+        error: Box<BtError>,
+    },
+}
+
+impl BtError {
+    /// Checks whether the error is caused by a transient condition, so repeating the operation may
+    /// succeed: a refused connection (`ECONNREFUSED`), a device that is down (`EHOSTDOWN`), a
+    /// busy resource (`EBUSY`, `EAGAIN`) or a timeout (`ETIMEDOUT`, `BtError::Timeout`). All
+    /// other errors, like `EACCES`, `EAFNOSUPPORT` or an invalid address, are permanent.
+    pub fn is_retryable(&self) -> bool {
+        let retryable_errno = |errno: i32| {
+            [
+                libc::ECONNREFUSED,
+                libc::EHOSTDOWN,
+                libc::ETIMEDOUT,
+                libc::EBUSY,
+                libc::EAGAIN,
+            ]
+            .contains(&errno)
+        };
+        match self {
+            BtError::Errno(errno, _) => retryable_errno(*errno as i32),
+            BtError::IoError(error) => match error.raw_os_error() {
+                Some(errno) => retryable_errno(errno),
+                None => error.kind() == std::io::ErrorKind::TimedOut,
+            },
+            BtError::Timeout { .. } => true,
+            BtError::WithContext { error, .. } => error.is_retryable(),
+            BtError::Unknown
+            | BtError::Desc(_)
+            | BtError::Interrupted
+            | BtError::UnexpectedEof { .. }
+            | BtError::PartialWrite { .. } => false,
+        }
+    }
+
+    /// Annotates the error with the high level operation that failed, like
+    /// `"while connecting to AA:BB:CC:DD:EE:FF"`. The original error is kept and still decides the
+    /// kind of the `std::io::Error` the error converts to.
+    pub fn context(self, context: &str) -> BtError {
+        BtError::WithContext {
+            context: context.to_string(),
+            error: Box::new(self),
+        }
+    }
+
+    /// Returns a message for end users that explains common error conditions and what to do
+    /// about them, like "The device refused the connection — make sure it's powered on and in
+    /// range". Other errors return their `Display` text. Use the error itself for logs, it keeps
+    /// the details.
+    pub fn user_message(&self) -> String {
+        match self.explanation() {
+            Some(message) => message.to_string(),
+            None => self.to_string(),
+        }
+    }
+
+    /// The message of `user_message` for errors that have one
+    fn explanation(&self) -> Option<&'static str> {
+        let errno = match self {
+            BtError::Errno(errno, _) => Some(*errno as i32),
+            BtError::IoError(error) => error.raw_os_error(),
+            BtError::WithContext { error, .. } | BtError::PartialWrite { error, .. } => {
+                return error.explanation()
+            }
+            _ => None,
+        };
+        Some(match (self, errno) {
+            (_, Some(libc::ECONNREFUSED)) => {
+                "The device refused the connection — make sure it's powered on and in range"
+            }
+            (_, Some(libc::EHOSTDOWN)) | (_, Some(libc::EHOSTUNREACH)) => {
+                "The device can't be reached — make sure it's powered on, in range and \
+                 discoverable"
+            }
+            (_, Some(libc::ETIMEDOUT)) | (BtError::Timeout { .. }, _) => {
+                "The device didn't respond in time — move it closer and try again"
+            }
+            (_, Some(libc::EACCES)) | (_, Some(libc::EPERM)) => {
+                "Access was denied — pair the device first, or check the permissions of the \
+                 application"
+            }
+            (_, Some(libc::EBUSY)) | (_, Some(libc::EAGAIN)) => {
+                "The Bluetooth adapter is busy — try again in a moment"
+            }
+            (_, Some(libc::ECONNRESET))
+            | (_, Some(libc::ECONNABORTED))
+            | (_, Some(libc::EPIPE))
+            | (_, Some(libc::ENOTCONN))
+            | (BtError::UnexpectedEof { .. }, _) => {
+                "The connection to the device was lost — make sure it's still in range"
+            }
+            (_, Some(libc::ENODEV)) | (_, Some(libc::ENETDOWN)) => {
+                "No Bluetooth adapter is available — make sure Bluetooth is turned on"
+            }
+            (_, Some(libc::EAFNOSUPPORT)) | (_, Some(libc::EPROTONOSUPPORT)) => {
+                "Bluetooth isn't supported on this system"
+            }
+            (_, Some(libc::EADDRINUSE)) => "The channel is already used by another application",
+            _ => return None,
+        })
+    }
+}
+
+#[allow(deprecated)]
+impl std::fmt::Display for BtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BtError::WithContext { context, error } => write!(f, "{}: {}", context, error),
+            _ => write!(f, "{:}", std::error::Error::description(self)),
+        }
+    }
+}
+
+impl std::error::Error for BtError {
     fn description(&self) -> &str {
         match self {
             BtError::Unknown => "Unknown Bluetooth Error",
             BtError::Errno(_, ref message) => message.as_str(),
             BtError::Desc(ref message) => message.as_str(),
             BtError::IoError(_) => "io error",
+            BtError::Timeout { .. } => "Operation timed out",
+            BtError::Interrupted => "Read interrupted",
+            BtError::UnexpectedEof { .. } => "Connection closed by the remote device",
+            BtError::PartialWrite { .. } => "Message was only partially written",
+            BtError::WithContext { ref context, .. } => context.as_str(),
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BtError::IoError(error) => Some(error),
+            BtError::PartialWrite { error, .. } | BtError::WithContext { error, .. } => {
+                Some(error.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<BtError> for std::io::Error {
+    /// Keeps wrapped `std::io::Error`s as they are, other errors become the custom error of an
+    /// `std::io::Error` whose kind is derived from the error code, if any.
+    fn from(error: BtError) -> Self {
+        fn kind(error: &BtError) -> std::io::ErrorKind {
+            match error {
+                BtError::IoError(error) => error.kind(),
+                BtError::Errno(errno, _) => std::io::Error::from_raw_os_error(*errno as i32).kind(),
+                BtError::Timeout { .. } => std::io::ErrorKind::TimedOut,
+                BtError::UnexpectedEof { .. } => std::io::ErrorKind::UnexpectedEof,
+                BtError::PartialWrite { .. } => std::io::ErrorKind::WriteZero,
+                BtError::WithContext { error, .. } => kind(error),
+                BtError::Unknown | BtError::Desc(_) | BtError::Interrupted => {
+                    std::io::ErrorKind::Other
+                }
+            }
+        }
+
+        match error {
+            BtError::IoError(error) => error,
+            error => std::io::Error::new(kind(&error), error),
+        }
+    }
+}
+
+impl From<std::io::Error> for BtError {
+    fn from(error: std::io::Error) -> Self {
+        BtError::IoError(error)
+    }
+}
+
+/// A 6-byte long MAC address.
+///
+/// The default is the all-zeros address, like `BtAddr::any()`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct BtAddr(pub [u8; 6]);
+
+impl std::fmt::Debug for BtAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
+        )
+    }
+}
+
+impl BtAddr {
+    /// Returns the MAC address `00:00:00:00:00:00`
+    pub fn any() -> BtAddr {
+        BtAddr([0, 0, 0, 0, 0, 0])
+    }
+
+    /// Linux lower-layers actually hold the address in native byte-order
+    /// althrough they are always displayed in network byte-order
+    #[doc(hidden)]
+    #[inline(always)]
+    #[cfg(target_endian = "little")]
+    pub fn convert_host_byteorder(mut self) -> BtAddr {
+        {
+            let (value_1, value_2) = (&mut self.0).split_at_mut(3);
+            std::mem::swap(&mut value_1[0], &mut value_2[2]);
+            std::mem::swap(&mut value_1[1], &mut value_2[1]);
+            std::mem::swap(&mut value_1[2], &mut value_2[0]);
+        }
+
+        self
+    }
+
+    #[doc(hidden)]
+    #[inline(always)]
+    #[cfg(target_endian = "big")]
+    pub fn convert_host_byteorder(self) -> BtAddr {
+        // Public address structure contents are always big-endian
+        self
+    }
+}
+
+impl ToString for BtAddr {
+    /// Converts `BtAddr` to a string of the format `XX:XX:XX:XX:XX:XX`.
+    fn to_string(&self) -> String {
+        format!(
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
+        )
+    }
+}
+
+impl BtAddr {
+    /// Checks whether the address belongs to the manufacturer with the organizationally unique
+    /// identifier `oui`, i.e. whether it starts with those three bytes. For `00:1A:7D:DA:71:13`
+    /// the OUI is `[0x00, 0x1A, 0x7D]`.
+    ///
+    /// Random addresses (as used by many LE devices) don't contain an OUI.
+    pub fn matches_oui(&self, oui: [u8; 3]) -> bool {
+        self.0[..3] == oui
+    }
+
+    /// Returns the BlueZ D-Bus object path of the device with this address on `adapter` (like
+    /// `"hci0"`), as used by `bluer`: `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF`.
+    pub fn to_dbus_path(&self, adapter: &str) -> String {
+        format!(
+            "/org/bluez/{}/dev_{}",
+            adapter,
+            self.to_string().replace(':', "_")
+        )
+    }
+
+    /// Extracts the address from the BlueZ D-Bus object path of a device, see `to_dbus_path`.
+    /// Returns `None` for paths of other objects, like adapters.
+    pub fn from_dbus_path(path: &str) -> Option<BtAddr> {
+        let mut segments = path.strip_prefix("/org/bluez/")?.split('/');
+        match (segments.next(), segments.next(), segments.next()) {
+            (Some(adapter), Some(device), None) if !adapter.is_empty() => device
+                .strip_prefix("dev_")
+                .filter(|addr| !addr.contains(':'))?
+                .replace('_', ":")
+                .parse()
+                .ok(),
+            _ => None,
+        }
+    }
+
+    /// Parses a list of addresses of the format `XX:XX:XX:XX:XX:XX`, separated by commas and/or
+    /// whitespace, like `"00:11:22:33:44:55, 66:77:88:99:AA:BB"`.
+    pub fn parse_many(s: &str) -> Result<Vec<BtAddr>, AddrParseError> {
+        s.split(|c: char| c == ',' || c.is_ascii_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .enumerate()
+            .map(|(index, entry)| {
+                entry.parse().map_err(|()| AddrParseError {
+                    index,
+                    entry: entry.to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl str::FromStr for BtAddr {
+    type Err = ();
+    /// Converts a string of the format `XX:XX:XX:XX:XX:XX` to a `BtAddr`.
+    ///
+    /// Surrounding ASCII whitespace (like a trailing newline) is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let splits_iter = s.trim_matches(|c: char| c.is_ascii_whitespace()).split(':');
+        let mut addr = BtAddr::any();
+        let mut i = 0;
+        for split_str in splits_iter {
+            if i == 6 || split_str.len() != 2 {
+                return Err(());
+            } // only 6 values (0 <= i <= 5) are allowed
+            let high = (split_str.as_bytes()[0] as char).to_digit(16).ok_or(())?;
+            let low = (split_str.as_bytes()[1] as char).to_digit(16).ok_or(())?;
+            addr.0[i] = (high * 16 + low) as u8;
+            i += 1;
+        }
+        if i != 6 {
+            return Err(());
+        }
+        Ok(addr)
+    }
+}
+
+impl std::convert::TryFrom<u64> for BtAddr {
+    type Error = BtError;
+
+    /// Converts the lower 48 bits of `value` to an address, the most significant byte first:
+    /// `0x001A_7DDA_7113` is `00:1A:7D:DA:71:13`. Fails if any of the upper 16 bits is set.
+    fn try_from(value: u64) -> Result<BtAddr, BtError> {
+        if value >> 48 != 0 {
+            return Err(BtError::Desc(format!(
+                "{:#X} is not a Bluetooth address, it has more than 48 bits",
+                value
+            )));
+        }
+        let bytes = value.to_be_bytes();
+        Ok(BtAddr([
+            bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]))
+    }
+}
+
+impl From<BtAddr> for u64 {
+    /// Converts the address to its 48 bit value, see `TryFrom<u64>`.
+    fn from(addr: BtAddr) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[2..].copy_from_slice(&addr.0);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+impl AsRef<[u8]> for BtAddr {
+    /// The 6 bytes of the address, the most significant byte first (as written in
+    /// `00:1A:7D:DA:71:13`).
+    fn as_ref(&self) -> &[u8] {
+        // The array only requires an alignment of 1, so borrowing it from the packed struct is fine
+        &self.0
+    }
+}
+
+/// Error of `BtAddr::parse_many`: one of the entries is not a valid address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrParseError {
+    /// Position of the invalid entry in the list, starting at 0.
+    pub index: usize,
+
+    /// The invalid entry.
+    pub entry: String,
+}
+
+impl std::fmt::Display for AddrParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid Bluetooth address \"{}\" (entry {})",
+            self.entry,
+            self.index + 1
+        )
+    }
+}
+
+impl std::error::Error for AddrParseError {}
+
+/// The address of a remote device together with the RFCOMM channel of one of its services.
+///
+/// Storing an endpoint allows reconnecting to a known service without another SDP search. The
+/// string form is `XX:XX:XX:XX:XX:XX/<channel>`, which is also used for serde (with the `serde`
+/// feature).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BtEndpoint {
+    /// The MAC address of the device.
+    pub addr: BtAddr,
+
+    /// The RFCOMM channel of the service.
+    pub channel: u8,
+}
+
+impl std::fmt::Display for BtEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr.to_string(), self.channel)
+    }
+}
+
+impl str::FromStr for BtEndpoint {
+    type Err = ();
+    /// Converts a string of the format `XX:XX:XX:XX:XX:XX/<channel>` to a `BtEndpoint`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim_matches(|c: char| c.is_ascii_whitespace()).split('/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(addr), Some(channel), None) => Ok(BtEndpoint {
+                addr: addr.parse()?,
+                channel: channel
+                    .parse()
+                    .ok()
+                    .and_then(|channel| check_rfcomm_channel(channel).ok())
+                    .ok_or(())?,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for BtEndpoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BtEndpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid Bluetooth endpoint \"{}\"", s)))
+    }
+}
+
+/// The address of a Bluetooth socket, like `std::net::SocketAddr`: a device address and an
+/// RFCOMM channel.
+///
+/// Connecting to an address without channel determines the channel through SDP. `BtAddr`s convert
+/// into such addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BtSocketAddr {
+    /// The address of the device.
+    pub addr: BtAddr,
+    /// The RFCOMM channel, `None` to look it up through SDP.
+    pub channel: Option<u8>,
+}
+
+impl BtSocketAddr {
+    /// Creates an address of `channel` on the device `addr`.
+    pub fn new(addr: BtAddr, channel: Option<u8>) -> BtSocketAddr {
+        BtSocketAddr { addr, channel }
+    }
+}
+
+impl From<BtAddr> for BtSocketAddr {
+    fn from(addr: BtAddr) -> BtSocketAddr {
+        BtSocketAddr::new(addr, None)
+    }
+}
+
+impl From<BtEndpoint> for BtSocketAddr {
+    fn from(endpoint: BtEndpoint) -> BtSocketAddr {
+        BtSocketAddr::new(endpoint.addr, Some(endpoint.channel))
+    }
+}
+
+impl From<(BtAddr, u8)> for BtSocketAddr {
+    fn from((addr, channel): (BtAddr, u8)) -> BtSocketAddr {
+        BtSocketAddr::new(addr, Some(channel))
+    }
+}
+
+/// A device with its a name and address.
+///
+/// Like `BtAddr`, devices are plain data and therefore `Send` and `Sync`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtDevice {
+    /// The name of the device.
+    ///
+    /// Invalid UTF-8 is replaced with `U+FFFD`, see `name_raw` for the bytes as received.
+    pub name: String,
+
+    /// The name as received from the device, for names in other encodings (like Latin-1 or
+    /// Shift-JIS). Empty if the name couldn't be read.
+    pub name_raw: Vec<u8>,
+
+    /// The MAC address of the device.
+    pub addr: BtAddr,
+
+    /// The received signal strength in dBm, if it was measured during the scan.
+    pub rssi: Option<i8>,
+
+    /// The class of device, if it was reported during the scan.
+    pub class: Option<DeviceClass>,
+
+    /// The paging parameters reported during the scan, see `BtSocket::connect_with_paging`.
+    pub paging: Option<PagingParams>,
+
+    /// The appearance, if the device reported it in its EIR or advertisements (see
+    /// `discover_devices`).
+    pub appearance: Option<Appearance>,
+}
+
+/// Parameters an inquiry reports that speed up paging (connecting to) the device.
+///
+/// Parameters are equal if they page the device the same way, no matter when they were reported.
+#[derive(Debug, Clone, Copy)]
+pub struct PagingParams {
+    /// The page scan repetition mode of the device.
+    pub pscan_rep_mode: u8,
+
+    /// The offset between the device's clock and the local clock.
+    pub clock_offset: u16,
+
+    /// When the parameters were reported. The clock offset drifts, so they become useless after
+    /// some time.
+    pub found_at: time::Instant,
+}
+
+impl PartialEq for PagingParams {
+    fn eq(&self, other: &Self) -> bool {
+        self.pscan_rep_mode == other.pscan_rep_mode && self.clock_offset == other.clock_offset
+    }
+}
+
+impl Eq for PagingParams {}
+
+/// A local Bluetooth adapter (controller).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Adapter {
+    /// The device id, as used in `hci_open_dev()`.
+    pub id: u16,
+
+    /// The kernel name of the adapter, like `hci0`.
+    pub name: String,
+
+    /// The MAC address of the adapter.
+    pub addr: BtAddr,
+
+    /// Whether the adapter is up.
+    pub up: bool,
+
+    /// The friendly name of the adapter that remote devices see, if the adapter is up.
+    pub friendly_name: Option<String>,
+}
+
+/// The 24 bit class of device (CoD) a device reports during an inquiry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DeviceClass(pub u32);
+
+impl DeviceClass {
+    /// Assembles the class from the three bytes as transmitted (least significant byte first).
+    pub fn from_bytes(bytes: [u8; 3]) -> DeviceClass {
+        DeviceClass(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    }
+
+    /// The major device class (bits 8 to 12).
+    pub fn major_class(&self) -> u8 {
+        ((self.0 >> 8) & 0x1F) as u8
+    }
+
+    /// The minor device class (bits 2 to 7), whose meaning depends on the major class.
+    pub fn minor_class(&self) -> u8 {
+        ((self.0 >> 2) & 0x3F) as u8
+    }
+
+    /// The major service classes (bits 13 to 23) as bit field.
+    pub fn service_classes(&self) -> u16 {
+        ((self.0 >> 13) & 0x07FF) as u16
+    }
+
+    /// A coarse category of the device, e.g. to pick an icon for it.
+    pub fn category(&self) -> DeviceCategory {
+        match self.major_class() {
+            0x01 => DeviceCategory::Computer,
+            0x02 => DeviceCategory::Phone,
+            0x04 => DeviceCategory::AudioVideo,
+            0x05 => DeviceCategory::Peripheral,
+            0x07 => DeviceCategory::Wearable,
+            0x08 => DeviceCategory::Toy,
+            0x09 => DeviceCategory::Health,
+            _ => DeviceCategory::Uncategorized,
         }
     }
 }
 
-impl From<std::io::Error> for BtError {
-    fn from(error: std::io::Error) -> Self {
-        BtError::IoError(error)
+/// Names of the major service classes, by bit of `DeviceClass::service_classes`
+const SERVICE_CLASS_NAMES: [&str; 11] = [
+    "Limited Discoverable",
+    "LE Audio",
+    "Reserved",
+    "Positioning",
+    "Networking",
+    "Rendering",
+    "Capturing",
+    "Object Transfer",
+    "Audio",
+    "Telephony",
+    "Information",
+];
+
+/// Names of the minor classes of the major classes that enumerate them, by minor class
+const COMPUTER_NAMES: &[&str] = &[
+    "",
+    "Desktop",
+    "Server",
+    "Laptop",
+    "Handheld PC/PDA",
+    "Palm-size PC/PDA",
+    "Wearable Computer",
+    "Tablet",
+];
+const PHONE_NAMES: &[&str] = &[
+    "",
+    "Cellular",
+    "Cordless",
+    "Smartphone",
+    "Modem/Voice Gateway",
+    "ISDN Access",
+];
+const AUDIO_VIDEO_NAMES: &[&str] = &[
+    "",
+    "Wearable Headset",
+    "Hands-free",
+    "",
+    "Microphone",
+    "Loudspeaker",
+    "Headphones",
+    "Portable Audio",
+    "Car Audio",
+    "Set-top Box",
+    "HiFi Audio",
+    "VCR",
+    "Video Camera",
+    "Camcorder",
+    "Video Monitor",
+    "Video Display and Loudspeaker",
+    "Video Conferencing",
+    "",
+    "Gaming/Toy",
+];
+const WEARABLE_NAMES: &[&str] = &["", "Wristwatch", "Pager", "Jacket", "Helmet", "Glasses"];
+const TOY_NAMES: &[&str] = &["", "Robot", "Vehicle", "Doll", "Controller", "Game"];
+const HEALTH_NAMES: &[&str] = &[
+    "",
+    "Blood Pressure Monitor",
+    "Thermometer",
+    "Weighing Scale",
+    "Glucose Meter",
+    "Pulse Oximeter",
+    "Heart Rate Monitor",
+    "Health Data Display",
+    "Step Counter",
+    "Body Composition Analyzer",
+    "Peak Flow Monitor",
+    "Medication Monitor",
+    "Knee Prosthesis",
+    "Ankle Prosthesis",
+    "Generic Health Manager",
+    "Personal Mobility Device",
+];
+// The peripheral minor class combines a keyboard/pointing part (bits 6 and 7) with a type (bits 2
+// to 5), imaging devices set a bit (4 to 7) for each of their functions
+const PERIPHERAL_NAMES: &[&str] = &[
+    "",
+    "Keyboard",
+    "Pointing Device",
+    "Keyboard/Pointing Device",
+];
+const PERIPHERAL_TYPE_NAMES: &[&str] = &[
+    "",
+    "Joystick",
+    "Gamepad",
+    "Remote Control",
+    "Sensing Device",
+    "Digitizer Tablet",
+    "Card Reader",
+    "Digital Pen",
+    "Handheld Scanner",
+    "Gestural Input Device",
+];
+const IMAGING_NAMES: [&str; 4] = ["Display", "Camera", "Scanner", "Printer"];
+
+impl DeviceClass {
+    /// The name of the major class
+    fn major_class_name(&self) -> &'static str {
+        match self.major_class() {
+            0x00 => "Miscellaneous",
+            0x01 => "Computer",
+            0x02 => "Phone",
+            0x03 => "Network Access Point",
+            0x04 => "Audio/Video",
+            0x05 => "Peripheral",
+            0x06 => "Imaging",
+            0x07 => "Wearable",
+            0x08 => "Toy",
+            0x09 => "Health",
+            _ => "Uncategorized",
+        }
     }
-}
 
-/// A 6-byte long MAC address.
-#[repr(C, packed)]
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct BtAddr(pub [u8; 6]);
+    /// The names the minor class decodes to, empty if it is unknown or unspecified
+    fn minor_class_names(&self) -> Vec<&'static str> {
+        let minor = usize::from(self.minor_class());
+        let lookup = |names: &[&'static str], index: usize| names.get(index).copied();
+        let names = match self.major_class() {
+            0x01 => vec![lookup(COMPUTER_NAMES, minor)],
+            0x02 => vec![lookup(PHONE_NAMES, minor)],
+            0x04 => vec![lookup(AUDIO_VIDEO_NAMES, minor)],
+            0x05 => vec![
+                lookup(PERIPHERAL_NAMES, minor >> 4),
+                lookup(PERIPHERAL_TYPE_NAMES, minor & 0x0F),
+            ],
+            0x06 => (0..4)
+                .filter(|bit| minor & (0x04 << bit) != 0)
+                .map(|bit| Some(IMAGING_NAMES[bit]))
+                .collect(),
+            0x07 => vec![lookup(WEARABLE_NAMES, minor)],
+            0x08 => vec![lookup(TOY_NAMES, minor)],
+            0x09 => vec![lookup(HEALTH_NAMES, minor)],
+            _ => vec![],
+        };
+        names
+            .into_iter()
+            .flatten()
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
 
-impl std::fmt::Debug for BtAddr {
+impl std::fmt::Display for DeviceClass {
+    /// Formats the class like `Audio/Video › Wearable Headset (services: Audio, Rendering)`.
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
-        )
+        write!(f, "{}", self.major_class_name())?;
+        let minor = self.minor_class_names();
+        if !minor.is_empty() {
+            write!(f, " › {}", minor.join(", "))?;
+        }
+        let services: Vec<_> = (0..SERVICE_CLASS_NAMES.len())
+            .rev()
+            .filter(|bit| self.service_classes() & (1 << bit) != 0)
+            .map(|bit| SERVICE_CLASS_NAMES[bit])
+            .collect();
+        if !services.is_empty() {
+            write!(f, " (services: {})", services.join(", "))?;
+        }
+        Ok(())
     }
 }
 
-impl BtAddr {
-    /// Returns the MAC address `00:00:00:00:00:00`
-    pub fn any() -> BtAddr {
-        BtAddr([0, 0, 0, 0, 0, 0])
-    }
+/// Coarse device categories derived from the major device class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceCategory {
+    /// Cellular and cordless phones, smartphones, ...
+    Phone,
+    /// Desktops, laptops, PDAs, ...
+    Computer,
+    /// Headsets, speakers, TVs, ...
+    AudioVideo,
+    /// Keyboards, mice, game controllers, ...
+    Peripheral,
+    /// Watches, glasses, ...
+    Wearable,
+    /// Robots, dolls, game consoles, ...
+    Toy,
+    /// Health monitors, scales, ...
+    Health,
+    /// Any other (or no) major class, like network access points or printers.
+    Uncategorized,
+}
 
-    /// Linux lower-layers actually hold the address in native byte-order
-    /// althrough they are always displayed in network byte-order
-    #[doc(hidden)]
-    #[inline(always)]
-    #[cfg(target_endian = "little")]
-    pub fn convert_host_byteorder(mut self) -> BtAddr {
-        {
-            let (value_1, value_2) = (&mut self.0).split_at_mut(3);
-            std::mem::swap(&mut value_1[0], &mut value_2[2]);
-            std::mem::swap(&mut value_1[1], &mut value_2[1]);
-            std::mem::swap(&mut value_1[2], &mut value_2[0]);
-        }
+/// The devices found by a scan, with helpers for presenting them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScanResults(pub Vec<BtDevice>);
 
-        self
+impl ScanResults {
+    /// Sorts the devices by signal strength, strongest first. Devices without a known signal
+    /// strength are sorted last.
+    pub fn sort_by_rssi(&mut self) {
+        self.0.sort_by_key(|device| std::cmp::Reverse(device.rssi));
     }
 
-    #[doc(hidden)]
-    #[inline(always)]
-    #[cfg(target_endian = "big")]
-    pub fn convert_host_byteorder(self) -> BtAddr {
-        // Public address structure contents are always big-endian
-        self
+    /// Sorts the devices alphabetically (ignoring case) by name.
+    pub fn sort_by_name(&mut self) {
+        self.0
+            .sort_by_cached_key(|device| device.name.to_lowercase());
     }
 }
 
-impl ToString for BtAddr {
-    /// Converts `BtAddr` to a string of the format `XX:XX:XX:XX:XX:XX`.
-    fn to_string(&self) -> String {
-        format!(
-            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
-        )
+impl From<Vec<BtDevice>> for ScanResults {
+    fn from(devices: Vec<BtDevice>) -> ScanResults {
+        ScanResults(devices)
     }
 }
 
-impl str::FromStr for BtAddr {
-    type Err = ();
-    /// Converts a string of the format `XX:XX:XX:XX:XX:XX` to a `BtAddr`.
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let splits_iter = s.split(':');
-        let mut addr = BtAddr::any();
-        let mut i = 0;
-        for split_str in splits_iter {
-            if i == 6 || split_str.len() != 2 {
-                return Err(());
-            } // only 6 values (0 <= i <= 5) are allowed
-            let high = (split_str.as_bytes()[0] as char).to_digit(16).ok_or(())?;
-            let low = (split_str.as_bytes()[1] as char).to_digit(16).ok_or(())?;
-            addr.0[i] = (high * 16 + low) as u8;
-            i += 1;
-        }
-        if i != 6 {
-            return Err(());
-        }
-        Ok(addr)
+impl std::ops::Deref for ScanResults {
+    type Target = Vec<BtDevice>;
+
+    fn deref(&self) -> &Vec<BtDevice> {
+        &self.0
     }
 }
 
-/// A device with its a name and address.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct BtDevice {
-    /// The name of the device.
-    pub name: String,
+impl std::ops::DerefMut for ScanResults {
+    fn deref_mut(&mut self) -> &mut Vec<BtDevice> {
+        &mut self.0
+    }
+}
 
-    /// The MAC address of the device.
-    pub addr: BtAddr,
+impl IntoIterator for ScanResults {
+    type Item = BtDevice;
+    type IntoIter = std::vec::IntoIter<BtDevice>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
 }
 
-/// The Bluetooth protocol you can use with this libary.
+/// The Bluetooth protocol you can use with this libary, together with its connection
+/// parameters.
 ///
-/// Will probably be always `RFCOMM`.
-#[derive(Clone, Copy, Debug)]
+/// The default is an RFCOMM connection whose channel is determined through SDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum BtProtocol {
-    // L2CAP = BTPROTO_L2CAP,
     // HCI = BTPROTO_HCI,
     // SCO = BTPROTO_SCO,
     // BNEP = BTPROTO_BNEP,
@@ -309,13 +2312,224 @@ pub enum BtProtocol {
     // HIDP = BTPROTO_HIDP,
     // AVDTP = BTPROTO_AVDTP
     /// Serial RFCOMM connection to a bluetooth device.
-    RFCOMM, // = BTPROTO_RFCOMM */
+    ///
+    /// If `channel` is `None` the channel of the serial port service is looked up through SDP
+    /// when connecting, otherwise the given channel is connected to directly.
+    Rfcomm {
+        /// The RFCOMM channel to connect to.
+        channel: Option<u8>,
+    },
+
+    /// L2CAP connection to the given PSM (Protocol/Service Multiplexer) of a bluetooth device.
+    L2cap {
+        /// The PSM to connect to.
+        psm: u16,
+    },
+}
+
+impl BtProtocol {
+    /// Serial RFCOMM connection whose channel is determined through SDP.
+    pub const RFCOMM: BtProtocol = BtProtocol::Rfcomm { channel: None };
+}
+
+// Not derived like the other enums: `#[default]` only supports variants without fields
+impl Default for BtProtocol {
+    fn default() -> Self {
+        BtProtocol::Rfcomm { channel: None }
+    }
+}
+
+/// The physical transport to reach a remote device over.
+///
+/// Dual-mode devices can be reachable over both transports, with different address types for
+/// LE. RFCOMM is only available over BR/EDR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum BtTransport {
+    /// Classic Bluetooth (Basic Rate / Enhanced Data Rate).
+    #[default]
+    BrEdr,
+    /// Bluetooth Low Energy, to a device with a public address.
+    LePublic,
+    /// Bluetooth Low Energy, to a device with a random address.
+    LeRandom,
+}
+
+/// Flow control state of a connection, see `BtSocket::flow_info`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlowInfo {
+    /// Bytes written to the socket that the kernel didn't transmit yet.
+    pub unsent: usize,
+
+    /// Bytes received that weren't read yet.
+    pub unread: usize,
+
+    /// RFCOMM credits granted to the remote device, i.e. frames it may still send.
+    pub rx_credits: Option<u8>,
+
+    /// RFCOMM credits granted by the remote device, i.e. frames that may still be sent. Writes
+    /// stall while this is 0.
+    pub tx_credits: Option<u32>,
+}
+
+/// The LMP features supported by a remote device (page 0 of its feature mask), see
+/// `remote_features`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemoteFeatures {
+    /// The feature mask as received, see the Bluetooth Core Specification, Vol 2, Part C, 3.3.
+    pub raw: [u8; 8],
+
+    /// Packets spanning 3 slots.
+    pub three_slot_packets: bool,
+
+    /// Packets spanning 5 slots.
+    pub five_slot_packets: bool,
+
+    /// Link encryption.
+    pub encryption: bool,
+
+    /// Switching the master and slave roles of a link.
+    pub role_switch: bool,
+
+    /// Hold mode.
+    pub hold_mode: bool,
+
+    /// Sniff mode, to save power while idle.
+    pub sniff_mode: bool,
+
+    /// Power control requests.
+    pub power_control_requests: bool,
+
+    /// SCO links, e.g. for headset audio.
+    pub sco_link: bool,
+
+    /// Power control.
+    pub power_control: bool,
+
+    /// EDR ACL packets at 2 Mb/s.
+    pub edr_acl_2mbps: bool,
+
+    /// EDR ACL packets at 3 Mb/s.
+    pub edr_acl_3mbps: bool,
+
+    /// Extended SCO (eSCO) links.
+    pub extended_sco_link: bool,
+
+    /// LE on the same controller.
+    pub le_supported: bool,
+
+    /// EDR ACL packets spanning 3 slots.
+    pub three_slot_edr_acl: bool,
+
+    /// EDR ACL packets spanning 5 slots.
+    pub five_slot_edr_acl: bool,
+
+    /// Sniff subrating.
+    pub sniff_subrating: bool,
+
+    /// EDR eSCO packets at 2 Mb/s.
+    pub edr_esco_2mbps: bool,
+
+    /// EDR eSCO packets at 3 Mb/s.
+    pub edr_esco_3mbps: bool,
+
+    /// Extended inquiry response.
+    pub extended_inquiry_response: bool,
+
+    /// Secure simple pairing.
+    pub secure_simple_pairing: bool,
+
+    /// Further feature pages, which this library doesn't read.
+    pub extended_features: bool,
+}
+
+impl RemoteFeatures {
+    /// Decodes the feature mask as received (least significant byte first).
+    pub fn from_bytes(raw: [u8; 8]) -> RemoteFeatures {
+        let bit = |byte: usize, bit: u8| raw[byte] & (1 << bit) != 0;
+        RemoteFeatures {
+            raw,
+            three_slot_packets: bit(0, 0),
+            five_slot_packets: bit(0, 1),
+            encryption: bit(0, 2),
+            role_switch: bit(0, 5),
+            hold_mode: bit(0, 6),
+            sniff_mode: bit(0, 7),
+            power_control_requests: bit(1, 1),
+            sco_link: bit(1, 3),
+            power_control: bit(2, 2),
+            edr_acl_2mbps: bit(3, 1),
+            edr_acl_3mbps: bit(3, 2),
+            extended_sco_link: bit(3, 7),
+            le_supported: bit(4, 6),
+            three_slot_edr_acl: bit(4, 7),
+            five_slot_edr_acl: bit(5, 0),
+            sniff_subrating: bit(5, 1),
+            edr_esco_2mbps: bit(5, 5),
+            edr_esco_3mbps: bit(5, 6),
+            extended_inquiry_response: bit(6, 0),
+            secure_simple_pairing: bit(6, 3),
+            extended_features: bit(7, 7),
+        }
+    }
+}
+
+/// How `BtSocket` reads treat line endings, see `BtSocket::set_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum BtLineMode {
+    /// Data is passed through unchanged.
+    #[default]
+    Raw,
+    /// CR LF and lone CR line endings are translated to LF, like a tty with `ICRNL` that also
+    /// swallows the LF of a CR LF.
+    Cooked,
+}
+
+/// The role of the local side in the baseband link of an RFCOMM connection, see
+/// `BtSocket::set_rfcomm_role`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum BtRole {
+    /// The roles are left to the controllers: the side establishing the link usually is the
+    /// master, but either side may request a role switch.
+    #[default]
+    Any,
+    /// The local side insists on being the master, and switches roles if the remote device
+    /// established the link (`RFCOMM_LM_MASTER`).
+    Master,
+}
+
+/// Check that `channel` is a valid RFCOMM server channel (1 to 30).
+pub(crate) fn check_rfcomm_channel(channel: u8) -> Result<u8, BtError> {
+    if (1..=30).contains(&channel) {
+        Ok(channel)
+    } else {
+        Err(BtError::Desc("RFCOMM channel must be 1..=30".to_string()))
+    }
 }
 
 impl BtDevice {
     /// Create a new `BtDevice` manually from a name and addr.
     pub fn new(name: String, addr: BtAddr) -> BtDevice {
-        BtDevice { name, addr }
+        BtDevice {
+            name_raw: name.clone().into_bytes(),
+            name,
+            addr,
+            rssi: None,
+            class: None,
+            paging: None,
+            appearance: None,
+        }
+    }
+
+    /// Sets the received signal strength (in dBm) of the device.
+    pub fn with_rssi(mut self, rssi: i8) -> BtDevice {
+        self.rssi = Some(rssi);
+        self
+    }
+
+    /// Sets the class of the device.
+    pub fn with_class(mut self, class: DeviceClass) -> BtDevice {
+        self.class = Some(class);
+        self
     }
 }
 
@@ -324,6 +2538,11 @@ mod tests {
     use super::*;
     use std::str::FromStr;
 
+    fn socket_pair() -> (BtSocket, StdUnixStream) {
+        let (socket, remote) = platform::socket_pair();
+        (BtSocket::from(socket), remote)
+    }
+
     #[test]
     fn btaddr_from_string() {
         match BtAddr::from_str("00:00:00:00:00:00") {
@@ -337,6 +2556,8 @@ mod tests {
             "00:00:00:00:00:00:00",
             "-00:00:00:00:00:00",
             "0G:00:00:00:00:00",
+            "00:00:00: 00:00:00",
+            "00:00:00:00:00:0 0",
         ];
         for &s in &fail_strings {
             match BtAddr::from_str(s) {
@@ -346,6 +2567,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn btaddr_from_string_ignores_surrounding_whitespace() {
+        assert_eq!(
+            BtAddr::from_str(" 01:02:03:04:05:06\n"),
+            Ok(BtAddr([1, 2, 3, 4, 5, 6]))
+        );
+        assert_eq!(
+            BtAddr::from_str("\t01:02:03:04:05:06 \r\n"),
+            Ok(BtAddr([1, 2, 3, 4, 5, 6]))
+        );
+    }
+
+    #[test]
+    fn btendpoint_roundtrips_to_from_str() {
+        let endpoint = BtEndpoint {
+            addr: BtAddr([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]),
+            channel: 1,
+        };
+        assert_eq!(endpoint.to_string(), "AA:BB:CC:DD:EE:FF/1");
+        assert_eq!(BtEndpoint::from_str("AA:BB:CC:DD:EE:FF/1"), Ok(endpoint));
+
+        for &s in &[
+            "AA:BB:CC:DD:EE:FF",
+            "AA:BB:CC:DD:EE:FF/",
+            "AA:BB:CC:DD:EE:FF/1/2",
+        ] {
+            assert!(BtEndpoint::from_str(s).is_err());
+        }
+    }
+
+    #[test]
+    fn builds_complete_device() {
+        let device = BtDevice::new("Headset".to_string(), BtAddr::any())
+            .with_rssi(-60)
+            .with_class(DeviceClass(0x240404));
+        assert_eq!(device.rssi, Some(-60));
+        assert_eq!(device.class, Some(DeviceClass(0x240404)));
+    }
+
+    #[test]
+    fn formats_device_class() {
+        assert_eq!(
+            DeviceClass(0x240404).to_string(),
+            "Audio/Video › Wearable Headset (services: Audio, Rendering)"
+        );
+        assert_eq!(
+            DeviceClass(0x5A020C).to_string(),
+            "Phone › Smartphone (services: Telephony, Object Transfer, Capturing, Networking)"
+        );
+        assert_eq!(DeviceClass(0x000540).to_string(), "Peripheral › Keyboard");
+        assert_eq!(
+            DeviceClass(0x0006A0).to_string(),
+            "Imaging › Camera, Printer"
+        );
+        assert_eq!(DeviceClass(0x001F00).to_string(), "Uncategorized");
+    }
+
+    #[test]
+    fn device_class_categories() {
+        // Smartphone with telephony, object transfer and networking services
+        let phone = DeviceClass::from_bytes([0x0C, 0x02, 0x5A]);
+        assert_eq!(phone, DeviceClass(0x5A020C));
+        assert_eq!(phone.major_class(), 0x02);
+        assert_eq!(phone.minor_class(), 0x03);
+        assert_eq!(phone.service_classes(), 0x2D0);
+        assert_eq!(phone.category(), DeviceCategory::Phone);
+
+        assert_eq!(DeviceClass(0x240404).category(), DeviceCategory::AudioVideo);
+        assert_eq!(
+            DeviceClass(0x000600).category(),
+            DeviceCategory::Uncategorized
+        );
+        assert_eq!(
+            DeviceClass(0x001F00).category(),
+            DeviceCategory::Uncategorized
+        );
+    }
+
+    #[test]
+    fn converts_to_io_error() {
+        let error = std::io::Error::from(BtError::Errno(
+            libc::ECONNREFUSED as u32,
+            "Connection refused".to_string(),
+        ));
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(error.to_string(), "Connection refused");
+
+        let error = std::io::Error::from(BtError::Timeout { transferred: 0 });
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+        let error = std::io::Error::from(BtError::IoError(std::io::ErrorKind::BrokenPipe.into()));
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
     #[test]
     fn btaddr_to_string() {
         assert_eq!(BtAddr::any().to_string(), "00:00:00:00:00:00");
@@ -363,12 +2678,306 @@ mod tests {
         );
     }
 
+    #[test]
+    fn btaddr_converts_from_u64() {
+        use std::convert::TryFrom;
+
+        let addr = BtAddr::try_from(0x001A_7DDA_7113).unwrap();
+        assert_eq!(addr, BtAddr([0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x13]));
+        assert_eq!(u64::from(addr), 0x001A_7DDA_7113);
+        assert!(BtAddr::try_from(0xFFFF_FFFF_FFFF).is_ok());
+        assert!(BtAddr::try_from(1 << 48).is_err());
+        assert!(BtAddr::try_from(-1i64 as u64).is_err());
+        assert_eq!(addr.as_ref(), &[0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x13]);
+    }
+
+    #[test]
+    fn btaddr_matches_oui() {
+        let addr = BtAddr([0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x13]);
+        assert!(addr.matches_oui([0x00, 0x1A, 0x7D]));
+        assert!(!addr.matches_oui([0x13, 0x71, 0xDA]));
+    }
+
+    #[test]
+    fn btaddr_byteorder_round_trips() {
+        let addr = BtAddr([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(addr.convert_host_byteorder().convert_host_byteorder(), addr);
+        assert_eq!(BtAddr::any().convert_host_byteorder(), BtAddr::any());
+
+        // The kernel stores the least significant byte first
+        #[cfg(target_endian = "little")]
+        assert_eq!(
+            addr.convert_host_byteorder(),
+            BtAddr([0x55, 0x44, 0x33, 0x22, 0x11, 0x00])
+        );
+    }
+
+    #[test]
+    fn btaddr_dbus_path() {
+        let addr = BtAddr([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0F]);
+        let path = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_0F";
+        assert_eq!(addr.to_dbus_path("hci0"), path);
+        assert_eq!(BtAddr::from_dbus_path(path), Some(addr));
+
+        assert_eq!(BtAddr::from_dbus_path("/org/bluez/hci0"), None);
+        assert_eq!(BtAddr::from_dbus_path("/org/bluez/hci0/dev_AA_BB"), None);
+        assert_eq!(
+            BtAddr::from_dbus_path("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_0F/service0001"),
+            None
+        );
+    }
+
+    #[test]
+    fn btaddr_parse_many() {
+        assert_eq!(
+            BtAddr::parse_many("00:11:22:33:44:55,66:77:88:99:AA:BB 01:02:03:04:05:06, \n"),
+            Ok(vec![
+                BtAddr([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                BtAddr([0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB]),
+                BtAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            ])
+        );
+        assert_eq!(
+            BtAddr::parse_many("00:11:22:33:44:55, 00:11:22"),
+            Err(AddrParseError {
+                index: 1,
+                entry: "00:11:22".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn btprotocol_defaults_to_rfcomm_via_sdp() {
+        assert_eq!(BtProtocol::default(), BtProtocol::Rfcomm { channel: None });
+        assert_eq!(BtProtocol::default(), BtProtocol::RFCOMM);
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let errno = |errno: i32| BtError::Errno(errno as u32, String::new());
+        assert!(errno(libc::EHOSTDOWN).is_retryable());
+        assert!(errno(libc::EBUSY)
+            .context("while connecting")
+            .is_retryable());
+        assert!(BtError::Timeout { transferred: 0 }.is_retryable());
+        assert!(BtError::IoError(std::io::Error::from_raw_os_error(libc::EAGAIN)).is_retryable());
+        assert!(!errno(libc::EACCES).is_retryable());
+        assert!(!errno(libc::EAFNOSUPPORT).is_retryable());
+        assert!(!BtError::Desc("Invalid address".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn user_messages() {
+        let refused = BtError::Errno(libc::ECONNREFUSED as u32, "Connection refused".to_string());
+        assert!(refused
+            .user_message()
+            .starts_with("The device refused the connection"));
+        assert_eq!(
+            refused.context("Connecting failed").user_message(),
+            BtError::from(std::io::Error::from_raw_os_error(libc::ECONNREFUSED)).user_message()
+        );
+        assert!(BtError::Timeout { transferred: 0 }
+            .user_message()
+            .starts_with("The device didn't respond"));
+        assert_eq!(
+            BtError::Desc("No RFCOMM service".to_string())
+                .context("SDP search failed")
+                .user_message(),
+            "SDP search failed: No RFCOMM service"
+        );
+    }
+
+    #[test]
+    fn context_keeps_original_error() {
+        let error = BtError::Errno(
+            libc::ECONNREFUSED as u32,
+            "Failed to connect() to target device".to_string(),
+        )
+        .context("while connecting to 00:11:22:33:44:55");
+
+        assert_eq!(
+            error.to_string(),
+            "while connecting to 00:11:22:33:44:55: Failed to connect() to target device"
+        );
+        assert!(matches!(
+            std::error::Error::source(&error)
+                .unwrap()
+                .downcast_ref::<BtError>(),
+            Some(BtError::Errno(errno, _)) if *errno == libc::ECONNREFUSED as u32
+        ));
+        assert_eq!(
+            std::io::Error::from(error).kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn sorts_scan_results() {
+        let device = |name: &str, rssi| BtDevice {
+            rssi,
+            ..BtDevice::new(name.to_string(), BtAddr::any())
+        };
+        let mut results = ScanResults(vec![
+            device("b", None),
+            device("C", Some(-80)),
+            device("a", Some(-40)),
+        ]);
+
+        results.sort_by_rssi();
+        let names: Vec<_> = results.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["a", "C", "b"]);
+
+        results.sort_by_name();
+        let names: Vec<_> = results.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "C"]);
+    }
+
     #[cfg(not(feature = "test_without_hardware"))]
     #[test]
     fn creates_rfcomm_socket() {
         BtSocket::new(BtProtocol::RFCOMM).unwrap();
     }
 
+    #[test]
+    fn public_types_are_thread_safe() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<BtSocket>();
+        assert_send_sync::<BtAddr>();
+        assert_send_sync::<BtDevice>();
+        assert_send_sync::<BtError>();
+        #[cfg(feature = "mio")]
+        assert_send::<BtSocketConnect<'static>>();
+        assert_send::<platform::BtSocketConnect<'static>>();
+
+        // The connect future can be spawned on multi-threaded runtimes
+        fn assert_send_future<F: std::future::Future + Send>(_: F) {}
+        let _ = |socket: &mut BtSocket| assert_send_future(socket.connect_future(BtAddr::any()));
+    }
+
+    #[test]
+    fn owned_fd_conversion_rejects_other_sockets() {
+        let (fd, _) = StdUnixStream::pair().unwrap();
+        let fd = std::os::unix::io::OwnedFd::from(fd);
+        assert!(<BtSocket as std::convert::TryFrom<_>>::try_from(fd).is_err());
+    }
+
+    #[test]
+    fn buffered_socket_reads_lines_and_buffers_writes() {
+        let (socket, mut remote) = socket_pair();
+        let mut socket = socket.buffered(64, 16);
+
+        remote.write_all(b"AT+OK\nAT+ERROR\n").unwrap();
+        let mut line = String::new();
+        socket.read_line(&mut line).unwrap();
+        assert_eq!(line, "AT+OK\n");
+
+        socket.write_all(b"AT").unwrap();
+        remote.set_nonblocking(true).unwrap();
+        assert_eq!(
+            remote.read(&mut [0; 16]).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        socket.write_all(b"+RESET\n").unwrap();
+        socket.flush().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(remote.read(&mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"AT+RESET\n");
+    }
+
+    #[test]
+    fn streams_received_chunks() {
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let (socket, mut remote) = socket_pair();
+        let mut stream = socket.into_stream(4);
+        let mut next = || {
+            async_std::task::block_on(std::future::poll_fn(|cx| {
+                Pin::new(&mut stream).poll_next(cx)
+            }))
+        };
+
+        remote.write_all(b"$GPGGA").unwrap();
+        assert_eq!(next().unwrap().unwrap(), b"$GPG");
+        assert_eq!(next().unwrap().unwrap(), b"GA");
+        drop(remote);
+        assert!(next().is_none());
+    }
+
+    #[test]
+    fn splits_into_buffered_halves() {
+        let (socket, mut remote) = socket_pair();
+        let (mut reader, mut writer) = socket.into_buffered_split(64, 64);
+
+        let writing = std::thread::spawn(move || {
+            writer.write_all(b"AT\r\n").unwrap();
+            writer.flush().unwrap();
+            writer
+        });
+        remote.write_all(b"OK\r\nERROR\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "OK\r\n");
+        assert_eq!(reader.buffer(), b"ERROR\r\n");
+
+        let writer = writing.join().unwrap();
+        let mut request = [0; 4];
+        remote.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"AT\r\n");
+        assert_eq!(reader.get_ref().bytes_read(), 11);
+        assert_eq!(reader.get_ref().bytes_written(), 4);
+
+        // The connection stays open until both halves are dropped
+        drop(reader);
+        remote.write_all(b"RING").unwrap();
+        drop(writer);
+        assert!(remote.write_all(b"RING").is_err());
+    }
+
+    #[test]
+    fn read_exact_bt_reports_eof() {
+        let (mut socket, mut remote) = socket_pair();
+
+        socket.write_all_bt(b"AT").unwrap();
+        let mut request = [0; 2];
+        remote.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"AT");
+        remote.write_all(b"OK\r\n").unwrap();
+        drop(remote);
+        let mut buf = [0; 3];
+        socket.read_exact_bt(&mut buf).unwrap();
+        assert_eq!(&buf, b"OK\r");
+        assert!(matches!(
+            socket.read_exact_bt(&mut buf),
+            Err(BtError::UnexpectedEof { transferred: 1 })
+        ));
+    }
+
+    #[test]
+    fn write_message_reports_partial_writes() {
+        let (mut socket, mut remote) = socket_pair();
+
+        socket.write_message(b"AT").unwrap();
+        let mut buf = [0; 2];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"AT");
+
+        // The message doesn't fit into the socket buffer, a non-blocking write stops midway
+        socket.get_stream_std().set_nonblocking(true).unwrap();
+        match socket.write_message(&vec![0; 16 * 1024 * 1024]) {
+            Err(BtError::PartialWrite { transferred, error }) => {
+                assert!(transferred > 0);
+                assert_eq!(
+                    std::io::Error::from(*error).kind(),
+                    std::io::ErrorKind::WouldBlock
+                );
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
     #[cfg(not(feature = "test_without_hardware"))]
     #[test]
     fn scans_devices() {
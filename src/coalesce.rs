@@ -0,0 +1,216 @@
+//! Batching of small writes, see `CoalescingWriter`.
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread, time,
+};
+
+/// A writer that collects small writes and passes them on in larger chunks, like Nagle's
+/// algorithm does for TCP.
+///
+/// Buffered data is written once it reaches the size threshold or once the oldest buffered byte
+/// waited for the delay, whichever happens first. The delay is enforced by a background thread,
+/// errors it runs into are returned by the next `write` or `flush`.
+///
+/// With `set_nodelay(true)`, writes are passed on immediately, for latency critical traffic.
+/// Dropping the writer flushes the remaining data.
+pub struct CoalescingWriter<W: Write + Send + 'static> {
+    shared: Arc<Shared<W>>,
+    threshold: usize,
+}
+
+struct Shared<W> {
+    state: Mutex<State<W>>,
+    changed: Condvar,
+    delay: time::Duration,
+}
+
+struct State<W> {
+    writer: W,
+    buf: Vec<u8>,
+    /// When the oldest byte in `buf` was buffered
+    since: Option<time::Instant>,
+    nodelay: bool,
+    /// Error of a flush by the background thread
+    error: Option<io::Error>,
+    closed: bool,
+}
+
+impl<W: Write + Send + 'static> CoalescingWriter<W> {
+    /// Wraps `writer`, buffering up to `threshold` bytes for at most `delay`.
+    pub fn new(writer: W, threshold: usize, delay: time::Duration) -> CoalescingWriter<W> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                writer,
+                buf: Vec::with_capacity(threshold),
+                since: None,
+                nodelay: false,
+                error: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            delay,
+        });
+        let flusher = Arc::clone(&shared);
+        thread::spawn(move || flusher.flush_delayed());
+        CoalescingWriter { shared, threshold }
+    }
+
+    /// Disables (`true`) or enables (`false`) the batching. Disabling it flushes the buffered
+    /// data.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        let mut state = self.shared.lock()?;
+        state.nodelay = nodelay;
+        if nodelay {
+            state.flush_buf()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the batching is disabled.
+    pub fn nodelay(&self) -> bool {
+        self.shared.state.lock().unwrap().nodelay
+    }
+}
+
+impl<W: Write> Shared<W> {
+    /// Locks the state, returning the pending error of the background thread first
+    fn lock(&self) -> io::Result<MutexGuard<'_, State<W>>> {
+        let mut state = self.state.lock().unwrap();
+        match state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(state),
+        }
+    }
+
+    /// Background thread flushing the data that waited for the delay
+    fn flush_delayed(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            state = match state.since {
+                Some(since) if since.elapsed() >= self.delay => {
+                    if let Err(e) = state.flush_buf() {
+                        state.error = Some(e);
+                    }
+                    state
+                }
+                Some(since) => {
+                    let remaining = self.delay - since.elapsed().min(self.delay);
+                    self.changed.wait_timeout(state, remaining).unwrap().0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl<W: Write> State<W> {
+    fn flush_buf(&mut self) -> io::Result<()> {
+        self.since = None;
+        let result = self.writer.write_all(&self.buf);
+        self.buf.clear();
+        result
+    }
+}
+
+impl<W: Write + Send + 'static> Write for CoalescingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.lock()?;
+        if state.nodelay {
+            return state.writer.write(buf);
+        }
+        if state.buf.len() + buf.len() > self.threshold {
+            state.flush_buf()?;
+            if buf.len() >= self.threshold {
+                return state.writer.write(buf);
+            }
+        }
+        state.buf.extend_from_slice(buf);
+        if state.buf.len() >= self.threshold {
+            state.flush_buf()?;
+        } else if state.since.is_none() {
+            state.since = Some(time::Instant::now());
+            self.shared.changed.notify_one();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.shared.lock()?;
+        state.flush_buf()?;
+        state.writer.flush()
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for CoalescingWriter<W> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let _ = state.flush_buf();
+        state.closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl<W: Write + Send + fmt::Debug + 'static> fmt::Debug for CoalescingWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.state.lock().unwrap();
+        f.debug_struct("CoalescingWriter")
+            .field("writer", &state.writer)
+            .field("buffered", &state.buf.len())
+            .field("threshold", &self.threshold)
+            .field("delay", &self.shared.delay)
+            .field("nodelay", &state.nodelay)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every write it receives
+    #[derive(Debug, Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !buf.is_empty() {
+                self.0.lock().unwrap().push(buf.to_vec());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn coalesces_small_writes() {
+        let writes = Writes::default();
+        let mut writer = CoalescingWriter::new(writes.clone(), 4, time::Duration::from_millis(50));
+
+        writer.write_all(b"ab").unwrap();
+        writer.write_all(b"cd").unwrap();
+        writer.write_all(b"e").unwrap();
+        assert_eq!(*writes.0.lock().unwrap(), vec![b"abcd".to_vec()]);
+
+        // The delay flushes the rest
+        thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(writes.0.lock().unwrap().len(), 2);
+
+        writer.set_nodelay(true).unwrap();
+        writer.write_all(b"f").unwrap();
+        writer.write_all(b"g").unwrap();
+        assert_eq!(
+            *writes.0.lock().unwrap(),
+            vec![
+                b"abcd".to_vec(),
+                b"e".to_vec(),
+                b"f".to_vec(),
+                b"g".to_vec()
+            ]
+        );
+    }
+}
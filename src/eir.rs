@@ -0,0 +1,179 @@
+//! Extended inquiry response (EIR) and advertising data, see `EirData`.
+use crate::bluetooth::DeviceClass;
+
+// Data types, see the Bluetooth Assigned Numbers, 2.3
+const EIR_NAME_SHORT: u8 = 0x08;
+const EIR_NAME_COMPLETE: u8 = 0x09;
+const EIR_CLASS_OF_DEVICE: u8 = 0x0D;
+const EIR_APPEARANCE: u8 = 0x19;
+
+/// The fields of an extended inquiry response or LE advertisement that this library interprets.
+///
+/// The data is a sequence of length-prefixed structures, see the Bluetooth Core Specification,
+/// Vol 3, Part C, 8. Unknown structures are skipped, the parsing stops at the first malformed one.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EirData {
+    /// The complete or (if only that is present) the shortened name of the device.
+    ///
+    /// Invalid UTF-8 is replaced with `U+FFFD`, see `name_raw` for the bytes as received.
+    pub name: Option<String>,
+
+    /// The bytes of `name` as received.
+    pub name_raw: Option<Vec<u8>>,
+
+    /// The class of device.
+    pub class: Option<DeviceClass>,
+
+    /// The appearance of the device.
+    pub appearance: Option<Appearance>,
+}
+
+impl EirData {
+    /// Parses the data as received, e.g. the EIR of a "Device Found" event.
+    pub fn parse(mut data: &[u8]) -> EirData {
+        let mut eir = EirData::default();
+        let mut short_name = None;
+        // A zero length structure marks the end of the significant part
+        while let [len, rest @ ..] = data {
+            let len = usize::from(*len);
+            if len == 0 || rest.len() < len {
+                break;
+            }
+            let (kind, value) = (rest[0], &rest[1..len]);
+            match (kind, value) {
+                (EIR_NAME_COMPLETE, _) => eir.name_raw = Some(value.to_vec()),
+                (EIR_NAME_SHORT, _) => short_name = Some(value.to_vec()),
+                (EIR_CLASS_OF_DEVICE, &[a, b, c]) => {
+                    eir.class = Some(DeviceClass::from_bytes([a, b, c]))
+                }
+                (EIR_APPEARANCE, &[a, b]) => {
+                    eir.appearance = Some(Appearance(u16::from_le_bytes([a, b])))
+                }
+                _ => {}
+            }
+            data = &rest[len..];
+        }
+        eir.name_raw = eir.name_raw.or(short_name);
+        eir.name = eir
+            .name_raw
+            .as_deref()
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        eir
+    }
+}
+
+/// The 16 bit appearance a device reports in its EIR or advertisements, see the Bluetooth
+/// Assigned Numbers, 2.6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Appearance(pub u16);
+
+impl Appearance {
+    /// The category (bits 6 to 15).
+    pub fn category(&self) -> u16 {
+        self.0 >> 6
+    }
+
+    /// The subcategory (bits 0 to 5), whose meaning depends on the category.
+    pub fn subcategory(&self) -> u8 {
+        (self.0 & 0x3F) as u8
+    }
+
+    /// The kind of device, e.g. to pick an icon for it.
+    pub fn kind(&self) -> AppearanceKind {
+        match (self.category(), self.subcategory()) {
+            (0x001, _) => AppearanceKind::Phone,
+            (0x002, _) => AppearanceKind::Computer,
+            (0x003, _) => AppearanceKind::Watch,
+            (0x004, _) => AppearanceKind::Clock,
+            (0x005, _) => AppearanceKind::Display,
+            (0x006, _) => AppearanceKind::RemoteControl,
+            (0x007, _) => AppearanceKind::Glasses,
+            (0x008, _) | (0x009, _) => AppearanceKind::Tag,
+            (0x00A, _) => AppearanceKind::MediaPlayer,
+            (0x00F, 0x01) => AppearanceKind::Keyboard,
+            (0x00F, 0x02) => AppearanceKind::Mouse,
+            (0x00F, 0x03) | (0x00F, 0x04) => AppearanceKind::GameController,
+            (0x00F, _) => AppearanceKind::InputDevice,
+            (0x00C, _) | (0x00D, _) | (0x00E, _) | (0x010, _) | (0x015, _) | (0x031, _) => {
+                AppearanceKind::Sensor
+            }
+            (0x011, _) | (0x012, _) => AppearanceKind::FitnessSensor,
+            (0x025, _) => AppearanceKind::Headset,
+            (0x021, _) => AppearanceKind::Speaker,
+            _ => AppearanceKind::Unknown,
+        }
+    }
+}
+
+/// Kinds of devices derived from the appearance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppearanceKind {
+    /// Phones and smartphones.
+    Phone,
+    /// Desktops, laptops, tablets, ...
+    Computer,
+    /// Watches and smartwatches.
+    Watch,
+    /// Clocks.
+    Clock,
+    /// Displays.
+    Display,
+    /// Remote controls.
+    RemoteControl,
+    /// Eye glasses.
+    Glasses,
+    /// Tags and keyrings.
+    Tag,
+    /// Media players.
+    MediaPlayer,
+    /// Keyboards.
+    Keyboard,
+    /// Mice.
+    Mouse,
+    /// Joysticks and gamepads.
+    GameController,
+    /// Other human interface devices, like digitizer tablets or card readers.
+    InputDevice,
+    /// Thermometers, heart rate and blood pressure monitors, glucose meters, generic sensors, ...
+    Sensor,
+    /// Running, walking and cycling sensors.
+    FitnessSensor,
+    /// Headsets, earbuds and headphones.
+    Headset,
+    /// Speakers.
+    Speaker,
+    /// Any other (or an unknown) appearance.
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_eir() {
+        let data = [
+            0x04, 0x08, b'K', b'B', b'D', // shortened name
+            0x09, 0x09, b'K', b'e', b'y', b'b', b'o', b'a', b'r', b'd', // complete name
+            0x03, 0x19, 0xC1, 0x03, // appearance: keyboard
+            0x02, 0x01, 0x06, // flags, ignored
+            0x00, 0x00, // padding
+        ];
+        let eir = EirData::parse(&data);
+        assert_eq!(eir.name.as_deref(), Some("Keyboard"));
+        assert_eq!(eir.name_raw.as_deref(), Some(&b"Keyboard"[..]));
+        assert_eq!(eir.class, None);
+        assert_eq!(eir.appearance, Some(Appearance(0x03C1)));
+        assert_eq!(eir.appearance.unwrap().kind(), AppearanceKind::Keyboard);
+
+        // Truncated structures are ignored
+        assert_eq!(EirData::parse(&data[..3]).name, None);
+        assert_eq!(Appearance(0x03C2).kind(), AppearanceKind::Mouse);
+        assert_eq!(Appearance(0).kind(), AppearanceKind::Unknown);
+
+        // Names in other encodings are kept as received
+        let eir = EirData::parse(&[0x04, 0x09, b'C', 0xE9, b'a']);
+        assert_eq!(eir.name.as_deref(), Some("C\u{FFFD}a"));
+        assert_eq!(eir.name_raw, Some(vec![b'C', 0xE9, b'a']));
+    }
+}
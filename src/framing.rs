@@ -0,0 +1,132 @@
+//! Splitting the byte stream of a connection into messages, see `BtSocket::read_frame`.
+use crate::bluetooth::BtError;
+use std::convert::TryFrom;
+
+/// Decides when the bytes received so far contain a complete message (frame).
+pub trait Framer {
+    /// Returns the length of the complete frame at the start of `buf`, or `None` if more data is
+    /// needed. The length must not exceed `buf.len()`.
+    fn frame_len(&mut self, buf: &[u8]) -> Result<Option<usize>, BtError>;
+}
+
+/// Frames that start with their payload length as an unsigned integer of 1 to 8 bytes.
+///
+/// Frames longer than the maximum frame length (1 MiB by default) are rejected with an error
+/// before they are buffered, so a corrupt or hostile prefix can't exhaust the memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefixed {
+    width: usize,
+    little_endian: bool,
+    max_frame_len: usize,
+}
+
+impl LengthPrefixed {
+    /// Frames with a big endian length prefix of `width` bytes. Panics unless `width` is within
+    /// `1..=8`.
+    pub fn new(width: usize) -> LengthPrefixed {
+        assert!(
+            (1..=8).contains(&width),
+            "Length prefix must have 1 to 8 bytes"
+        );
+        LengthPrefixed {
+            width,
+            little_endian: false,
+            max_frame_len: 1024 * 1024,
+        }
+    }
+
+    /// Reads the length prefix as little endian integer instead.
+    pub fn little_endian(mut self) -> LengthPrefixed {
+        self.little_endian = true;
+        self
+    }
+
+    /// Sets the maximum length of a frame, including its length prefix.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> LengthPrefixed {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+}
+
+impl Framer for LengthPrefixed {
+    fn frame_len(&mut self, buf: &[u8]) -> Result<Option<usize>, BtError> {
+        let prefix = match buf.get(..self.width) {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+        let mut bytes = [0u8; 8];
+        let len = if self.little_endian {
+            bytes[..self.width].copy_from_slice(prefix);
+            u64::from_le_bytes(bytes)
+        } else {
+            bytes[8 - self.width..].copy_from_slice(prefix);
+            u64::from_be_bytes(bytes)
+        };
+
+        let frame_len = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_add(self.width))
+            .filter(|&frame_len| frame_len <= self.max_frame_len)
+            .ok_or_else(|| {
+                BtError::Desc(format!(
+                    "Frame length {} exceeds the maximum of {}",
+                    len, self.max_frame_len
+                ))
+            })?;
+        Ok(Some(frame_len).filter(|&frame_len| frame_len <= buf.len()))
+    }
+}
+
+/// Frames that end with a delimiter, like `\r\n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delimiter {
+    delimiter: Vec<u8>,
+}
+
+impl Delimiter {
+    /// Frames ending with `delimiter`. Panics if `delimiter` is empty.
+    pub fn new(delimiter: &[u8]) -> Delimiter {
+        assert!(!delimiter.is_empty(), "Delimiter must not be empty");
+        Delimiter {
+            delimiter: delimiter.to_vec(),
+        }
+    }
+}
+
+impl Framer for Delimiter {
+    fn frame_len(&mut self, buf: &[u8]) -> Result<Option<usize>, BtError> {
+        Ok(buf
+            .windows(self.delimiter.len())
+            .position(|window| window == self.delimiter.as_slice())
+            .map(|position| position + self.delimiter.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_frame_ends() {
+        let mut framer = LengthPrefixed::new(2);
+        assert_eq!(framer.frame_len(&[0x00]).unwrap(), None);
+        assert_eq!(framer.frame_len(&[0x00, 0x02, 0xAA]).unwrap(), None);
+        assert_eq!(
+            framer.frame_len(&[0x00, 0x02, 0xAA, 0xBB, 0x00]).unwrap(),
+            Some(4)
+        );
+
+        let mut framer = LengthPrefixed::new(2).little_endian();
+        assert_eq!(framer.frame_len(&[0x01, 0x00, 0xAA]).unwrap(), Some(3));
+
+        let mut framer = LengthPrefixed::new(2).with_max_frame_len(4);
+        assert_eq!(framer.frame_len(&[0x00, 0x02]).unwrap(), None);
+        assert!(framer.frame_len(&[0x00, 0x03]).is_err());
+        let mut framer = LengthPrefixed::new(8);
+        assert!(framer.frame_len(&[0xFF; 8]).is_err());
+
+        let mut framer = Delimiter::new(b"\r\n");
+        assert_eq!(framer.frame_len(b"OK\r").unwrap(), None);
+        assert_eq!(framer.frame_len(b"OK\r\nAT").unwrap(), Some(4));
+    }
+}
@@ -8,9 +8,57 @@
     unused_qualifications
 )]
 
+/// Log through the `log` crate and emit `tracing` events if the respective features are enabled,
+/// otherwise only type check the message arguments.
+macro_rules! bt_log {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::$level!($($arg)+);
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        if false {
+            let _ = format!($($arg)+);
+        }
+    };
+}
+
+/// Enter a `tracing` span with the given name and fields until the end of the current block, if
+/// the `tracing` feature is enabled.
+macro_rules! bt_span {
+    ($name:expr, $($fields:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($name, $($fields)*).entered();
+    };
+}
+
 mod bluetooth;
 pub use crate::bluetooth::*;
 
+mod sdp;
+pub use crate::sdp::{Profile, SdpRecord, SdpService, SdpValue, Uuid};
+
+mod coalesce;
+pub use crate::coalesce::CoalescingWriter;
+
+mod eir;
+pub use crate::eir::{Appearance, AppearanceKind, EirData};
+
+mod framing;
+pub use crate::framing::{Delimiter, Framer, LengthPrefixed};
+
+mod pool;
+pub use crate::pool::BtPool;
+
+mod relay;
+pub use crate::relay::{copy_bidirectional, copy_bidirectional_async};
+
+mod scanner;
+pub use crate::scanner::{Scanner, ScannerHandle};
+
+mod tracker;
+pub use crate::tracker::{DeviceTracker, ScanDiff};
+
 // ////////////////////////////////////
 // Linux implementation of functions
 #[cfg(target_os = "linux")]
@@ -34,6 +82,10 @@ pub mod os {
     /// Linux-specific definitions
     #[cfg(target_os = "linux")]
     pub mod linux {
-        pub use crate::linux::{BtSocket, BtSocketConnect};
+        pub use crate::linux::{BtListener, BtSocket, BtSocketConnect};
+
+        /// Low-level access to the BlueZ management API
+        #[doc(hidden)]
+        pub use crate::linux::{MgmtEvent, MgmtSocket, MGMT_INDEX_NONE};
     }
 }
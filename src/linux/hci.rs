@@ -1,6 +1,14 @@
-use super::{ffi::*, socket::create_error_from_last};
+use super::{
+    ffi::*,
+    mgmt::{MgmtSocket, MGMT_OP_START_DISCOVERY, MGMT_OP_STOP_DISCOVERY},
+    socket::{create_error_from_errno, create_error_from_last},
+    sys::{default_sys, BtSys},
+};
 
-use crate::bluetooth::{BtAddr, BtDevice, BtError};
+use crate::bluetooth::{
+    Adapter, BtAddr, BtDevice, BtError, DeviceClass, PagingParams, RemoteFeatures, ScanOptions,
+    ScanParams, ScanProgress,
+};
 
 use libc::close;
 use std::{
@@ -11,7 +19,8 @@ use std::{
         io::{AsRawFd, FromRawFd, IntoRawFd},
         net::UnixStream,
     },
-    ptr, time, vec,
+    sync::atomic::{AtomicBool, Ordering},
+    time, vec,
 };
 
 #[repr(C, packed)]
@@ -31,17 +40,130 @@ impl Default for InquiryInfo {
     }
 }
 
+/// `struct hci_dev_info`, as returned by the `HCIGETDEVINFO` ioctl
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct HciDevInfo {
+    pub dev_id: uint16_t,
+    pub name: [c_char; 8],
+    pub bdaddr: BtAddr,
+    pub flags: uint32_t,
+    pub dev_type: uint8_t,
+    pub features: [uint8_t; 8],
+    pub pkt_type: uint32_t,
+    pub link_policy: uint32_t,
+    pub link_mode: uint32_t,
+    pub acl_mtu: uint16_t,
+    pub acl_pkts: uint16_t,
+    pub sco_mtu: uint16_t,
+    pub sco_pkts: uint16_t,
+    pub stat: [uint32_t; 10],
+}
+
+impl Default for HciDevInfo {
+    fn default() -> Self {
+        unsafe { mem::zeroed() }
+    }
+}
+
+/// `struct hci_dev_req`, an entry of the `HCIGETDEVLIST` ioctl's result
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HciDevReq {
+    pub dev_id: uint16_t,
+    pub dev_opt: uint32_t,
+}
+
+/// `struct hci_dev_list_req` with room for the maximum number of adapters
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct HciDevListReq {
+    pub dev_num: uint16_t,
+    pub dev_req: [HciDevReq; HCI_MAX_DEV],
+}
+
+/// `struct hci_conn_info_req` with room for the one connection it is asked for
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HciConnInfoReq {
+    pub bdaddr: BtAddr,
+    pub link_type: uint8_t,
+    pub conn_info: HciConnInfo,
+}
+
+/// `struct hci_conn_info`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HciConnInfo {
+    pub handle: uint16_t,
+    pub bdaddr: BtAddr,
+    pub link_type: uint8_t,
+    pub out: uint8_t,
+    pub state: uint16_t,
+    pub link_mode: uint32_t,
+}
+
+pub const ACL_LINK: u8 = 0x01;
+
+pub const HCI_MAX_DEV: usize = 16;
+// _IOR('H', 210, int) and _IOR('H', 211, int)
+pub const HCIGETDEVLIST: c_ulong = 0x8004_48d2;
+pub const HCIGETDEVINFO: c_ulong = 0x8004_48d3;
+// _IOR('H', 213, int)
+pub const HCIGETCONNINFO: c_ulong = 0x8004_48d5;
+// Bit in `HciDevInfo::flags`
+const HCI_UP: u32 = 0;
+
 const IREQ_CACHE_FLUSH: c_long = 1;
 
+pub(super) const OGF_LINK_CTL: u16 = 0x01;
+const OCF_INQUIRY_CANCEL: u16 = 0x0002;
+pub(super) const OCF_CREATE_CONN: u16 = 0x0005;
+
+const OGF_HOST_CTL: u16 = 0x03;
+const OCF_WRITE_PAGE_TIMEOUT: u16 = 0x0018;
+const OCF_READ_PAGE_SCAN_ACTIVITY: u16 = 0x001B;
+const OCF_WRITE_PAGE_SCAN_ACTIVITY: u16 = 0x001C;
+const OCF_READ_INQUIRY_SCAN_ACTIVITY: u16 = 0x001D;
+const OCF_WRITE_INQUIRY_SCAN_ACTIVITY: u16 = 0x001E;
+
+// Length of a baseband slot, the unit of the page timeout
+const SLOT: time::Duration = time::Duration::from_micros(625);
+
+/// How long `hci_send_req()` waits for the reply to a command
+pub(super) const HCI_COMMAND_TIMEOUT_MS: c_int = 2000;
+/// How long paging a device may take, twice the default page timeout of 5.12 seconds
+pub(super) const CREATE_CONN_TIMEOUT_MS: c_int = 10_240;
+/// Event reporting the result of Create Connection
+pub(super) const EVT_CONN_COMPLETE: c_int = 0x03;
+
+/// Command and reply buffers of `hci_send_req()`
+#[repr(C)]
+#[derive(Debug)]
+pub struct HciRequest {
+    pub ogf: uint16_t,
+    pub ocf: uint16_t,
+    /// Event to wait for besides "Command Complete", 0 for none
+    pub event: c_int,
+    pub cparam: *mut c_void,
+    pub clen: c_int,
+    pub rparam: *mut c_void,
+    pub rlen: c_int,
+}
+
+// DM1, DM3, DM5, DH1, DH3 and DH5 ACL packets
+const ACL_PACKET_TYPES: u16 = 0xCC18;
+// Set in the clock offset parameter if the offset is valid
+const CLOCK_OFFSET_VALID: u16 = 0x8000;
+
 // BlueZ funcitons
 #[cfg(target_os = "linux")]
-#[link(name = "bluetooth")]
-extern "C" {
-    fn hci_get_route(addr: *mut BtAddr) -> c_int /* device_id */;
-    fn hci_open_dev(device_id: c_int) -> c_int /* socket to local bluetooth adapter */;
+libbluetooth! {
+    pub(super) fn hci_get_route(addr: *mut BtAddr) -> c_int /* device_id */;
+    pub(super) fn hci_open_dev(device_id: c_int) -> c_int /* socket to local bluetooth adapter */;
 
     // The inquiry last at most for "1.28 * timout" seconds
-    fn hci_inquiry(
+    pub(super) fn hci_inquiry(
         device_id: c_int,
         timeout: c_int,
         max_rsp: c_int,
@@ -50,34 +172,657 @@ extern "C" {
         flags: c_long,
     ) -> c_int;
 
-    fn hci_read_remote_name(
+    pub(super) fn hci_send_cmd(
+        socket: c_int,
+        ogf: uint16_t,
+        ocf: uint16_t,
+        plen: uint8_t,
+        param: *mut c_void,
+    ) -> c_int;
+
+    pub(super) fn hci_send_req(socket: c_int, request: *mut HciRequest, timeout_ms: c_int)
+        -> c_int;
+
+    pub(super) fn hci_read_local_name(
+        socket: c_int,
+        len: c_int,
+        name: *mut c_char,
+        timeout_ms: c_int,
+    ) -> c_int;
+
+    pub(super) fn hci_read_remote_name(
         socket: c_int,
         addr: *const BtAddr,
         max_len: c_int,
         name: *mut c_char,
         timeout_ms: c_int,
     ) -> c_int;
+
+    pub(super) fn hci_read_remote_features(
+        socket: c_int,
+        handle: uint16_t,
+        features: *mut uint8_t,
+        timeout_ms: c_int,
+    ) -> c_int;
 }
 
-pub fn scan_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
-    let device_id = unsafe { hci_get_route(ptr::null_mut()) };
-    if device_id < 0 {
-        return Err(create_error_from_last(
-            "hci_get_route(): No local bluetooth adapter found",
-        ));
+pub fn scan_devices_with(options: &ScanOptions) -> Result<Vec<BtDevice>, BtError> {
+    scan(default_sys(), options)
+}
+
+pub fn scan_devices_until(
+    options: &ScanOptions,
+    stop: &AtomicBool,
+) -> Result<Vec<BtDevice>, BtError> {
+    scan_until(default_sys(), options, stop)
+}
+
+/// Never trust the number of responses the driver reports beyond the size of the buffer, the
+/// entries after that don't exist
+fn clamp_responses(number_responses: usize, buffer_len: usize) -> usize {
+    if number_responses > buffer_len {
+        bt_log!(
+            warn,
+            "hci_inquiry() reported {} responses for a buffer of {}",
+            number_responses,
+            buffer_len
+        );
     }
+    number_responses.min(buffer_len)
+}
+
+// The kernel answers an inquiry from its cache if that is recent (at most 30 seconds old), not
+// empty and not to be flushed. Otherwise it runs the inquiry, so the shortest valid length (1.28
+// seconds) is used: the controller rejects inquiries of length zero.
+const CACHE_INQUIRY_LENGTH: c_int = 1;
+
+pub fn inquiry_cache() -> Result<Vec<BtAddr>, BtError> {
+    read_inquiry_cache(default_sys(), 0)
+}
+
+pub fn clear_inquiry_cache() -> Result<(), BtError> {
+    read_inquiry_cache(default_sys(), IREQ_CACHE_FLUSH).map(|_| ())
+}
+
+fn read_inquiry_cache(sys: &dyn BtSys, flags: c_long) -> Result<Vec<BtAddr>, BtError> {
+    Ok(cached_inquiry_infos(sys, flags)?
+        .iter()
+        .map(|info| info.bdaddr.convert_host_byteorder())
+        .collect())
+}
+
+/// Look up the class of device `addr` reported during the last inquiry
+pub(super) fn cached_class(sys: &dyn BtSys, addr: BtAddr) -> Result<DeviceClass, BtError> {
+    cached_inquiry_infos(sys, 0)?
+        .iter()
+        .find(|info| info.bdaddr.convert_host_byteorder() == addr)
+        .map(|info| DeviceClass::from_bytes(info.dev_class))
+        .ok_or_else(|| {
+            BtError::Desc(format!(
+                "The class of {:?} is unknown, it isn't in the inquiry cache",
+                addr
+            ))
+        })
+}
+
+fn cached_inquiry_infos(sys: &dyn BtSys, flags: c_long) -> Result<Vec<InquiryInfo>, BtError> {
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+
+    let mut inquiry_infos = vec::from_elem(InquiryInfo::default(), 256);
+    let number_responses = sys
+        .hci_inquiry(
+            device_id,
+            CACHE_INQUIRY_LENGTH,
+            None,
+            &mut inquiry_infos,
+            flags,
+        )
+        .map_err(|errno| {
+            create_error_from_errno("hci_inquiry(): Reading the inquiry cache failed", errno)
+        })?;
+    inquiry_infos.truncate(clamp_responses(number_responses, inquiry_infos.len()));
+    Ok(inquiry_infos)
+}
 
-    let local_socket = unsafe { hci_open_dev(device_id) };
-    if local_socket < 0 {
-        return Err(create_error_from_last(
+pub fn list_adapters() -> Result<Vec<Adapter>, BtError> {
+    adapters(default_sys())
+}
+
+pub fn local_adapter() -> Result<Adapter, BtError> {
+    default_adapter(default_sys())
+}
+
+fn default_adapter(sys: &dyn BtSys) -> Result<Adapter, BtError> {
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+    adapters(sys)?
+        .into_iter()
+        .find(|adapter| c_int::from(adapter.id) == device_id)
+        .ok_or_else(|| BtError::Desc("The default adapter was removed".to_string()))
+}
+
+pub(super) fn adapters(sys: &dyn BtSys) -> Result<Vec<Adapter>, BtError> {
+    let infos = sys.hci_devices().map_err(|errno| {
+        create_error_from_errno("Listing the local bluetooth adapters failed", errno)
+    })?;
+
+    Ok(infos
+        .iter()
+        .map(|info| {
+            let up = info.flags & (1 << HCI_UP) != 0;
+            Adapter {
+                id: info.dev_id,
+                name: unsafe { CStr::from_ptr(info.name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned(),
+                addr: info.bdaddr.convert_host_byteorder(),
+                up,
+                // Only an adapter that is up can be asked for its name
+                friendly_name: if up {
+                    read_local_name(sys, c_int::from(info.dev_id))
+                } else {
+                    None
+                },
+            }
+        })
+        .collect())
+}
+
+/// Read the friendly name of the adapter `device_id`
+fn read_local_name(sys: &dyn BtSys, device_id: c_int) -> Option<String> {
+    let local_socket = sys.hci_open_dev(device_id).ok()?;
+    let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
+
+    // The name has at most 248 bytes
+    let mut cname = [0; 249];
+    sys.hci_read_local_name(local_socket.as_raw_fd(), &mut cname)
+        .ok()?;
+    Some(
+        unsafe { CStr::from_ptr(cname.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Abort a running inquiry on the default adapter, which makes the scan return early
+pub fn cancel_inquiry() -> Result<(), BtError> {
+    let sys = default_sys();
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+    let local_socket = sys.hci_open_dev(device_id).map_err(|errno| {
+        create_error_from_errno(
             "hci_open_dev(): Opening local bluetooth adapter failed",
-        ));
+            errno,
+        )
+    })?;
+    let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
+
+    sys.hci_send_cmd(
+        local_socket.as_raw_fd(),
+        OGF_LINK_CTL,
+        OCF_INQUIRY_CANCEL,
+        &mut [],
+    )
+    .map_err(|errno| create_error_from_errno("hci_send_cmd(): Cancelling inquiry failed", errno))
+}
+
+/// Parameters of the HCI Create Connection command paging `addr` (in host byte order)
+fn create_conn_params(addr: BtAddr, paging: &PagingParams) -> [u8; 13] {
+    let mut params = [0u8; 13];
+    params[..6].copy_from_slice(&addr.convert_host_byteorder().0);
+    params[6..8].copy_from_slice(&ACL_PACKET_TYPES.to_le_bytes());
+    params[8] = paging.pscan_rep_mode;
+    // params[9] is reserved
+    params[10..12].copy_from_slice(&(paging.clock_offset | CLOCK_OFFSET_VALID).to_le_bytes());
+    params[12] = 0x01; // allow a role switch
+    params
+}
+
+/// Let the default adapter page `addr` (in host byte order) with the known paging parameters and
+/// wait until the ACL link is established. The kernel uses that link for the next connection to
+/// the device.
+pub(super) fn create_connection(
+    sys: &dyn BtSys,
+    addr: BtAddr,
+    paging: &PagingParams,
+) -> Result<(), BtError> {
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+    let local_socket = sys.hci_open_dev(device_id).map_err(|errno| {
+        create_error_from_errno(
+            "hci_open_dev(): Opening local bluetooth adapter failed",
+            errno,
+        )
+    })?;
+    let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
+
+    sys.hci_create_connection(
+        local_socket.as_raw_fd(),
+        &mut create_conn_params(addr, paging),
+    )
+    .map_err(|errno| create_error_from_errno("Paging device failed", errno))
+}
+
+/// Send the command `ogf`/`ocf` to the adapter `device_id` and return the return parameters of
+/// its "Command Complete" event
+pub fn hci_send_command(
+    device_id: u16,
+    ogf: u8,
+    ocf: u16,
+    params: &[u8],
+) -> Result<Vec<u8>, BtError> {
+    hci_send_command_with(default_sys(), device_id, ogf, ocf, params)
+}
+
+fn hci_send_command_with(
+    sys: &dyn BtSys,
+    device_id: u16,
+    ogf: u8,
+    ocf: u16,
+    params: &[u8],
+) -> Result<Vec<u8>, BtError> {
+    // The opcode has 6 bits for the OGF and 10 for the OCF
+    if ogf > 0x3F || ocf > 0x3FF {
+        return Err(BtError::Desc(format!(
+            "Invalid HCI opcode {:#04x}/{:#06x}",
+            ogf, ocf
+        )));
+    }
+    if params.len() > usize::from(u8::MAX) {
+        return Err(BtError::Desc("HCI parameters too long".to_string()));
     }
 
+    let local_socket = sys.hci_open_dev(c_int::from(device_id)).map_err(|errno| {
+        create_error_from_errno(
+            "hci_open_dev(): Opening local bluetooth adapter failed",
+            errno,
+        )
+    })?;
     let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
 
-    let mut inquiry_infos = vec::from_elem(InquiryInfo::default(), 256);
+    let mut params = params.to_vec();
+    let mut response = vec![0; usize::from(u8::MAX)];
+    let len = sys
+        .hci_send_req(
+            local_socket.as_raw_fd(),
+            u16::from(ogf),
+            ocf,
+            &mut params,
+            &mut response,
+        )
+        .map_err(|errno| {
+            create_error_from_errno("hci_send_req(): Sending HCI command failed", errno)
+        })?;
+    response.truncate(len);
+    Ok(response)
+}
+
+/// Set how long the adapter `device_id` pages a device before giving up
+pub fn set_page_timeout(device_id: u16, timeout: time::Duration) -> Result<(), BtError> {
+    set_page_timeout_with(default_sys(), device_id, timeout)
+}
+
+fn set_page_timeout_with(
+    sys: &dyn BtSys,
+    device_id: u16,
+    timeout: time::Duration,
+) -> Result<(), BtError> {
+    let slots = page_timeout_slots(timeout)?;
+    host_ctl_command(
+        sys,
+        device_id,
+        OCF_WRITE_PAGE_TIMEOUT,
+        &slots.to_le_bytes(),
+        "Setting the page timeout failed",
+    )
+    .map(|_| ())
+}
+
+/// Send a host controller command, returning the return parameters after the status
+fn host_ctl_command(
+    sys: &dyn BtSys,
+    device_id: u16,
+    ocf: u16,
+    params: &[u8],
+    operation: &str,
+) -> Result<Vec<u8>, BtError> {
+    let mut response = hci_send_command_with(sys, device_id, OGF_HOST_CTL as u8, ocf, params)
+        .map_err(|e| e.context(operation))?;
+    match response.first() {
+        Some(0) => Ok(response.split_off(1)),
+        Some(status) => Err(BtError::Desc(format!(
+            "{} with status {:#04x}",
+            operation, status
+        ))),
+        None => Err(BtError::Desc(format!("{} without status", operation))),
+    }
+}
+
+pub fn scan_parameters(device_id: u16) -> Result<ScanParams, BtError> {
+    scan_parameters_with(default_sys(), device_id)
+}
+
+fn scan_parameters_with(sys: &dyn BtSys, device_id: u16) -> Result<ScanParams, BtError> {
+    let (inquiry_scan_interval, inquiry_scan_window) = parse_scan_activity(&host_ctl_command(
+        sys,
+        device_id,
+        OCF_READ_INQUIRY_SCAN_ACTIVITY,
+        &[],
+        "Reading the inquiry scan activity failed",
+    )?)?;
+    let (page_scan_interval, page_scan_window) = parse_scan_activity(&host_ctl_command(
+        sys,
+        device_id,
+        OCF_READ_PAGE_SCAN_ACTIVITY,
+        &[],
+        "Reading the page scan activity failed",
+    )?)?;
+    Ok(ScanParams {
+        inquiry_scan_interval,
+        inquiry_scan_window,
+        page_scan_interval,
+        page_scan_window,
+    })
+}
+
+pub fn set_scan_parameters(device_id: u16, params: &ScanParams) -> Result<(), BtError> {
+    set_scan_parameters_with(default_sys(), device_id, params)
+}
 
+fn set_scan_parameters_with(
+    sys: &dyn BtSys,
+    device_id: u16,
+    params: &ScanParams,
+) -> Result<(), BtError> {
+    // Check both before changing anything
+    let inquiry_scan = scan_activity(params.inquiry_scan_interval, params.inquiry_scan_window)?;
+    let page_scan = scan_activity(params.page_scan_interval, params.page_scan_window)?;
+    host_ctl_command(
+        sys,
+        device_id,
+        OCF_WRITE_INQUIRY_SCAN_ACTIVITY,
+        &inquiry_scan,
+        "Setting the inquiry scan activity failed",
+    )?;
+    host_ctl_command(
+        sys,
+        device_id,
+        OCF_WRITE_PAGE_SCAN_ACTIVITY,
+        &page_scan,
+        "Setting the page scan activity failed",
+    )
+    .map(|_| ())
+}
+
+/// Parameters of the Write Inquiry/Page Scan Activity commands: the interval (rounded up to an
+/// even number of slots) and the window
+fn scan_activity(interval: time::Duration, window: time::Duration) -> Result<[u8; 4], BtError> {
+    let slots = |duration: time::Duration| duration.as_micros().div_ceil(SLOT.as_micros());
+    let interval = slots(interval).next_multiple_of(2);
+    let window = slots(window);
+    if !(0x12..=0x1000).contains(&interval) || !(0x11..=interval).contains(&window) {
+        return Err(BtError::Desc(format!(
+            "The scan interval must be between {:?} and {:?}, the window between {:?} and the \
+             interval",
+            SLOT * 0x12,
+            SLOT * 0x1000,
+            SLOT * 0x11
+        )));
+    }
+    let mut params = [0; 4];
+    params[..2].copy_from_slice(&(interval as u16).to_le_bytes());
+    params[2..].copy_from_slice(&(window as u16).to_le_bytes());
+    Ok(params)
+}
+
+/// Interval and window of the return parameters of the Read Inquiry/Page Scan Activity commands
+fn parse_scan_activity(response: &[u8]) -> Result<(time::Duration, time::Duration), BtError> {
+    match *response {
+        [interval_lo, interval_hi, window_lo, window_hi, ..] => Ok((
+            SLOT * u32::from(u16::from_le_bytes([interval_lo, interval_hi])),
+            SLOT * u32::from(u16::from_le_bytes([window_lo, window_hi])),
+        )),
+        _ => Err(BtError::Desc("Malformed scan activity".to_string())),
+    }
+}
+
+/// Convert `timeout` to baseband slots, rounding up
+fn page_timeout_slots(timeout: time::Duration) -> Result<u16, BtError> {
+    let slots = timeout.as_micros().div_ceil(SLOT.as_micros());
+    match slots {
+        1..=0xFFFF => Ok(slots as u16),
+        _ => Err(BtError::Desc(format!(
+            "The page timeout must be between {:?} and {:?}",
+            SLOT,
+            SLOT * 0xFFFF
+        ))),
+    }
+}
+
+/// Name of devices whose name couldn't be read
+pub(crate) const UNKNOWN_NAME: &str = "[unknown]";
+
+/// Combine two responses of the same device, keeping the resolved name and the best RSSI
+fn merge_duplicate(known: &mut BtDevice, device: BtDevice) {
+    if known.name == UNKNOWN_NAME {
+        known.name = device.name;
+        known.name_raw = device.name_raw;
+    }
+    known.rssi = known.rssi.max(device.rssi);
+    known.class = known.class.or(device.class);
+    known.paging = device.paging.or(known.paging);
+    known.appearance = known.appearance.or(device.appearance);
+}
+
+// Address types of the mgmt "Start Discovery" command: BR/EDR, LE public and LE random
+const DISCOVERY_TYPE: u8 = 0x07;
+
+pub fn discover_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
+    let device_id = default_sys().hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+    let index = device_id as u16;
+
+    let socket = MgmtSocket::open()?;
+    socket.command(MGMT_OP_START_DISCOVERY, index, &[DISCOVERY_TYPE])?;
+
+    let deadline = time::Instant::now() + timeout;
+    let mut devices: Vec<BtDevice> = Vec::new();
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(time::Instant::now());
+        let event = match socket.read_event_timeout(remaining) {
+            Ok(Some(event)) => event,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        if event.index != index {
+            continue;
+        }
+        if let Some(device) = event.device_found() {
+            match devices.iter_mut().find(|known| known.addr == device.addr) {
+                Some(known) => merge_duplicate(known, device),
+                None => devices.push(device),
+            }
+        }
+    };
+
+    // The discovery may have ended on its own already
+    let _ = socket.command(MGMT_OP_STOP_DISCOVERY, index, &[DISCOVERY_TYPE]);
+    result.map(|()| devices)
+}
+
+fn scan(sys: &dyn BtSys, options: &ScanOptions) -> Result<Vec<BtDevice>, BtError> {
+    scan_until(sys, options, &AtomicBool::new(false))
+}
+
+/// Like `scan`, but gives up with `BtError::Interrupted` as soon as `stop` is set. It is checked
+/// before the inquiry and before each name request.
+pub(crate) fn scan_until(
+    sys: &dyn BtSys,
+    options: &ScanOptions,
+    stop: &AtomicBool,
+) -> Result<Vec<BtDevice>, BtError> {
+    let mut devices = Vec::new();
+    scan_into(sys, options, stop, &mut devices)?;
+    Ok(devices)
+}
+
+/// Length of the inquiries `scan_devices_progress` reports progress after
+const PROGRESS_WINDOW: time::Duration = time::Duration::from_millis(2560);
+
+pub fn scan_devices_progress(
+    timeout: time::Duration,
+    progress: &mut dyn FnMut(ScanProgress),
+) -> Result<Vec<BtDevice>, BtError> {
+    scan_progress(default_sys(), timeout, progress)
+}
+
+fn scan_progress(
+    sys: &dyn BtSys,
+    timeout: time::Duration,
+    progress: &mut dyn FnMut(ScanProgress),
+) -> Result<Vec<BtDevice>, BtError> {
+    let start = time::Instant::now();
+    let mut options = ScanOptions::default();
+    let mut devices = Vec::new();
+    if timeout.is_zero() {
+        return Ok(devices);
+    }
+    loop {
+        options.timeout = timeout.saturating_sub(start.elapsed()).min(PROGRESS_WINDOW);
+        // Every window flushes the cache, otherwise the kernel would answer later windows from
+        // it instead of running an inquiry. The devices are merged here instead.
+        scan_into(sys, &options, &AtomicBool::new(false), &mut devices)?;
+
+        let elapsed = start.elapsed();
+        progress(ScanProgress {
+            elapsed,
+            fraction: (elapsed.as_secs_f32() / timeout.as_secs_f32()).min(1.),
+            devices_found: devices.len(),
+        });
+        if elapsed >= timeout {
+            return Ok(devices);
+        }
+    }
+}
+
+pub fn wait_for_device(
+    addr: BtAddr,
+    timeout: time::Duration,
+    poll_interval: time::Duration,
+) -> Result<BtDevice, BtError> {
+    wait_for(default_sys(), addr, timeout, poll_interval)
+}
+
+fn wait_for(
+    sys: &dyn BtSys,
+    addr: BtAddr,
+    timeout: time::Duration,
+    poll_interval: time::Duration,
+) -> Result<BtDevice, BtError> {
+    if poll_interval.is_zero() {
+        return Err(BtError::Desc("Poll interval must not be zero".to_string()));
+    }
+    let start = time::Instant::now();
+    let mut options = ScanOptions::default();
+    let mut devices = Vec::new();
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(create_error_from_errno("Device not found", libc::ETIMEDOUT));
+        }
+        let round = time::Instant::now();
+        options.timeout = remaining.min(poll_interval);
+        // Every round flushes the cache, so it runs an inquiry instead of answering from the
+        // cache. The names of devices found by earlier rounds aren't read again.
+        scan_into(sys, &options, &AtomicBool::new(false), &mut devices)?;
+        if let Some(device) = devices.iter().find(|device| device.addr == addr) {
+            return Ok(device.clone());
+        }
+
+        let next_round = poll_interval.saturating_sub(round.elapsed());
+        std::thread::sleep(next_round.min(timeout.saturating_sub(start.elapsed())));
+    }
+}
+
+pub fn remote_features(addr: BtAddr) -> Result<RemoteFeatures, BtError> {
+    remote_features_with(default_sys(), addr)
+}
+
+fn remote_features_with(sys: &dyn BtSys, addr: BtAddr) -> Result<RemoteFeatures, BtError> {
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+
+    let local_socket = sys.hci_open_dev(device_id).map_err(|errno| {
+        create_error_from_errno(
+            "hci_open_dev(): Opening local bluetooth adapter failed",
+            errno,
+        )
+    })?;
+
+    let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
+
+    // The kernel reports a missing connection as ENOENT
+    let handle = sys
+        .hci_conn_handle(local_socket.as_raw_fd(), &addr.convert_host_byteorder())
+        .map_err(|errno| {
+            let errno = if errno == libc::ENOENT {
+                libc::ENOTCONN
+            } else {
+                errno
+            };
+            create_error_from_errno("Device is not connected", errno)
+        })?;
+    let mut features = [0; 8];
+    sys.hci_read_remote_features(local_socket.as_raw_fd(), handle, &mut features)
+        .map_err(|errno| {
+            create_error_from_errno("hci_read_remote_features(): Reading features failed", errno)
+        })?;
+    Ok(RemoteFeatures::from_bytes(features))
+}
+
+/// Run an inquiry and add the devices it finds to `devices`. The names of devices that are
+/// already known are not read again.
+fn scan_into(
+    sys: &dyn BtSys,
+    options: &ScanOptions,
+    stop: &AtomicBool,
+    devices: &mut Vec<BtDevice>,
+) -> Result<(), BtError> {
+    bt_span!("bt_scan", timeout = ?options.timeout, flush_cache = options.flush_cache);
+    if !(1..=255).contains(&options.max_responses) {
+        return Err(BtError::Desc(format!(
+            "Invalid maximum number of responses {}, must be between 1 and 255",
+            options.max_responses
+        )));
+    }
+
+    if stop.load(Ordering::SeqCst) {
+        return Err(BtError::Interrupted);
+    }
+
+    let device_id = sys.hci_get_route().map_err(|errno| {
+        create_error_from_errno("hci_get_route(): No local bluetooth adapter found", errno)
+    })?;
+
+    let local_socket = sys.hci_open_dev(device_id).map_err(|errno| {
+        create_error_from_errno(
+            "hci_open_dev(): Opening local bluetooth adapter failed",
+            errno,
+        )
+    })?;
+
+    let local_socket = unsafe { UnixStream::from_raw_fd(local_socket) };
+
+    let mut inquiry_infos = vec::from_elem(InquiryInfo::default(), options.max_responses);
+
+    let timeout = options.timeout;
     let timeout_secs = timeout.as_secs();
     let max_secs = u64::from(u32::max_value());
     let timeout_secs = if timeout_secs > max_secs {
@@ -93,51 +838,72 @@ pub fn scan_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
         / 1.28)
         .round();
     let timeout = timeout.min(f64::from(c_int::max_value())).max(1.) as c_int;
-    let flags = IREQ_CACHE_FLUSH;
+    let flags = if options.flush_cache {
+        IREQ_CACHE_FLUSH
+    } else {
+        0
+    };
 
-    let mut inquiry_info = inquiry_infos.as_mut_ptr();
-    let number_responses = unsafe {
-        hci_inquiry(
+    let number_responses = sys
+        .hci_inquiry(
             device_id,
             timeout,
-            inquiry_infos.len() as c_int,
-            ptr::null(),
-            &mut inquiry_info,
+            options.lap.as_ref(),
+            &mut inquiry_infos,
             flags,
         )
-    };
-    if number_responses < 0 {
-        return Err(create_error_from_last(
-            "hci_inquiry(): Scanning remote bluetooth devices failed",
-        ));
-    }
+        .map_err(|errno| {
+            create_error_from_errno(
+                "hci_inquiry(): Scanning remote bluetooth devices failed",
+                errno,
+            )
+        })?;
 
-    inquiry_infos.truncate(number_responses as usize);
+    let number_responses = clamp_responses(number_responses, inquiry_infos.len());
+    inquiry_infos.truncate(number_responses);
+    bt_log!(debug, "hci_inquiry() found {} devices", number_responses);
 
-    let mut devices = Vec::with_capacity(inquiry_infos.len());
     for inquiry_info in &inquiry_infos {
+        if stop.load(Ordering::SeqCst) {
+            return Err(BtError::Interrupted);
+        }
+
+        let addr = inquiry_info.bdaddr.convert_host_byteorder();
+        // A device can respond several times, only ask for its name again if that failed before
+        let known = devices.iter().position(|device| device.addr == addr);
+        if matches!(known, Some(i) if devices[i].name != UNKNOWN_NAME) {
+            continue;
+        }
+
         let mut cname = [0; 256];
-        let name = if unsafe {
-            hci_read_remote_name(
-                local_socket.as_raw_fd(),
-                &inquiry_info.bdaddr,
-                cname.len() as c_int,
-                &mut cname[0],
-                0,
-            )
-        } < 0
+        let (name, name_raw) = if sys
+            .hci_read_remote_name(local_socket.as_raw_fd(), &inquiry_info.bdaddr, &mut cname)
+            .is_err()
         {
-            "[unknown]".to_string()
+            (UNKNOWN_NAME.to_string(), Vec::new())
         } else {
-            unsafe { CStr::from_ptr(&cname[0]) }
-                .to_string_lossy()
-                .into_owned()
+            let name_raw = unsafe { CStr::from_ptr(&cname[0]) }.to_bytes().to_vec();
+            (String::from_utf8_lossy(&name_raw).into_owned(), name_raw)
         };
 
-        devices.push(BtDevice {
+        bt_log!(debug, "Found device {:?} ({})", addr, name);
+        let device = BtDevice {
             name,
-            addr: inquiry_info.bdaddr.convert_host_byteorder(),
-        })
+            name_raw,
+            addr,
+            rssi: None,
+            class: Some(DeviceClass::from_bytes(inquiry_info.dev_class)),
+            paging: Some(PagingParams {
+                pscan_rep_mode: inquiry_info.pscan_rep_mode,
+                clock_offset: u16::from_le(inquiry_info.clock_offset),
+                found_at: time::Instant::now(),
+            }),
+            appearance: None,
+        };
+        match known {
+            Some(i) => merge_duplicate(&mut devices[i], device),
+            None => devices.push(device),
+        }
     }
 
     let local_socket = local_socket.into_raw_fd();
@@ -145,5 +911,377 @@ pub fn scan_devices(timeout: time::Duration) -> Result<Vec<BtDevice>, BtError> {
         return Err(create_error_from_last("close()"));
     }
 
-    Ok(devices)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linux::sys::MockBt;
+
+    #[test]
+    fn reads_inquiry_cache() {
+        let sys = MockBt {
+            devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+            ..MockBt::default()
+        }
+        .install();
+
+        assert_eq!(
+            read_inquiry_cache(sys, 0).unwrap(),
+            vec![BtAddr([1, 2, 3, 4, 5, 6])]
+        );
+    }
+
+    #[test]
+    fn refreshes_stale_inquiry_cache() {
+        let mock = MockBt {
+            devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+            stale_cache: true,
+            ..MockBt::default()
+        };
+        let inquiries = std::sync::Arc::clone(&mock.inquiries);
+        let sys = mock.install();
+
+        // The kernel runs an inquiry instead, which needs a valid length
+        assert_eq!(
+            read_inquiry_cache(sys, 0).unwrap(),
+            vec![BtAddr([1, 2, 3, 4, 5, 6])]
+        );
+        assert_eq!(*inquiries.lock().unwrap(), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn flushes_inquiry_cache() {
+        let mock = MockBt {
+            devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+            ..MockBt::default()
+        };
+        let inquiries = std::sync::Arc::clone(&mock.inquiries);
+        let sys = mock.install();
+
+        read_inquiry_cache(sys, IREQ_CACHE_FLUSH).unwrap();
+        assert_eq!(*inquiries.lock().unwrap(), vec![(1, IREQ_CACHE_FLUSH)]);
+    }
+
+    #[test]
+    fn looks_up_cached_class() {
+        for stale_cache in [false, true] {
+            let sys = MockBt {
+                devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+                stale_cache,
+                ..MockBt::default()
+            }
+            .install();
+
+            assert_eq!(
+                cached_class(sys, BtAddr([1, 2, 3, 4, 5, 6])).unwrap(),
+                DeviceClass(0)
+            );
+            assert!(matches!(
+                cached_class(sys, BtAddr([1, 1, 1, 1, 1, 1])),
+                Err(BtError::Desc(message)) if message.ends_with("it isn't in the inquiry cache")
+            ));
+        }
+    }
+
+    #[test]
+    fn reports_scan_progress() {
+        let mock = MockBt {
+            devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+            ..MockBt::default()
+        };
+        let inquiries = std::sync::Arc::clone(&mock.inquiries);
+        let sys = mock.install();
+
+        let mut reports = Vec::new();
+        let devices = scan_progress(sys, time::Duration::from_millis(20), &mut |progress| {
+            reports.push(progress)
+        })
+        .unwrap();
+        // Found again by later windows, but reported once
+        assert_eq!(devices.len(), 1);
+        assert!(!reports.is_empty());
+        assert!(reports.iter().all(|progress| progress.devices_found == 1));
+        assert_eq!(reports.last().unwrap().fraction, 1.);
+        let inquiries = inquiries.lock().unwrap();
+        assert!(inquiries.len() > 1);
+        assert!(inquiries
+            .iter()
+            .all(|&(_, flags)| flags == IREQ_CACHE_FLUSH));
+    }
+
+    #[test]
+    fn waits_for_device() {
+        let addr = BtAddr([6, 5, 4, 3, 2, 1]);
+        let mock = MockBt {
+            devices: vec![(addr, "Headset")],
+            ..MockBt::default()
+        };
+        let inquiries = std::sync::Arc::clone(&mock.inquiries);
+        let sys = mock.install();
+        let interval = time::Duration::from_millis(5);
+
+        let device = wait_for(sys, addr.convert_host_byteorder(), interval * 4, interval).unwrap();
+        assert_eq!(device.name, "Headset");
+        assert_eq!(device.name_raw, b"Headset");
+        let error = wait_for(sys, BtAddr::any(), interval * 4, interval).unwrap_err();
+        assert!(matches!(error, BtError::Errno(errno, _) if errno == libc::ETIMEDOUT as u32));
+        assert!(wait_for(sys, addr, interval, time::Duration::ZERO).is_err());
+
+        // Every round runs an inquiry
+        let inquiries = inquiries.lock().unwrap();
+        assert!(inquiries.len() > 2);
+        assert!(inquiries
+            .iter()
+            .all(|&(_, flags)| flags == IREQ_CACHE_FLUSH));
+    }
+
+    #[test]
+    fn reads_remote_features() {
+        let addr = BtAddr([6, 5, 4, 3, 2, 1]);
+        let error = remote_features_with(MockBt::default().install(), addr).unwrap_err();
+        assert!(matches!(error, BtError::Errno(errno, _) if errno == libc::ENOTCONN as u32));
+
+        let sys = MockBt {
+            remote_features: Some([0xBF, 0xFC, 0x8F, 0xFE, 0x98, 0x3F, 0x5B, 0x87]),
+            ..MockBt::default()
+        }
+        .install();
+        let features = remote_features_with(sys, addr).unwrap();
+        assert_eq!(features.raw[0], 0xBF);
+        assert!(features.three_slot_packets && features.sniff_mode && features.sco_link);
+        assert!(features.edr_acl_2mbps && features.edr_acl_3mbps && features.extended_sco_link);
+        assert!(features.secure_simple_pairing && features.extended_features);
+        assert!(!features.le_supported && !features.power_control_requests);
+    }
+
+    #[test]
+    fn sends_hci_command() {
+        let sys = MockBt::default().install();
+
+        assert_eq!(
+            hci_send_command_with(sys, 0, 0x3F, 0x001, &[1, 2]).unwrap(),
+            vec![0, 1, 2]
+        );
+        assert!(hci_send_command_with(sys, 0, 0x40, 0x001, &[]).is_err());
+        assert!(hci_send_command_with(sys, 0, 0x3F, 0x400, &[]).is_err());
+        assert!(hci_send_command_with(sys, 0, 0x3F, 0x001, &[0; 256]).is_err());
+    }
+
+    #[test]
+    fn converts_scan_activity() {
+        let sys = MockBt::default().install();
+        let ms = time::Duration::from_millis;
+
+        // 1.28 s and 11.25 ms, the defaults
+        assert_eq!(
+            scan_activity(ms(1280), SLOT * 18).unwrap(),
+            [0x00, 0x08, 0x12, 0x00]
+        );
+        // The interval is rounded up to an even number of slots
+        assert_eq!(
+            scan_activity(SLOT * 19, SLOT * 19).unwrap(),
+            [0x14, 0x00, 0x13, 0x00]
+        );
+        assert!(scan_activity(ms(5), ms(5)).is_err());
+        assert!(scan_activity(ms(3000), ms(20)).is_err());
+        assert!(scan_activity(ms(100), ms(200)).is_err());
+
+        assert_eq!(
+            parse_scan_activity(&[0x00, 0x08, 0x12, 0x00]).unwrap(),
+            (ms(1280), SLOT * 18)
+        );
+        assert!(parse_scan_activity(&[0x00]).is_err());
+
+        let params = ScanParams {
+            inquiry_scan_interval: ms(2560),
+            inquiry_scan_window: ms(20),
+            page_scan_interval: ms(100),
+            page_scan_window: ms(100),
+        };
+        assert!(set_scan_parameters_with(sys, 0, &params).is_ok());
+        // The mock answers without return parameters
+        assert!(scan_parameters_with(sys, 0).is_err());
+    }
+
+    #[test]
+    fn converts_page_timeout() {
+        let sys = MockBt::default().install();
+
+        assert_eq!(page_timeout_slots(SLOT).unwrap(), 1);
+        assert_eq!(
+            page_timeout_slots(time::Duration::from_millis(5120)).unwrap(),
+            0x2000
+        );
+        assert_eq!(page_timeout_slots(SLOT * 0xFFFF).unwrap(), 0xFFFF);
+        assert!(page_timeout_slots(time::Duration::from_secs(0)).is_err());
+        assert!(page_timeout_slots(time::Duration::from_secs(41)).is_err());
+
+        assert!(set_page_timeout_with(sys, 0, time::Duration::from_secs(20)).is_ok());
+        assert!(set_page_timeout_with(sys, 0, time::Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn lists_adapters() {
+        let sys = MockBt {
+            adapters: vec![BtAddr([6, 5, 4, 3, 2, 1]), BtAddr([1, 1, 1, 1, 1, 1])],
+            ..MockBt::default()
+        }
+        .install();
+
+        assert_eq!(
+            adapters(sys).unwrap(),
+            vec![
+                Adapter {
+                    id: 0,
+                    name: "hci0".to_string(),
+                    addr: BtAddr([1, 2, 3, 4, 5, 6]),
+                    up: true,
+                    friendly_name: Some("Mock adapter".to_string()),
+                },
+                Adapter {
+                    id: 1,
+                    name: "hci1".to_string(),
+                    addr: BtAddr([1, 1, 1, 1, 1, 1]),
+                    up: true,
+                    friendly_name: Some("Mock adapter".to_string()),
+                },
+            ]
+        );
+        assert_eq!(default_adapter(sys).unwrap().name, "hci0");
+    }
+
+    #[test]
+    fn scan_reports_devices_once() {
+        let sys = MockBt {
+            devices: vec![
+                (BtAddr([6, 5, 4, 3, 2, 1]), "Headset"),
+                (BtAddr([1, 1, 1, 1, 1, 1]), "GPS"),
+                (BtAddr([6, 5, 4, 3, 2, 1]), "Headset"),
+            ],
+            ..MockBt::default()
+        }
+        .install();
+
+        let devices = scan(sys, &ScanOptions::default()).unwrap();
+        let names: Vec<_> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["Headset", "GPS"]);
+    }
+
+    #[test]
+    fn merges_duplicate_responses() {
+        let mut known = BtDevice::new(UNKNOWN_NAME.to_string(), BtAddr::any()).with_rssi(-60);
+        merge_duplicate(
+            &mut known,
+            BtDevice::new("Headset".to_string(), BtAddr::any()).with_rssi(-40),
+        );
+        assert_eq!(known.name, "Headset");
+        assert_eq!(known.rssi, Some(-40));
+    }
+
+    #[test]
+    fn builds_create_connection_parameters() {
+        let paging = PagingParams {
+            pscan_rep_mode: 0x01,
+            clock_offset: 0x1234,
+            found_at: time::Instant::now(),
+        };
+        assert_eq!(
+            create_conn_params(BtAddr([1, 2, 3, 4, 5, 6]), &paging),
+            [6, 5, 4, 3, 2, 1, 0x18, 0xCC, 0x01, 0, 0x34, 0x92, 0x01]
+        );
+
+        // Devices found by different scans are equal if their parameters are
+        let later = PagingParams {
+            found_at: time::Instant::now(),
+            ..paging
+        };
+        assert_eq!(later, paging);
+    }
+
+    #[test]
+    fn scan_reports_found_devices() {
+        let sys = MockBt {
+            devices: vec![
+                (BtAddr([6, 5, 4, 3, 2, 1]), "Headset"),
+                (BtAddr([1, 1, 1, 1, 1, 1]), "GPS"),
+            ],
+            ..MockBt::default()
+        }
+        .install();
+
+        let mut devices = scan(
+            sys,
+            &ScanOptions::default().with_timeout(time::Duration::from_secs(1)),
+        )
+        .unwrap();
+        for device in &mut devices {
+            assert!(device.paging.take().is_some());
+        }
+        assert_eq!(
+            devices,
+            vec![
+                BtDevice::new("Headset".to_string(), BtAddr([1, 2, 3, 4, 5, 6]))
+                    .with_class(DeviceClass(0)),
+                BtDevice::new("GPS".to_string(), BtAddr([1, 1, 1, 1, 1, 1]))
+                    .with_class(DeviceClass(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn validates_max_responses() {
+        let sys = MockBt::default().install();
+
+        assert_eq!(
+            ScanOptions::default().with_max_responses(0).max_responses,
+            1
+        );
+        assert_eq!(
+            ScanOptions::default()
+                .with_max_responses(1000)
+                .max_responses,
+            255
+        );
+        for max_responses in [0, 256] {
+            let options = ScanOptions {
+                max_responses,
+                ..ScanOptions::default()
+            };
+            assert!(scan(sys, &options).is_err());
+        }
+    }
+
+    #[test]
+    fn ignores_responses_beyond_buffer() {
+        let sys = MockBt {
+            devices: vec![
+                (BtAddr([6, 5, 4, 3, 2, 1]), "Headset"),
+                (BtAddr([1, 2, 3, 4, 5, 6]), "Phone"),
+                (BtAddr([1, 1, 1, 1, 1, 1]), "Speaker"),
+            ],
+            ..MockBt::default()
+        }
+        .install();
+
+        let options = ScanOptions::default().with_max_responses(2);
+        let devices = scan(sys, &options).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].name, "Phone");
+    }
+
+    #[test]
+    fn scan_stops_when_asked() {
+        let sys = MockBt {
+            devices: vec![(BtAddr([6, 5, 4, 3, 2, 1]), "Headset")],
+            ..MockBt::default()
+        }
+        .install();
+
+        assert!(matches!(
+            scan_until(sys, &ScanOptions::default(), &AtomicBool::new(true)),
+            Err(BtError::Interrupted)
+        ));
+    }
 }
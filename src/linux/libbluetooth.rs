@@ -0,0 +1,126 @@
+//! Access to the functions of `libbluetooth`, see `libbluetooth!`.
+//!
+//! By default the library is linked. With the `dlopen` feature, it is loaded with `dlopen()` on
+//! the first call instead, so binaries also start on systems without it. If it can't be loaded,
+//! the functions fail with `ELIBACC` (or return a null pointer), which `create_error_from_errno`
+//! reports as `LIBBLUETOOTH_MISSING`.
+
+#[cfg(feature = "dlopen")]
+use std::sync::OnceLock;
+
+/// Description of the error when `libbluetooth` couldn't be loaded
+#[cfg(feature = "dlopen")]
+pub(super) const LIBBLUETOOTH_MISSING: &str = "libbluetooth not available";
+
+/// Declares functions of `libbluetooth`, like an `extern "C"` block
+macro_rules! libbluetooth {
+    ($($vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        #[cfg(not(feature = "dlopen"))]
+        #[link(name = "bluetooth")]
+        extern "C" {
+            $($vis fn $name($($arg: $ty),*) $(-> $ret)?;)*
+        }
+
+        $(
+            #[cfg(feature = "dlopen")]
+            $vis unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                static SYMBOL: super::libbluetooth::Symbol =
+                    super::libbluetooth::Symbol::new(concat!(stringify!($name), "\0"));
+                match SYMBOL.address() {
+                    0 => super::libbluetooth::unavailable(),
+                    address => {
+                        let function: unsafe extern "C" fn($($ty),*) $(-> $ret)? =
+                            std::mem::transmute(address);
+                        function($($arg),*)
+                    }
+                }
+            }
+        )*
+    };
+}
+
+/// A function of `libbluetooth`, looked up on first use
+#[cfg(feature = "dlopen")]
+pub(super) struct Symbol {
+    /// Nul-terminated name
+    name: &'static str,
+    address: OnceLock<usize>,
+}
+
+#[cfg(feature = "dlopen")]
+impl Symbol {
+    pub(super) const fn new(name: &'static str) -> Symbol {
+        Symbol {
+            name,
+            address: OnceLock::new(),
+        }
+    }
+
+    /// Address of the function, 0 if it isn't available
+    pub(super) fn address(&self) -> usize {
+        *self.address.get_or_init(|| match library() {
+            0 => 0,
+            library => unsafe {
+                libc::dlsym(
+                    library as *mut libc::c_void,
+                    self.name.as_ptr() as *const libc::c_char,
+                ) as usize
+            },
+        })
+    }
+}
+
+/// Fail a call of an unavailable function with `ELIBACC`
+#[cfg(feature = "dlopen")]
+pub(super) unsafe fn unavailable<T: Unavailable>() -> T {
+    *libc::__errno_location() = libc::ELIBACC;
+    T::unavailable()
+}
+
+/// Value returned by the functions if `libbluetooth` isn't available
+#[cfg(feature = "dlopen")]
+pub(super) trait Unavailable {
+    fn unavailable() -> Self;
+}
+
+#[cfg(feature = "dlopen")]
+impl Unavailable for () {
+    fn unavailable() {}
+}
+
+#[cfg(feature = "dlopen")]
+impl Unavailable for libc::c_int {
+    fn unavailable() -> Self {
+        -1
+    }
+}
+
+#[cfg(feature = "dlopen")]
+impl<T> Unavailable for *mut T {
+    fn unavailable() -> Self {
+        std::ptr::null_mut()
+    }
+}
+
+/// Handle of the loaded library, 0 if it couldn't be loaded
+#[cfg(feature = "dlopen")]
+fn library() -> usize {
+    static LIBRARY: OnceLock<usize> = OnceLock::new();
+    *LIBRARY.get_or_init(|| {
+        // Prefer the versioned name, the unversioned one is only installed with the headers
+        let names: [&[u8]; 2] = [b"libbluetooth.so.3\0", b"libbluetooth.so\0"];
+        let handle = names.iter().find_map(|name| {
+            let handle =
+                unsafe { libc::dlopen(name.as_ptr() as *const libc::c_char, libc::RTLD_NOW) };
+            (!handle.is_null()).then_some(handle as usize)
+        });
+        bt_log!(debug, "libbluetooth loaded: {}", handle.is_some());
+        handle.unwrap_or(0)
+    })
+}
+
+/// Whether `libbluetooth` couldn't be loaded
+#[cfg(feature = "dlopen")]
+pub(super) fn missing() -> bool {
+    library() == 0
+}
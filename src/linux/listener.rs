@@ -0,0 +1,222 @@
+use super::socket::{
+    create_error_from_errno, create_error_from_last, sockaddr_l2, sockaddr_rc, socket_kind,
+    BtSocket, AF_BLUETOOTH,
+};
+use crate::bluetooth::{BtAddr, BtError, BtProtocol};
+use async_io::Async;
+
+use std::{
+    io, mem,
+    os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+};
+
+/// Number of pending connections the kernel queues for a listening socket
+const LISTEN_BACKLOG: libc::c_int = 10;
+
+/// Linux (Bluez) listening socket, created with AF_BLUETOOTH
+///
+/// The socket is kept in non-blocking mode so it can be driven by the async runtime. It is closed
+/// on drop; unlike TCP, Bluetooth sockets have no `TIME_WAIT` state that would keep the channel
+/// bound afterwards.
+#[derive(Debug)]
+pub struct BtListener {
+    fd: OwnedFd,
+    protocol: BtProtocol,
+}
+
+impl BtListener {
+    /// Bind to the channel/PSM of `proto` on all local adapters and start listening
+    pub fn bind(proto: BtProtocol) -> Result<BtListener, BtError> {
+        let (kind, bt_proto) = socket_kind(proto);
+        let fd = unsafe {
+            libc::socket(
+                AF_BLUETOOTH,
+                kind | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                bt_proto,
+            )
+        };
+        if fd < 0 {
+            return Err(create_error_from_last("Failed to create Bluetooth socket"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let result = match proto {
+            BtProtocol::Rfcomm { channel } => {
+                let full_address = sockaddr_rc {
+                    rc_family: AF_BLUETOOTH as u16,
+                    rc_bdaddr: BtAddr::any(),
+                    rc_channel: channel.unwrap_or(0),
+                };
+                unsafe { bind(fd.as_raw_fd(), &full_address) }
+            }
+            BtProtocol::L2cap { psm } => {
+                let full_address = sockaddr_l2 {
+                    l2_family: AF_BLUETOOTH as u16,
+                    l2_psm: psm.to_le(),
+                    l2_bdaddr: BtAddr::any(),
+                    l2_cid: 0,
+                    l2_bdaddr_type: 0,
+                };
+                unsafe { bind(fd.as_raw_fd(), &full_address) }
+            }
+        };
+        if result < 0 {
+            return Err(create_error_from_last("Failed to bind() Bluetooth socket"));
+        }
+
+        if unsafe { libc::listen(fd.as_raw_fd(), LISTEN_BACKLOG) } < 0 {
+            return Err(create_error_from_last(
+                "Failed to listen() on Bluetooth socket",
+            ));
+        }
+
+        Ok(BtListener {
+            fd,
+            protocol: proto,
+        })
+    }
+
+    /// Accept a pending connection, fails with `WouldBlock` if there is none
+    fn try_accept(&self) -> io::Result<(BtSocket, BtAddr)> {
+        let mut full_address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut socklen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let fd = unsafe {
+            libc::accept4(
+                self.fd.as_raw_fd(),
+                &mut full_address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut socklen,
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let peer = match self.protocol {
+            BtProtocol::Rfcomm { .. } => {
+                let full_address = unsafe {
+                    *(&full_address as *const libc::sockaddr_storage as *const sockaddr_rc)
+                };
+                full_address.rc_bdaddr
+            }
+            BtProtocol::L2cap { .. } => {
+                let full_address = unsafe {
+                    *(&full_address as *const libc::sockaddr_storage as *const sockaddr_l2)
+                };
+                full_address.l2_bdaddr
+            }
+        };
+
+        let socket = unsafe { BtSocket::adopt(fd, self.protocol) };
+        Ok((socket, peer.convert_host_byteorder()))
+    }
+
+    /// Block until a connection arrives and accept it
+    pub fn accept(&self) -> Result<(BtSocket, BtAddr), BtError> {
+        loop {
+            match self.try_accept() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut pollfd = libc::pollfd {
+                        fd: self.fd.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0
+                        && nix::errno::Errno::last() != nix::errno::Errno::EINTR
+                    {
+                        return Err(create_error_from_last("poll() failed"));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(create_error_from_errno(
+                        "accept() failed",
+                        e.raw_os_error().unwrap_or(0),
+                    ))
+                }
+                Ok(accepted) => return Ok(accepted),
+            }
+        }
+    }
+
+    /// Wait for a connection using the async runtime's reactor and accept it
+    pub async fn accept_async(&self) -> Result<(BtSocket, BtAddr), BtError> {
+        let listener = Async::new_nonblocking(self.fd.as_fd())?;
+        Ok(listener.read_with(|_| self.try_accept()).await?)
+    }
+}
+
+/// Check whether another socket is bound to the RFCOMM `channel` by trying to bind to it
+fn rfcomm_channel_in_use(channel: u8) -> Result<bool, BtError> {
+    let (kind, bt_proto) = socket_kind(BtProtocol::RFCOMM);
+    let fd = unsafe { libc::socket(AF_BLUETOOTH, kind | libc::SOCK_CLOEXEC, bt_proto) };
+    if fd < 0 {
+        return Err(create_error_from_last("Failed to create Bluetooth socket"));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let full_address = sockaddr_rc {
+        rc_family: AF_BLUETOOTH as u16,
+        rc_bdaddr: BtAddr::any(),
+        rc_channel: channel,
+    };
+    if unsafe { bind(fd.as_raw_fd(), &full_address) } == 0 {
+        return Ok(false);
+    }
+    match nix::errno::errno() {
+        libc::EADDRINUSE => Ok(true),
+        errno => Err(create_error_from_errno(
+            "Failed to bind() Bluetooth socket",
+            errno,
+        )),
+    }
+}
+
+pub fn bound_rfcomm_channels() -> Result<Vec<u8>, BtError> {
+    let mut channels = Vec::new();
+    for channel in 1..=30 {
+        if rfcomm_channel_in_use(channel)? {
+            channels.push(channel);
+        }
+    }
+    Ok(channels)
+}
+
+pub fn free_rfcomm_channel() -> Result<u8, BtError> {
+    for channel in 1..=30 {
+        if !rfcomm_channel_in_use(channel)? {
+            return Ok(channel);
+        }
+    }
+    Err(BtError::Desc("No free RFCOMM channel".to_string()))
+}
+
+unsafe fn bind<T>(fd: libc::c_int, full_address: &T) -> libc::c_int {
+    libc::bind(
+        fd,
+        full_address as *const T as *const libc::sockaddr,
+        mem::size_of::<T>() as libc::socklen_t,
+    )
+}
+
+#[cfg(all(test, not(feature = "test_without_hardware")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_releases_channel() {
+        let channel = free_rfcomm_channel().unwrap();
+        let listener = BtListener::bind(BtProtocol::Rfcomm {
+            channel: Some(channel),
+        })
+        .unwrap();
+        assert!(rfcomm_channel_in_use(channel).unwrap());
+
+        drop(listener);
+        assert!(!rfcomm_channel_in_use(channel).unwrap());
+        BtListener::bind(BtProtocol::Rfcomm {
+            channel: Some(channel),
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! The BlueZ management (mgmt) API, reached through the control channel of an HCI socket.
+//!
+//! Every packet starts with a little endian header of opcode (or event code), controller index
+//! and parameter length, followed by the parameters.
+use super::{
+    hci::UNKNOWN_NAME,
+    socket::{create_error_from_last, AF_BLUETOOTH, BTPROTO_HCI},
+};
+use crate::{
+    bluetooth::{BtAddr, BtDevice, BtError},
+    eir::EirData,
+};
+
+use std::{
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+const HCI_DEV_NONE: u16 = 0xFFFF;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+
+/// Controller index for commands not directed at a specific adapter
+pub const MGMT_INDEX_NONE: u16 = 0xFFFF;
+
+const MGMT_HEADER_SIZE: usize = 6;
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+const MGMT_EV_DEVICE_FOUND: u16 = 0x0012;
+
+pub(super) const MGMT_OP_START_DISCOVERY: u16 = 0x0023;
+pub(super) const MGMT_OP_STOP_DISCOVERY: u16 = 0x0024;
+
+/// Size of the fixed part of a "Device Found" event: address, address type, RSSI, flags and EIR
+/// length
+const DEVICE_FOUND_SIZE: usize = 14;
+/// RSSI value of a "Device Found" event if the RSSI is unknown
+const RSSI_INVALID: i8 = 127;
+
+#[repr(C)]
+#[derive(Copy, Debug, Clone)]
+struct sockaddr_hci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+/// An event received on the management socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MgmtEvent {
+    /// Event code, e.g. 0x0001 for "Command Complete"
+    pub code: u16,
+    /// Index of the controller the event belongs to
+    pub index: u16,
+    /// Event parameters
+    pub params: Vec<u8>,
+}
+
+impl MgmtEvent {
+    fn parse(packet: &[u8]) -> Result<MgmtEvent, BtError> {
+        let field = |offset: usize| u16::from_le_bytes([packet[offset], packet[offset + 1]]);
+        if packet.len() < MGMT_HEADER_SIZE
+            || packet.len() != MGMT_HEADER_SIZE + usize::from(field(4))
+        {
+            return Err(BtError::Desc("Malformed mgmt event".to_string()));
+        }
+
+        Ok(MgmtEvent {
+            code: field(0),
+            index: field(2),
+            params: packet[MGMT_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// The opcode, status and return parameters if this is the reply to a command
+    fn command_reply(&self) -> Option<(u16, u8, &[u8])> {
+        match self.code {
+            MGMT_EV_CMD_COMPLETE | MGMT_EV_CMD_STATUS if self.params.len() >= 3 => Some((
+                u16::from_le_bytes([self.params[0], self.params[1]]),
+                self.params[2],
+                &self.params[3..],
+            )),
+            _ => None,
+        }
+    }
+
+    /// The device, with the data of its EIR, if this is a "Device Found" event
+    pub(super) fn device_found(&self) -> Option<BtDevice> {
+        let params = &self.params;
+        if self.code != MGMT_EV_DEVICE_FOUND || params.len() < DEVICE_FOUND_SIZE {
+            return None;
+        }
+        let eir_len = usize::from(u16::from_le_bytes([params[12], params[13]]));
+        let eir = EirData::parse(params.get(DEVICE_FOUND_SIZE..DEVICE_FOUND_SIZE + eir_len)?);
+
+        let mut addr = [0; 6];
+        addr.copy_from_slice(&params[..6]);
+        let rssi = params[7] as i8;
+        Some(BtDevice {
+            name: eir.name.unwrap_or_else(|| UNKNOWN_NAME.to_string()),
+            name_raw: eir.name_raw.unwrap_or_default(),
+            addr: BtAddr(addr).convert_host_byteorder(),
+            rssi: if rssi == RSSI_INVALID {
+                None
+            } else {
+                Some(rssi)
+            },
+            class: eir.class,
+            paging: None,
+            appearance: eir.appearance,
+        })
+    }
+}
+
+/// A socket bound to the BlueZ management channel
+///
+/// Using it requires the `CAP_NET_ADMIN` capability.
+#[derive(Debug)]
+pub struct MgmtSocket {
+    fd: OwnedFd,
+}
+
+impl MgmtSocket {
+    pub fn open() -> Result<MgmtSocket, BtError> {
+        let fd = unsafe {
+            libc::socket(
+                AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                BTPROTO_HCI as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(create_error_from_last("Failed to create HCI socket"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let full_address = sockaddr_hci {
+            hci_family: AF_BLUETOOTH as u16,
+            hci_dev: HCI_DEV_NONE,
+            hci_channel: HCI_CHANNEL_CONTROL,
+        };
+        if unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &full_address as *const sockaddr_hci as *const libc::sockaddr,
+                mem::size_of::<sockaddr_hci>() as libc::socklen_t,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last(
+                "Failed to bind() to the mgmt channel",
+            ));
+        }
+
+        Ok(MgmtSocket { fd })
+    }
+
+    /// Send the command `opcode` with `params` to the controller `index`
+    pub fn send(&self, opcode: u16, index: u16, params: &[u8]) -> Result<(), BtError> {
+        if params.len() > usize::from(u16::MAX) {
+            return Err(BtError::Desc("mgmt parameters too long".to_string()));
+        }
+
+        let mut packet = Vec::with_capacity(MGMT_HEADER_SIZE + params.len());
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        packet.extend_from_slice(&index.to_le_bytes());
+        packet.extend_from_slice(&(params.len() as u16).to_le_bytes());
+        packet.extend_from_slice(params);
+
+        if unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const _,
+                packet.len(),
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("Failed to send mgmt command"));
+        }
+        Ok(())
+    }
+
+    /// Block until the next event arrives
+    pub fn read_event(&self) -> Result<MgmtEvent, BtError> {
+        let mut packet = vec![0u8; MGMT_HEADER_SIZE + usize::from(u16::MAX)];
+        let len = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                packet.as_mut_ptr() as *mut _,
+                packet.len(),
+            )
+        };
+        if len < 0 {
+            return Err(create_error_from_last("Failed to read mgmt event"));
+        }
+        MgmtEvent::parse(&packet[..len as usize])
+    }
+
+    /// Wait up to `timeout` for the next event, `None` if none arrived in time
+    pub fn read_event_timeout(&self, timeout: Duration) -> Result<Option<MgmtEvent>, BtError> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            0 => Ok(None),
+            n if n < 0 => Err(create_error_from_last("poll() failed")),
+            _ => self.read_event().map(Some),
+        }
+    }
+
+    /// Send a command and wait for its reply, returning the reply's parameters
+    ///
+    /// Unrelated events received in the meantime are dropped.
+    pub fn command(&self, opcode: u16, index: u16, params: &[u8]) -> Result<Vec<u8>, BtError> {
+        self.send(opcode, index, params)?;
+        loop {
+            let event = self.read_event()?;
+            if event.index != index {
+                continue;
+            }
+            match event.command_reply() {
+                Some((reply_opcode, 0, reply)) if reply_opcode == opcode => {
+                    return Ok(reply.to_vec())
+                }
+                Some((reply_opcode, status, _)) if reply_opcode == opcode => {
+                    return Err(BtError::Desc(format!(
+                        "mgmt command {:#06x} failed with status {:#04x}",
+                        opcode, status
+                    )))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_command_reply() {
+        // "Command Complete" of "Read Version Information" (0x0001): version 1.22
+        let packet = [
+            0x01, 0x00, 0xFF, 0xFF, 0x06, 0x00, 0x01, 0x00, 0x00, 0x01, 0x16, 0x00,
+        ];
+        let event = MgmtEvent::parse(&packet).unwrap();
+        assert_eq!(event.index, MGMT_INDEX_NONE);
+        assert_eq!(
+            event.command_reply(),
+            Some((0x0001, 0, &[0x01, 0x16, 0x00][..]))
+        );
+
+        assert!(MgmtEvent::parse(&packet[..11]).is_err());
+    }
+
+    #[test]
+    fn parses_device_found() {
+        let mut params = vec![6, 5, 4, 3, 2, 1, 0x02, 0xC4, 0, 0, 0, 0, 9, 0];
+        params.extend_from_slice(&[0x04, 0x09, b'K', b'B', b'D', 0x03, 0x19, 0xC1, 0x03]);
+        let event = MgmtEvent {
+            code: MGMT_EV_DEVICE_FOUND,
+            index: 0,
+            params,
+        };
+
+        let device = event.device_found().unwrap();
+        assert_eq!(device.name, "KBD");
+        assert_eq!(device.addr, BtAddr([1, 2, 3, 4, 5, 6]));
+        assert_eq!(device.rssi, Some(-60));
+        assert_eq!(device.appearance.map(|a| a.0), Some(0x03C1));
+
+        // The EIR is longer than the event
+        let truncated = MgmtEvent {
+            params: event.params[..20].to_vec(),
+            ..event
+        };
+        assert!(truncated.device_found().is_none());
+    }
+}
@@ -2,11 +2,32 @@
 #[allow(dead_code)] // some types are currently not used
 mod ffi;
 
+#[macro_use]
+mod libbluetooth;
+
 mod hci;
+mod listener;
+mod mgmt;
+mod ping;
 mod sdp;
 mod socket;
+mod sys;
 
+pub(crate) use self::hci::UNKNOWN_NAME;
+#[cfg(test)]
+pub(crate) use self::{hci::scan_until, socket::tests::socket_pair, sys::MockBt};
 pub use self::{
-    hci::scan_devices,
-    socket::{BtSocket, BtSocketConnect},
+    hci::{
+        cancel_inquiry, clear_inquiry_cache, discover_devices, hci_send_command, inquiry_cache,
+        list_adapters, local_adapter, remote_features, scan_devices_progress, scan_devices_until,
+        scan_devices_with, scan_parameters, set_page_timeout, set_scan_parameters, wait_for_device,
+    },
+    listener::{bound_rfcomm_channels, free_rfcomm_channel, BtListener},
+    mgmt::{MgmtEvent, MgmtSocket, MGMT_INDEX_NONE},
+    ping::l2ping,
+    sdp::{
+        browse_services, clear_sdp_cache, query_sdp_raw, query_services, rfcomm_services,
+        sdp_available, set_sdp_cache_ttl,
+    },
+    socket::{BtInterruptHandle, BtSocket, BtSocketConnect, BtSocketPollable, BtStreamHalf},
 };
@@ -0,0 +1,112 @@
+use super::{
+    socket::{create_error_from_errno, sockaddr_l2, BtProtocolBlueZ, AF_BLUETOOTH},
+    sys::{default_sys, BtSys},
+};
+use crate::bluetooth::{BtAddr, BtError};
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+    os::unix::{io::FromRawFd, net::UnixStream},
+    time::{Duration, Instant},
+};
+
+// L2CAP signaling commands, see the Bluetooth Core Specification, Vol 3, Part A, 4
+const L2CAP_COMMAND_REJ: u8 = 0x01;
+const L2CAP_ECHO_REQ: u8 = 0x08;
+const L2CAP_ECHO_RSP: u8 = 0x09;
+
+/// Payload of the echo request, the same as the `l2ping` utility sends
+const ECHO_DATA: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZABCDEFGHIJKLMNOPQR";
+
+pub fn l2ping(addr: BtAddr, timeout: Duration) -> Result<Duration, BtError> {
+    l2ping_with(default_sys(), addr, timeout)
+}
+
+fn l2ping_with(sys: &dyn BtSys, addr: BtAddr, timeout: Duration) -> Result<Duration, BtError> {
+    if timeout.is_zero() {
+        return Err(BtError::Desc("Timeout must not be zero".to_string()));
+    }
+    let fd = sys
+        .socket(
+            AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            BtProtocolBlueZ::L2CAP as i32,
+        )
+        .map_err(|errno| create_error_from_errno("Failed to create L2CAP raw socket", errno))?;
+    let mut socket = unsafe { UnixStream::from_raw_fd(fd) };
+    // The send timeout also limits how long the connect pages the device
+    socket.set_write_timeout(Some(timeout))?;
+
+    let full_address = sockaddr_l2 {
+        l2_family: AF_BLUETOOTH as u16,
+        l2_psm: 0,
+        l2_bdaddr: addr.convert_host_byteorder(),
+        l2_cid: 0,
+        l2_bdaddr_type: 0,
+    };
+    sys.connect(
+        fd,
+        &full_address as *const sockaddr_l2 as *const libc::sockaddr,
+        mem::size_of::<sockaddr_l2>() as libc::socklen_t,
+    )
+    .map_err(|errno| create_error_from_errno("Failed to connect L2CAP raw socket", errno))?;
+
+    let ident = 200;
+    let start = Instant::now();
+    socket.write_all(&echo_request(ident, ECHO_DATA))?;
+
+    let mut buf = [0u8; 1024];
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(create_error_from_errno(
+                "No L2CAP echo response",
+                libc::ETIMEDOUT,
+            ));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = match socket.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(result) = echo_response(&buf[..len], ident) {
+            return result.map(|()| start.elapsed());
+        }
+    }
+}
+
+/// Build an echo request signaling command with the identifier `ident`
+fn echo_request(ident: u8, data: &[u8]) -> Vec<u8> {
+    let mut command = vec![L2CAP_ECHO_REQ, ident];
+    command.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    command.extend_from_slice(data);
+    command
+}
+
+/// Check whether `packet` answers the echo request `ident`, `None` if it is unrelated
+fn echo_response(packet: &[u8], ident: u8) -> Option<Result<(), BtError>> {
+    match *packet {
+        [L2CAP_ECHO_RSP, id, ..] if id == ident => Some(Ok(())),
+        [L2CAP_COMMAND_REJ, id, ..] if id == ident => Some(Err(BtError::Desc(
+            "The device rejected the L2CAP echo request".to_string(),
+        ))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_echo_response() {
+        assert_eq!(echo_request(7, b"AB"), vec![0x08, 7, 2, 0, b'A', b'B']);
+
+        assert!(matches!(echo_response(&[0x09, 7, 2, 0], 7), Some(Ok(()))));
+        assert!(matches!(echo_response(&[0x01, 7, 2, 0], 7), Some(Err(_))));
+        assert!(echo_response(&[0x09, 6, 2, 0], 7).is_none());
+        assert!(echo_response(&[], 7).is_none());
+    }
+}
@@ -3,17 +3,24 @@ use super::{
     socket::{create_error_from_errno, create_error_from_last},
 };
 
-use crate::bluetooth::{BtAddr, BtError};
+use crate::{
+    bluetooth::{BtAddr, BtError},
+    sdp::{parse_services, rfcomm_channels, SdpService, Uuid, PROTO_RFCOMM},
+};
 use itertools::Itertools;
+use nix::sys::socket::{getsockopt, sockopt};
 
 use enum_primitive::{
     enum_from_primitive, enum_from_primitive_impl, enum_from_primitive_impl_ty, FromPrimitive,
 };
 use std::{
+    collections::BTreeMap,
     mem,
     os::{raw::*, unix},
     ptr, slice,
     str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 #[repr(C)]
@@ -100,7 +107,6 @@ impl Default for sdp_list_t {
 #[derive(Copy, Clone)]
 #[repr(u32)]
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum SdpAttrReqType {
     Individual = 1,
     Range = 2,
@@ -190,6 +196,12 @@ enum_from_primitive! {
 }
 
 #[allow(dead_code)]
+/// Flags of `sdp_connect()`.
+///
+/// libbluetooth reassembles responses split by the server: `sdp_process()` sends the requests for
+/// the continuation state and appends each fragment to a buffer growing as needed, and the
+/// notification only gets the complete response. `LargeMtu` raises the incoming MTU of the L2CAP
+/// connection from 672 bytes, so large records need fewer fragments.
 enum SdpConnectFlags {
     RetryIfBusy = 0x01,
     WaitOnClose = 0x02,
@@ -197,22 +209,18 @@ enum SdpConnectFlags {
     LargeMtu = 0x08,
 }
 
-enum SdpProfile {
-    SerialPort = 0x1101,
-}
-
 enum SdpProtoUuid {
     Rfcomm = 0x0003,
 }
 
 #[cfg(target_os = "linux")]
-#[link(name = "bluetooth")]
-extern "C" {
+libbluetooth! {
     fn sdp_connect(src: *const BtAddr, dst: *const BtAddr, flags: uint32_t) -> *mut sdp_session_t;
 
     fn sdp_list_append(list: *mut sdp_list_t, d: *mut c_void) -> *mut sdp_list_t;
 
     fn sdp_uuid16_create(uuid: *mut uuid_t, data: uint16_t) -> *mut uuid_t;
+    fn sdp_uuid128_create(uuid: *mut uuid_t, data: *const c_void) -> *mut uuid_t;
     fn sdp_service_search_attr_async(
         session: *mut sdp_session_t,
         search: *const sdp_list_t,
@@ -243,6 +251,48 @@ extern "C" {
     fn sdp_record_free(rec: *mut sdp_record_t);
 }
 
+/// Error for a failed service search transaction
+unsafe fn status_error(status: u16, session: *mut sdp_session_t) -> BtError {
+    fn make_status_error(message: &str) -> BtError {
+        BtError::Desc(format!(
+            "sdp_service_search_attr_async(): Protocol error: {}",
+            message
+        ))
+    }
+
+    match status {
+        0x0001 =>
+        // SDP_INVALID_VERSION
+        {
+            make_status_error("Invalid version")
+        }
+        0x0002 =>
+        // SDP_INVALID_RECORD_HANDLE
+        {
+            make_status_error("Invalid record handle")
+        }
+        0x0003 =>
+        // SDP_INVALID_SYNTAX
+        {
+            make_status_error("Invalid syntax")
+        }
+        0x0004 =>
+        // SDP_INVALID_PDU_SIZE
+        {
+            make_status_error("Invalid PDU size")
+        }
+        0x0005 =>
+        // SDP_INVALID_CSTATE
+        {
+            make_status_error("Invalid CState")
+        }
+        _ => create_error_from_errno(
+            "sdp_service_search_attr_async(): Service record search failed",
+            sdp_get_error(session),
+        ),
+    }
+}
+
 #[derive(Debug)]
 enum QueryRFCOMMChannelState {
     New,
@@ -258,18 +308,25 @@ pub enum QueryRFCOMMChannelStatus {
     Done(u8),
 }
 
+/// Context of the errors connecting to the SDP server of a device, to tell them apart from
+/// failures of the actual connection to the service
+const SDP_CONNECT_FAILED: &str = "SDP connection failed";
+
 #[derive(Debug)]
 pub struct QueryRFCOMMChannel {
     addr: BtAddr,
+    /// Service class to search for
+    uuid: Uuid,
     session: *mut sdp_session_t,
     state: QueryRFCOMMChannelState,
 
     response: Option<Result<u8, BtError>>,
 }
 impl QueryRFCOMMChannel {
-    pub fn new(addr: BtAddr) -> Self {
+    pub fn new(addr: BtAddr, uuid: Uuid) -> Self {
         QueryRFCOMMChannel {
             addr,
+            uuid,
             session: ptr::null_mut(),
             state: QueryRFCOMMChannelState::New,
 
@@ -284,47 +341,11 @@ impl QueryRFCOMMChannel {
         size: usize,
         this_ptr: *mut c_void,
     ) {
-        fn make_status_error(message: &str) -> BtError {
-            BtError::Desc(format!(
-                "sdp_service_search_attr_async(): Protocol error: {}",
-                message
-            ))
-        }
-
         let this = &mut *(this_ptr as *mut Self);
 
         this.response = Some(match status {
             0 => Self::parse_response(slice::from_raw_parts(rsp, size)),
-
-            0x0001 =>
-            // SDP_INVALID_VERSION
-            {
-                Err(make_status_error("Invalid version"))
-            }
-            0x0002 =>
-            // SDP_INVALID_RECORD_HANDLE
-            {
-                Err(make_status_error("Invalid record handle"))
-            }
-            0x0003 =>
-            // SDP_INVALID_SYNTAX
-            {
-                Err(make_status_error("Invalid syntax"))
-            }
-            0x0004 =>
-            // SDP_INVALID_PDU_SIZE
-            {
-                Err(make_status_error("Invalid PDU size"))
-            }
-            0x0005 =>
-            // SDP_INVALID_CSTATE
-            {
-                Err(make_status_error("Invalid CState"))
-            }
-            _ => Err(create_error_from_errno(
-                "sdp_service_search_attr_async(): Service record search failed",
-                sdp_get_error(this.session),
-            )),
+            _ => Err(status_error(status, this.session)),
         });
     }
 
@@ -420,31 +441,44 @@ impl QueryRFCOMMChannel {
     }
 
     pub fn advance(&mut self) -> Result<QueryRFCOMMChannelStatus, BtError> {
+        bt_span!("bt_sdp_query", addr = ?self.addr, state = ?self.state);
         macro_rules! get_fd {
             () => {{
                 assert!(!self.session.is_null());
                 unsafe { (*self.session).sock }
             }};
-        };
+        }
 
         match self.state {
             QueryRFCOMMChannelState::New => {
-                let flags = SdpConnectFlags::NonBlocking as u32;
+                let flags = SdpConnectFlags::NonBlocking as u32 | SdpConnectFlags::LargeMtu as u32;
                 self.session = unsafe { sdp_connect(&BtAddr::any(), &self.addr, flags) };
                 if self.session.is_null() {
                     return Err(create_error_from_last(
                         "sdp_connect(): Bluetooth device not accessible",
-                    ));
+                    )
+                    .context(SDP_CONNECT_FAILED));
                 }
 
+                bt_log!(trace, "{:?}: SDP session connecting", self.addr);
                 self.state = QueryRFCOMMChannelState::Connecting;
                 Ok(QueryRFCOMMChannelStatus::WaitWritable(get_fd!()))
             }
 
             QueryRFCOMMChannelState::Connecting => {
+                // The L2CAP connection to the SDP server is established (or failed) once the
+                // socket is writable
+                match getsockopt(get_fd!(), sockopt::SocketError)? {
+                    0 => {}
+                    errno => {
+                        return Err(
+                            create_error_from_errno("connect()", errno).context(SDP_CONNECT_FAILED)
+                        )
+                    }
+                }
+
                 // specify the UUID of the application we're searching for
-                let mut service_uuid = uuid_t::default();
-                unsafe { sdp_uuid16_create(&mut service_uuid, SdpProfile::SerialPort as u16) };
+                let mut service_uuid = uuid_t::from(self.uuid);
                 let search_list = unsafe {
                     sdp_list_append(
                         ptr::null_mut(),
@@ -464,7 +498,7 @@ impl QueryRFCOMMChannel {
                     sdp_set_notify(self.session, Some(Self::notify_cb), this_ptr as *mut c_void)
                 };
 
-                // get a list of service records that have the serial port UUID
+                // get a list of service records that have the UUID
                 let result = unsafe {
                     let status = sdp_service_search_attr_async(
                         self.session,
@@ -488,6 +522,7 @@ impl QueryRFCOMMChannel {
                 // quit if sending service request failed
                 result?;
 
+                bt_log!(trace, "{:?}: SDP service search request sent", self.addr);
                 self.state = QueryRFCOMMChannelState::WaitForData;
                 Ok(QueryRFCOMMChannelStatus::WaitReadable(get_fd!()))
             }
@@ -509,10 +544,14 @@ impl QueryRFCOMMChannel {
                     self.state = QueryRFCOMMChannelState::Done;
                     match self.response.take().unwrap() {
                         Ok(channel) => Ok(QueryRFCOMMChannelStatus::Done(channel)),
-                        Err(error) => Err(error),
+                        Err(error) => {
+                            bt_log!(debug, "{:?}: SDP query failed: {:?}", self.addr, error);
+                            Err(error)
+                        }
                     }
                 } else {
                     // Transaction ongoing
+                    bt_log!(trace, "{:?}: SDP response pending", self.addr);
                     Ok(QueryRFCOMMChannelStatus::WaitReadable(get_fd!()))
                 }
             }
@@ -524,6 +563,10 @@ impl QueryRFCOMMChannel {
     }
 }
 
+// The session is owned by the query and only ever used through `&mut self`, libbluetooth doesn't
+// tie it to the thread that created it.
+unsafe impl Send for QueryRFCOMMChannel {}
+
 impl Drop for QueryRFCOMMChannel {
     fn drop(&mut self) {
         if !self.session.is_null() {
@@ -531,3 +574,224 @@ impl Drop for QueryRFCOMMChannel {
         }
     }
 }
+
+impl From<Uuid> for uuid_t {
+    fn from(uuid: Uuid) -> uuid_t {
+        let mut out = uuid_t::default();
+        match uuid.as_u16() {
+            Some(short) => unsafe { sdp_uuid16_create(&mut out, short) },
+            None => unsafe { sdp_uuid128_create(&mut out, uuid.0.as_ptr() as *const c_void) },
+        };
+        out
+    }
+}
+
+/// Response of a blocking service search, filled in by `search_notify_cb`
+struct SearchResponse {
+    session: *mut sdp_session_t,
+    response: Option<Result<Vec<u8>, BtError>>,
+}
+
+unsafe extern "C" fn search_notify_cb(
+    _: u8,
+    status: u16,
+    rsp: *const u8,
+    size: usize,
+    search_ptr: *mut c_void,
+) {
+    let search = &mut *(search_ptr as *mut SearchResponse);
+
+    search.response = Some(match status {
+        0 => Ok(slice::from_raw_parts(rsp, size).to_vec()),
+        _ => Err(status_error(status, search.session)),
+    });
+}
+
+/// Search the records of the remote device `addr` containing all `uuids` and return the raw
+/// attribute lists of the attributes `attrs`, or of all attributes if `attrs` is empty.
+///
+/// This function blocks until the transaction has completed.
+pub fn query_sdp_raw(addr: BtAddr, uuids: &[Uuid], attrs: &[u16]) -> Result<Vec<u8>, BtError> {
+    let addr = addr.convert_host_byteorder();
+    let flags = SdpConnectFlags::RetryIfBusy as u32 | SdpConnectFlags::LargeMtu as u32;
+    let session = unsafe { sdp_connect(&BtAddr::any(), &addr, flags) };
+    if session.is_null() {
+        return Err(create_error_from_last(
+            "sdp_connect(): Bluetooth device not accessible",
+        ));
+    }
+
+    let mut search = SearchResponse {
+        session,
+        response: None,
+    };
+
+    let mut uuids: Vec<uuid_t> = uuids.iter().map(|&uuid| uuid_t::from(uuid)).collect();
+    let mut search_list = ptr::null_mut();
+    for uuid in &mut uuids {
+        search_list = unsafe { sdp_list_append(search_list, uuid as *mut uuid_t as *mut c_void) };
+    }
+
+    // request either the given attributes or the range of all attributes
+    let mut attrs = attrs.to_vec();
+    let mut range = 0x0000_FFFFu32;
+    let mut attrid_list = ptr::null_mut();
+    let req_type = if attrs.is_empty() {
+        attrid_list =
+            unsafe { sdp_list_append(attrid_list, &mut range as *mut u32 as *mut c_void) };
+        SdpAttrReqType::Range
+    } else {
+        for attr in &mut attrs {
+            attrid_list = unsafe { sdp_list_append(attrid_list, attr as *mut u16 as *mut c_void) };
+        }
+        SdpAttrReqType::Individual
+    };
+
+    let status = unsafe {
+        sdp_set_notify(
+            session,
+            Some(search_notify_cb),
+            &mut search as *mut SearchResponse as *mut c_void,
+        );
+        let status = sdp_service_search_attr_async(session, search_list, req_type, attrid_list);
+
+        sdp_list_free(search_list, ptr::null());
+        sdp_list_free(attrid_list, ptr::null());
+        status
+    };
+
+    let result = if status < 0 {
+        Err(create_error_from_last(
+            "sdp_service_search_attr_async(): Sending service record search request failed",
+        ))
+    } else {
+        // The socket is blocking, each call processes one response PDU
+        while search.response.is_none() && unsafe { sdp_process(session) } == 0 {}
+
+        search.response.take().unwrap_or_else(|| {
+            Err(create_error_from_errno(
+                "sdp_process(): Service record search failed",
+                unsafe { sdp_get_error(session) },
+            ))
+        })
+    };
+
+    unsafe { sdp_close(session) };
+    result
+}
+
+/// Pseudo address libbluetooth uses for the SDP server of the local host
+const BDADDR_LOCAL: BtAddr = BtAddr([0, 0, 0, 0xff, 0xff, 0xff]);
+
+pub fn sdp_available() -> Result<bool, BtError> {
+    let session = unsafe { sdp_connect(&BtAddr::any(), &BDADDR_LOCAL, 0) };
+    if session.is_null() {
+        return match nix::errno::errno() {
+            // No SDP server listening
+            libc::ENOENT | libc::ECONNREFUSED => Ok(false),
+            errno => Err(create_error_from_errno(
+                "sdp_connect(): Connecting to the local SDP server failed",
+                errno,
+            )),
+        };
+    }
+
+    if unsafe { sdp_close(session) } < 0 {
+        return Err(create_error_from_last("sdp_close()"));
+    }
+    Ok(true)
+}
+
+/// Look up the services of remote device `addr` whose records contain `uuid`
+pub fn query_services(addr: BtAddr, uuid: Uuid) -> Result<Vec<SdpService>, BtError> {
+    parse_services(&query_sdp_raw(addr, &[uuid], &[])?)
+}
+
+/// Look up the services of remote device `addr` that use RFCOMM and map their service classes to
+/// their channels
+pub fn rfcomm_services(addr: BtAddr) -> Result<BTreeMap<Uuid, Vec<u8>>, BtError> {
+    Ok(rfcomm_channels(&query_services(
+        addr,
+        Uuid::from_u16(PROTO_RFCOMM),
+    )?))
+}
+
+/// The root of the browse group hierarchy, which publicly browsable services belong to
+const PUBLIC_BROWSE_GROUP: u16 = 0x1002;
+
+/// Look up all publicly browsable services of remote device `addr`
+pub fn browse_services(addr: BtAddr) -> Result<Vec<SdpService>, BtError> {
+    query_services(addr, Uuid::from_u16(PUBLIC_BROWSE_GROUP))
+}
+
+/// How long the RFCOMM channels found by SDP searches are reused by default
+const DEFAULT_CHANNEL_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// RFCOMM channels found by the SDP searches of `BtSocket::connect`
+static CHANNEL_CACHE: Mutex<ChannelCache> = Mutex::new(ChannelCache::new());
+
+pub(super) fn channel_cache() -> &'static Mutex<ChannelCache> {
+    &CHANNEL_CACHE
+}
+
+pub fn clear_sdp_cache() {
+    CHANNEL_CACHE.lock().unwrap().clear();
+}
+
+pub fn set_sdp_cache_ttl(ttl: Option<Duration>) {
+    CHANNEL_CACHE.lock().unwrap().set_ttl(ttl);
+}
+
+/// Maps remote devices and service classes to the RFCOMM channel of the service
+#[derive(Debug)]
+pub(crate) struct ChannelCache {
+    /// How long entries are valid, `None` disables the cache
+    ttl: Option<Duration>,
+    entries: BTreeMap<(BtAddr, Uuid), (u8, Instant)>,
+}
+
+impl ChannelCache {
+    pub(super) const fn new() -> ChannelCache {
+        ChannelCache {
+            ttl: Some(DEFAULT_CHANNEL_CACHE_TTL),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn get(&self, addr: BtAddr, uuid: Uuid) -> Option<u8> {
+        let ttl = self.ttl?;
+        self.entries
+            .get(&(addr, uuid))
+            .filter(|(_, found_at)| found_at.elapsed() < ttl)
+            .map(|&(channel, _)| channel)
+    }
+
+    pub(super) fn insert(&mut self, addr: BtAddr, uuid: Uuid, channel: u8) {
+        if self.ttl.is_some() {
+            self.entries.insert((addr, uuid), (channel, Instant::now()));
+        }
+    }
+
+    /// Forget all channels of the device `addr`, e.g. because connecting failed
+    pub(super) fn remove(&mut self, addr: BtAddr) {
+        self.entries
+            .retain(|&(entry_addr, _), _| entry_addr != addr);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(super) fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+        if ttl.is_none() {
+            self.clear();
+        }
+    }
+}
+
+impl Default for ChannelCache {
+    fn default() -> Self {
+        ChannelCache::new()
+    }
+}
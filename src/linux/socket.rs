@@ -1,17 +1,45 @@
-use super::sdp::{QueryRFCOMMChannel, QueryRFCOMMChannelStatus};
-use crate::bluetooth::{BtAddr, BtAsync, BtError, BtProtocol};
+use super::{
+    hci,
+    sdp::QueryRFCOMMChannelStatus,
+    sys::{default_sys, BtSys, SdpQuery},
+};
+#[cfg(feature = "mio")]
+use crate::bluetooth::BtAsync;
+use crate::bluetooth::{
+    check_rfcomm_channel, Adapter, BtAddr, BtError, BtLineMode, BtProtocol, BtRole, BtSocketAddr,
+    BtTransport, ConnectMetrics, DeviceClass, FlowInfo, PagingParams,
+};
+use crate::framing::Framer;
+use crate::sdp::{Profile, Uuid};
+use async_io::{Async, Timer};
 use async_std::os::unix::net::UnixStream;
+#[cfg(feature = "mio")]
 use mio::{unix::EventedFd, Poll, Ready};
+use nix::sys::socket::{getsockopt, setsockopt, sockopt};
 
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixStream as StdUnixStream;
 
 use std::{
+    fs::File,
+    future::Future,
     io::{Read, Write},
     mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    task::{self, ready, Context},
+    time::{Duration, Instant},
 };
 
 pub fn create_error_from_errno(message: &str, errno: i32) -> BtError {
+    #[cfg(feature = "dlopen")]
+    if errno == libc::ELIBACC && super::libbluetooth::missing() {
+        return BtError::Desc(super::libbluetooth::LIBBLUETOOTH_MISSING.to_string());
+    }
     let nix_error = nix::Error::from_errno(nix::errno::from_i32(errno));
     BtError::Errno(
         errno as u32,
@@ -23,10 +51,20 @@ pub fn create_error_from_last(message: &str) -> BtError {
     create_error_from_errno(message, nix::errno::errno())
 }
 
-const AF_BLUETOOTH: i32 = 31;
+pub(super) const AF_BLUETOOTH: i32 = 31;
+
+/// `SO_PRIORITY` of sockets in low latency mode, the highest one without `CAP_NET_ADMIN`
+const LOW_LATENCY_PRIORITY: libc::c_int = 6;
+
+/// How often `finish` checks whether the outgoing data has been transmitted
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const SOL_RFCOMM: libc::c_int = 18;
+const RFCOMM_LM: libc::c_int = 0x03;
+const RFCOMM_LM_MASTER: libc::c_int = 0x0001;
 
 const BTPROTO_L2CAP: isize = 0;
-const BTPROTO_HCI: isize = 1;
+pub(super) const BTPROTO_HCI: isize = 1;
 const BTPROTO_SCO: isize = 2;
 const BTPROTO_RFCOMM: isize = 3;
 const BTPROTO_BNEP: isize = 4;
@@ -35,7 +73,7 @@ const BTPROTO_HIDP: isize = 6;
 const BTPROTO_AVDTP: isize = 7;
 
 #[allow(dead_code)]
-enum BtProtocolBlueZ {
+pub(super) enum BtProtocolBlueZ {
     L2CAP = BTPROTO_L2CAP,
     HCI = BTPROTO_HCI,
     SCO = BTPROTO_SCO,
@@ -48,10 +86,44 @@ enum BtProtocolBlueZ {
 
 #[repr(C)]
 #[derive(Copy, Debug, Clone)]
-struct sockaddr_rc {
-    rc_family: libc::sa_family_t,
-    rc_bdaddr: BtAddr,
-    rc_channel: u8,
+pub(super) struct sockaddr_rc {
+    pub rc_family: libc::sa_family_t,
+    pub rc_bdaddr: BtAddr,
+    pub rc_channel: u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Debug, Clone)]
+pub(super) struct sockaddr_l2 {
+    pub l2_family: libc::sa_family_t,
+    pub l2_psm: u16,
+    pub l2_bdaddr: BtAddr,
+    pub l2_cid: u16,
+    pub l2_bdaddr_type: u8,
+}
+
+/// How long `BtSocketConnect::complete` waits for the SDP search, including the connection to
+/// the SDP server
+const SDP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Paging parameters older than this are ignored, like the kernel's inquiry cache does
+const PAGING_PARAMS_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Address type of the socket address for the given transport
+fn bdaddr_type(transport: BtTransport) -> u8 {
+    match transport {
+        BtTransport::BrEdr => 0,    // BDADDR_BREDR
+        BtTransport::LePublic => 1, // BDADDR_LE_PUBLIC
+        BtTransport::LeRandom => 2, // BDADDR_LE_RANDOM
+    }
+}
+
+/// Socket type and BlueZ protocol number used for sockets of the given protocol
+pub(super) fn socket_kind(proto: BtProtocol) -> (libc::c_int, libc::c_int) {
+    match proto {
+        BtProtocol::Rfcomm { .. } => (libc::SOCK_STREAM, BtProtocolBlueZ::RFCOMM as i32),
+        BtProtocol::L2cap { .. } => (libc::SOCK_SEQPACKET, BtProtocolBlueZ::L2CAP as i32),
+    }
 }
 
 /// Linux (Bluez) socket, created with AF_BLUETOOTH
@@ -59,233 +131,2312 @@ struct sockaddr_rc {
 pub struct BtSocket {
     pub stream: StdUnixStream,
     pub fd: i32,
+    protocol: BtProtocol,
+    transport: BtTransport,
+    sys: &'static dyn BtSys,
+    /// Eventfd that interrupts blocking reads, created by the first `interrupt_handle()` call
+    interrupt: OnceLock<Arc<OwnedFd>>,
+    mode: BtLineMode,
+    /// Whether the last byte read in `BtLineMode::Cooked` was a CR
+    after_cr: bool,
+    /// Data `read_frame` received beyond the last frame
+    frame_buf: Vec<u8>,
+    role: BtRole,
+    /// Bytes transferred, shared with the halves of `stream_half`
+    counters: Arc<IoCounters>,
+    /// Peer of a socket adopted by `from_connected_fd`
+    peer: Option<BtSocketAddr>,
+    /// Device (and RFCOMM channel) of the last successful connect, kept by `renew`
+    last_peer: Option<BtSocketAddr>,
+}
+
+/// The options of a socket that `renew` transfers to the new socket
+#[derive(Debug)]
+struct SocketOptions {
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+    priority: libc::c_int,
+    nonblocking: bool,
+    cloexec: bool,
+}
+
+impl SocketOptions {
+    fn read(socket: &BtSocket) -> Result<SocketOptions, BtError> {
+        Ok(SocketOptions {
+            recv_buffer_size: socket.recv_buffer_size()?,
+            send_buffer_size: socket.send_buffer_size()?,
+            priority: socket.priority()?,
+            nonblocking: socket.nonblocking()?,
+            cloexec: socket.cloexec()?,
+        })
+    }
+
+    fn apply(&self, socket: &BtSocket) -> Result<(), BtError> {
+        // Only sizes that were changed are set, the kernel doubles them again
+        if socket.recv_buffer_size()? != self.recv_buffer_size {
+            socket.set_recv_buffer_size(self.recv_buffer_size / 2)?;
+        }
+        if socket.send_buffer_size()? != self.send_buffer_size {
+            socket.set_send_buffer_size(self.send_buffer_size / 2)?;
+        }
+        if socket.priority()? != self.priority {
+            socket.set_priority(self.priority)?;
+        }
+        socket.stream.set_nonblocking(self.nonblocking)?;
+        socket.set_cloexec(self.cloexec)
+    }
+}
+
+/// Number of bytes received and sent through a socket
+#[derive(Debug, Default)]
+struct IoCounters {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl IoCounters {
+    fn add_read(&self, len: usize) {
+        self.read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn add_written(&self, len: usize) {
+        self.written.fetch_add(len as u64, Ordering::Relaxed);
+    }
 }
 
 impl BtSocket {
     /// Create an (still) unconnected socket, like `crate::BtSocket`
     pub fn new(proto: BtProtocol) -> Result<BtSocket, BtError> {
-        match proto {
-            BtProtocol::RFCOMM => {
-                let fd = unsafe {
-                    libc::socket(
-                        AF_BLUETOOTH,
-                        libc::SOCK_STREAM,
-                        BtProtocolBlueZ::RFCOMM as i32,
+        Self::new_with(default_sys(), proto)
+    }
+
+    fn new_with(sys: &'static dyn BtSys, proto: BtProtocol) -> Result<BtSocket, BtError> {
+        let (kind, bt_proto) = socket_kind(proto);
+        match sys.socket(AF_BLUETOOTH, kind | libc::SOCK_CLOEXEC, bt_proto) {
+            Err(errno) => Err(create_error_from_errno(
+                "Failed to create Bluetooth socket",
+                errno,
+            )),
+            Ok(fd) => Ok(BtSocket {
+                sys,
+                ..unsafe { Self::adopt(fd, proto) }
+            }),
+        }
+    }
+
+    /// Take ownership of `fd` after checking that it is an RFCOMM socket
+    pub fn from_fd(fd: RawFd) -> Result<BtSocket, BtError> {
+        let option = |name, message| {
+            let mut value: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            if unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    name,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            } < 0
+            {
+                Err(create_error_from_last(message))
+            } else {
+                Ok(value)
+            }
+        };
+
+        let domain = option(libc::SO_DOMAIN, "getsockopt(SO_DOMAIN) failed")?;
+        let kind = option(libc::SO_TYPE, "getsockopt(SO_TYPE) failed")?;
+        let protocol = option(libc::SO_PROTOCOL, "getsockopt(SO_PROTOCOL) failed")?;
+        if (domain, kind, protocol)
+            != (
+                AF_BLUETOOTH,
+                libc::SOCK_STREAM,
+                BtProtocolBlueZ::RFCOMM as libc::c_int,
+            )
+        {
+            return Err(BtError::Desc(
+                "File descriptor is not an RFCOMM socket".to_string(),
+            ));
+        }
+
+        Ok(unsafe { Self::adopt(fd, BtProtocol::RFCOMM) })
+    }
+
+    /// Take ownership of a socket like `from_fd`, recording that it is connected to `channel` of
+    /// the device `addr`
+    pub fn from_connected_fd(fd: RawFd, addr: BtAddr, channel: u8) -> Result<BtSocket, BtError> {
+        // Check the channel first, the fd must stay with the caller on errors
+        let channel = check_rfcomm_channel(channel)?;
+        Ok(BtSocket {
+            protocol: BtProtocol::Rfcomm {
+                channel: Some(channel),
+            },
+            peer: Some(BtSocketAddr::new(addr, Some(channel))),
+            last_peer: Some(BtSocketAddr::new(addr, Some(channel))),
+            ..Self::from_fd(fd)?
+        })
+    }
+
+    /// Take ownership of an already existing socket `fd` of the given protocol
+    pub(super) unsafe fn adopt(fd: RawFd, proto: BtProtocol) -> BtSocket {
+        BtSocket {
+            stream: StdUnixStream::from_raw_fd(fd),
+            fd,
+            protocol: proto,
+            transport: BtTransport::BrEdr,
+            sys: default_sys(),
+            interrupt: OnceLock::new(),
+            mode: BtLineMode::Raw,
+            after_cr: false,
+            frame_buf: Vec::new(),
+            role: BtRole::Any,
+            counters: Arc::default(),
+            peer: None,
+            last_peer: None,
+        }
+    }
+
+    pub fn set_transport(&mut self, transport: BtTransport) -> Result<(), BtError> {
+        match (self.protocol, transport) {
+            (BtProtocol::Rfcomm { .. }, BtTransport::LePublic | BtTransport::LeRandom) => {
+                Err(BtError::Desc(
+                    "RFCOMM is only available over BR/EDR (classic Bluetooth), not over LE"
+                        .to_string(),
+                ))
+            }
+            _ => {
+                self.transport = transport;
+                Ok(())
+            }
+        }
+    }
+
+    /// Replace the socket with a new, unconnected one with the same settings
+    pub fn renew(&mut self) -> Result<(), BtError> {
+        let role = self.role;
+        let options = SocketOptions::read(self)?;
+        // Create the new socket first, so the interrupt handles keep working if that fails
+        let renewed = Self::new_with(self.sys, self.protocol)?;
+        *self = BtSocket {
+            transport: self.transport,
+            interrupt: mem::take(&mut self.interrupt),
+            mode: self.mode,
+            last_peer: self.last_peer,
+            ..renewed
+        };
+        if role != BtRole::Any {
+            self.set_rfcomm_role(role)?;
+        }
+        options.apply(self)
+    }
+
+    /// Shut the connection down, replace the socket with a new one and connect it to the device
+    /// and channel of the last successful connect again
+    pub fn shutdown_and_reconnect(&mut self) -> Result<(), BtError> {
+        let peer = self
+            .last_peer
+            .ok_or_else(|| BtError::Desc("The socket was never connected".to_string()))?;
+        // Also ends the connection for duplicates of the descriptor, like `get_stream_std`. A lost
+        // link is not connected anymore.
+        match self.stream.shutdown(Shutdown::Both) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {}
+            result => result?,
+        }
+        self.renew()?;
+        let connect = match peer.channel {
+            Some(channel) => self.connect_channel(peer.addr, channel)?,
+            None => self.connect(peer.addr),
+        };
+        connect.complete().map(|_| ())
+    }
+
+    pub fn connect_any_adapter(&mut self, addr: BtAddr) -> Result<(), BtError> {
+        let mut adapters: Vec<Adapter> = hci::adapters(self.sys)?
+            .into_iter()
+            .filter(|adapter| adapter.up)
+            .collect();
+        // Try the default adapter first
+        if let Ok(default_id) = self.sys.hci_get_route() {
+            adapters.sort_by_key(|adapter| libc::c_int::from(adapter.id) != default_id);
+        }
+
+        let mut error = BtError::Desc("No local adapter is up".to_string());
+        for (i, adapter) in adapters.iter().enumerate() {
+            if i > 0 {
+                self.renew()?;
+            }
+            let result = self
+                .bind_adapter(adapter.addr)
+                .and_then(|()| self.connect(addr).complete().map(|_| ()));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    bt_log!(
+                        debug,
+                        "{:?}: connecting through {} failed ({:?})",
+                        addr,
+                        adapter.name,
+                        e
+                    );
+                    error = e.context(&format!("Connecting through {} failed", adapter.name));
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Bind the socket to the local adapter with address `adapter`, so connections go through it
+    fn bind_adapter(&self, adapter: BtAddr) -> Result<(), BtError> {
+        let adapter = adapter.convert_host_byteorder();
+        let result = match self.protocol {
+            BtProtocol::Rfcomm { .. } => {
+                let full_address = sockaddr_rc {
+                    rc_family: AF_BLUETOOTH as u16,
+                    rc_bdaddr: adapter,
+                    rc_channel: 0,
+                };
+                unsafe {
+                    libc::bind(
+                        self.fd,
+                        &full_address as *const sockaddr_rc as *const libc::sockaddr,
+                        mem::size_of::<sockaddr_rc>() as libc::socklen_t,
                     )
+                }
+            }
+            BtProtocol::L2cap { .. } => {
+                let full_address = sockaddr_l2 {
+                    l2_family: AF_BLUETOOTH as u16,
+                    l2_psm: 0,
+                    l2_bdaddr: adapter,
+                    l2_cid: 0,
+                    l2_bdaddr_type: bdaddr_type(self.transport),
                 };
-                if fd < 0 {
-                    Err(create_error_from_last("Failed to create Bluetooth socket"))
-                } else {
-                    Ok(BtSocket {
-                        stream: unsafe { StdUnixStream::from_raw_fd(fd) },
-                        fd,
-                    })
+                unsafe {
+                    libc::bind(
+                        self.fd,
+                        &full_address as *const sockaddr_l2 as *const libc::sockaddr,
+                        mem::size_of::<sockaddr_l2>() as libc::socklen_t,
+                    )
                 }
             }
+        };
+        if result < 0 {
+            return Err(create_error_from_last("bind() to the local adapter failed"));
+        }
+        Ok(())
+    }
+
+    pub fn set_rfcomm_role(&mut self, role: BtRole) -> Result<(), BtError> {
+        if !matches!(self.protocol, BtProtocol::Rfcomm { .. }) {
+            return Err(BtError::Desc(
+                "The RFCOMM role requires an RFCOMM socket".to_string(),
+            ));
+        }
+
+        let mut link_mode: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if unsafe {
+            libc::getsockopt(
+                self.fd,
+                SOL_RFCOMM,
+                RFCOMM_LM,
+                &mut link_mode as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("getsockopt(RFCOMM_LM) failed"));
+        }
+
+        // Keep the other link mode flags, like authentication and encryption
+        match role {
+            BtRole::Any => link_mode &= !RFCOMM_LM_MASTER,
+            BtRole::Master => link_mode |= RFCOMM_LM_MASTER,
+        }
+        if unsafe {
+            libc::setsockopt(
+                self.fd,
+                SOL_RFCOMM,
+                RFCOMM_LM,
+                &link_mode as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("setsockopt(RFCOMM_LM) failed"));
+        }
+        self.role = role;
+        Ok(())
+    }
+
+    pub fn connect_with_fallback(
+        &mut self,
+        addr: BtAddr,
+        channel: u8,
+    ) -> Result<BtSocketConnect<'_>, BtError> {
+        let channel = check_rfcomm_channel(channel)?;
+        Ok(BtSocketConnect {
+            fallback_channel: Some(channel),
+            ..self.connect(addr)
+        })
+    }
+
+    pub fn connect_channel(
+        &mut self,
+        addr: BtAddr,
+        channel: u8,
+    ) -> Result<BtSocketConnect<'_>, BtError> {
+        match self.protocol {
+            BtProtocol::Rfcomm { .. } => {
+                let channel = check_rfcomm_channel(channel)?;
+                Ok(BtSocketConnect {
+                    state: BtSocketConnectState::Channel(channel),
+                    ..self.connect(addr)
+                })
+            }
+            _ => Err(BtError::Desc(
+                "Connecting to a channel requires an RFCOMM socket".to_string(),
+            )),
         }
     }
+
+    pub fn connect_channel_timeout(
+        &mut self,
+        addr: BtAddr,
+        channel: u8,
+        timeout: Duration,
+    ) -> Result<(), BtError> {
+        self.connect_channel(addr, channel)?
+            .complete_within(timeout)
+            .map(|_| ())
+    }
+
     /// Initiate connection
-    pub fn connect(&mut self, addr: BtAddr) -> BtSocketConnect {
+    pub fn connect(&mut self, addr: BtAddr) -> BtSocketConnect<'_> {
+        self.connect_profile(addr, Profile::SerialPort)
+    }
+
+    /// Initiate connection, searching the channel of `profile` instead of the serial port
+    pub fn connect_profile(&mut self, addr: BtAddr, profile: Profile) -> BtSocketConnect<'_> {
         let addr = addr.convert_host_byteorder();
 
-        BtSocketConnect::new(self, addr)
+        BtSocketConnect::new(self, addr, profile.uuid())
+    }
+
+    pub async fn connect_future(&mut self, addr: BtAddr) -> Result<(), BtError> {
+        let mut connect = self.connect(addr);
+        let mut guard = AbortOnDrop {
+            connect: &mut connect,
+            armed: true,
+        };
+        let result = guard.connect.complete_async().await;
+        guard.armed = false;
+        result.map(|_| ())
+    }
+
+    /// Connect like `connect(addr).complete()`, but page the device with `paging` first if the
+    /// parameters are recent enough
+    pub fn connect_with_paging(
+        &mut self,
+        addr: BtAddr,
+        paging: Option<PagingParams>,
+    ) -> Result<(), BtError> {
+        let paging = paging.filter(|paging| paging.found_at.elapsed() < PAGING_PARAMS_MAX_AGE);
+        if let Some(paging) = paging {
+            if let Err(e) = hci::create_connection(self.sys, addr, &paging) {
+                bt_log!(
+                    debug,
+                    "{:?}: paging with cached parameters failed ({:?})",
+                    addr,
+                    e
+                );
+            }
+        }
+        self.connect(addr).complete().map(|_| ())
+    }
+
+    /// Connect like `connect(addr).complete()`, but retry up to `attempts` times in total after
+    /// retryable errors. Each retry waits for `backoff` first.
+    pub fn connect_retry(
+        &mut self,
+        addr: BtAddr,
+        attempts: usize,
+        backoff: Duration,
+    ) -> Result<(), BtError> {
+        let mut attempt = 1;
+        loop {
+            let error = match self.connect(addr).complete() {
+                Ok(_) => return Ok(()),
+                Err(error) => error,
+            };
+            match error {
+                error if attempt < attempts && error.is_retryable() => {
+                    bt_log!(
+                        debug,
+                        "{:?}: connect attempt {} failed ({:?}), retrying",
+                        addr,
+                        attempt,
+                        error
+                    );
+                }
+                error => return Err(error),
+            }
+
+            // A socket can't be connected again after a failed connect, start over with a new one
+            self.renew()?;
+            attempt += 1;
+            std::thread::sleep(backoff);
+        }
     }
 
     pub fn get_fd(&self) -> i32 {
         self.fd
     }
 
+    /// Duplicate the socket's descriptor, so the stream and the socket each own one
     pub fn get_stream_std(&self) -> StdUnixStream {
-        let stream: StdUnixStream = unsafe { StdUnixStream::from_raw_fd(self.fd) };
-        stream
+        self.try_get_stream_std()
+            .expect("Duplicating the socket's file descriptor failed")
+    }
+
+    pub fn try_get_stream_std(&self) -> Result<StdUnixStream, BtError> {
+        Ok(self.stream.try_clone()?)
     }
 
     pub fn get_stream(&self) -> UnixStream {
-        let stream: UnixStream = unsafe { UnixStream::from_raw_fd(self.fd) };
-        stream
+        UnixStream::from(self.get_stream_std())
     }
-}
 
-impl From<nix::Error> for BtError {
-    fn from(e: nix::Error) -> BtError {
-        BtError::Errno(e.as_errno().map(|x| x as u32).unwrap_or(0), e.to_string())
+    /// Duplicate the socket's descriptor like `get_stream_std`, but keep counting the bytes
+    pub fn stream_half(&self) -> BtStreamHalf {
+        BtStreamHalf {
+            stream: self.get_stream_std(),
+            counters: self.counters.clone(),
+        }
     }
-}
 
-impl mio::Evented for BtSocket {
-    fn register(
-        &self,
-        poll: &Poll,
-        token: mio::Token,
-        interest: Ready,
-        opts: mio::PollOpt,
-    ) -> std::io::Result<()> {
-        EventedFd(&self.get_fd()).register(poll, token, interest, opts)
+    pub fn bytes_read(&self) -> u64 {
+        self.counters.read.load(Ordering::Relaxed)
     }
 
-    fn reregister(
-        &self,
-        poll: &Poll,
-        token: mio::Token,
-        interest: Ready,
-        opts: mio::PollOpt,
-    ) -> std::io::Result<()> {
-        EventedFd(&self.get_fd()).reregister(poll, token, interest, opts)
+    pub fn bytes_written(&self) -> u64 {
+        self.counters.written.load(Ordering::Relaxed)
     }
 
-    fn deregister(&self, poll: &Poll) -> std::io::Result<()> {
-        EventedFd(&self.get_fd()).deregister(poll)
+    /// Wait until the remote device has closed the connection
+    pub async fn closed(&self) -> Result<(), BtError> {
+        // Watch the socket through a private epoll instance that is only interested in hang-ups,
+        // the epoll fd itself then becomes readable once the connection was closed.
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(create_error_from_last("epoll_create1() failed"));
+        }
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLRDHUP as u32,
+            u64: 0,
+        };
+        if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, self.fd, &mut event) }
+            < 0
+        {
+            return Err(create_error_from_last("epoll_ctl() failed"));
+        }
+
+        Async::new_nonblocking(epoll)?.readable().await?;
+        Ok(())
     }
-}
 
-impl Read for BtSocket {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.stream.read(buf)
+    pub fn is_alive(&self) -> Result<bool, BtError> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLRDHUP,
+            revents: 0,
+        };
+        while unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+            if nix::errno::Errno::last() != nix::errno::Errno::EINTR {
+                return Err(create_error_from_last("poll() failed"));
+            }
+        }
+        let dead = libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR | libc::POLLNVAL;
+        Ok(pollfd.revents & dead == 0)
     }
-}
 
-impl Write for BtSocket {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.stream.write(buf)
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<(), BtError> {
+        Ok(setsockopt(self.fd, sockopt::RcvBuf, &size)?)
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.stream.flush()
+    pub fn recv_buffer_size(&self) -> Result<usize, BtError> {
+        Ok(getsockopt(self.fd, sockopt::RcvBuf)?)
     }
-}
 
-#[derive(Debug)]
-enum BtSocketConnectState {
-    SDPSearch,
-    Connect,
-    Done,
-}
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<(), BtError> {
+        Ok(setsockopt(self.fd, sockopt::SndBuf, &size)?)
+    }
 
-/// Manages the bluetooth connection process when used from an asynchronous client.
-#[derive(Debug)]
-pub struct BtSocketConnect<'a> {
-    addr: BtAddr,
-    pollfd: RawFd,
-    state: BtSocketConnectState,
-    socket: &'a mut BtSocket,
-    query: QueryRFCOMMChannel,
-}
-impl<'a> BtSocketConnect<'a> {
-    fn new(socket: &'a mut BtSocket, addr: BtAddr) -> Self {
-        BtSocketConnect {
-            addr,
-            pollfd: 0,
-            query: QueryRFCOMMChannel::new(addr),
-            socket,
-            state: BtSocketConnectState::SDPSearch,
+    pub fn send_buffer_size(&self) -> Result<usize, BtError> {
+        Ok(getsockopt(self.fd, sockopt::SndBuf)?)
+    }
+
+    /// Raise the priority the kernel sends the socket's packets to the controller with
+    pub fn set_low_latency(&self, enabled: bool) -> Result<(), BtError> {
+        self.set_priority(if enabled { LOW_LATENCY_PRIORITY } else { 0 })
+    }
+
+    pub fn low_latency(&self) -> Result<bool, BtError> {
+        Ok(self.priority()? >= LOW_LATENCY_PRIORITY)
+    }
+
+    fn set_priority(&self, priority: libc::c_int) -> Result<(), BtError> {
+        if unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                &priority as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("setsockopt(SO_PRIORITY) failed"));
         }
+        Ok(())
     }
-    /// Advance the connection process to the next state
-    pub fn advance(&mut self) -> Result<BtAsync, BtError> {
-        match self.state {
-            BtSocketConnectState::SDPSearch => {
-                match self.query.advance()? {
-                    // Forward SDP's pleas for another round
-                    QueryRFCOMMChannelStatus::WaitReadable(fd) => {
-                        self.pollfd = fd;
-                        Ok(BtAsync::WaitFor(self, Ready::readable()))
-                    }
 
-                    QueryRFCOMMChannelStatus::WaitWritable(fd) => {
-                        self.pollfd = fd;
-                        Ok(BtAsync::WaitFor(self, Ready::writable()))
-                    }
+    fn priority(&self) -> Result<libc::c_int, BtError> {
+        let mut priority: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                &mut priority as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("getsockopt(SO_PRIORITY) failed"));
+        }
+        Ok(priority)
+    }
 
-                    // Received channel number, start actual connection
-                    QueryRFCOMMChannelStatus::Done(channel) => {
-                        let full_address = sockaddr_rc {
-                            rc_family: AF_BLUETOOTH as u16,
-                            rc_bdaddr: self.addr,
-                            rc_channel: channel,
-                        };
+    fn cloexec(&self) -> Result<bool, BtError> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(create_error_from_last("fcntl(F_GETFD) failed"));
+        }
+        Ok(flags & libc::FD_CLOEXEC != 0)
+    }
 
-                        self.pollfd = self.socket.get_fd();
-                        if unsafe {
-                            libc::connect(
-                                self.pollfd,
-                                &full_address as *const sockaddr_rc as *const libc::sockaddr,
-                                mem::size_of::<sockaddr_rc>() as u32,
-                            )
-                        } < 0
-                        {
-                            Err(create_error_from_last(
-                                "Failed to connect() to target device",
-                            ))
-                        } else {
-                            self.state = BtSocketConnectState::Connect;
-                            Ok(BtAsync::WaitFor(self, Ready::writable()))
-                        }
-                    }
+    fn nonblocking(&self) -> Result<bool, BtError> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(create_error_from_last("fcntl(F_GETFL) failed"));
+        }
+        Ok(flags & libc::O_NONBLOCK != 0)
+    }
+
+    pub fn write_all_timeout(&mut self, mut buf: &[u8], timeout: Duration) -> Result<(), BtError> {
+        let deadline = Instant::now() + timeout;
+        let mut transferred = 0;
+        while !buf.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+            let timeout_ms = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+                0 => return Err(BtError::Timeout { transferred }),
+                n if n < 0 && nix::errno::Errno::last() != nix::errno::Errno::EINTR => {
+                    return Err(create_error_from_last("poll() failed"))
                 }
+                _ => {}
             }
 
-            BtSocketConnectState::Connect => {
-                // First check if socket is actually connected using `getpeername()`
-                let mut full_address = sockaddr_rc {
-                    rc_family: AF_BLUETOOTH as u16,
-                    rc_bdaddr: BtAddr::any(),
-                    rc_channel: 0,
-                };
-                let mut socklen = mem::size_of::<sockaddr_rc>() as libc::socklen_t;
-                if unsafe {
-                    libc::getpeername(
-                        self.pollfd,
-                        &mut full_address as *mut sockaddr_rc as *mut libc::sockaddr,
-                        &mut socklen,
-                    )
-                } < 0
-                {
-                    if nix::errno::Errno::last() == nix::errno::Errno::ENOTCONN {
-                        // Connection has failed – obtain actual error code using `read()`
-                        let mut buf = [0u8; 1];
-                        nix::unistd::read(self.pollfd, &mut buf).unwrap_err();
-                        Err(create_error_from_last(
-                            "Failed to connect() to target device",
-                        ))
-                    } else {
-                        // Some unexpected error
-                        Err(create_error_from_last("getpeername() failed"))
-                    }
-                } else {
-                    self.state = BtSocketConnectState::Done;
-                    Ok(BtAsync::Done)
+            let sent = unsafe {
+                libc::send(
+                    self.fd,
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                )
+            };
+            if sent < 0 {
+                match nix::errno::Errno::last() {
+                    nix::errno::Errno::EAGAIN | nix::errno::Errno::EINTR => {}
+                    _ => return Err(create_error_from_last("send() failed")),
                 }
+            } else {
+                self.counters.add_written(sent as usize);
+                transferred += sent as usize;
+                buf = &buf[sent as usize..];
             }
+        }
+        Ok(())
+    }
 
-            BtSocketConnectState::Done => {
-                panic!("Trying advance `BtSocketConnect` from `Done` state");
+    pub fn send_file(&mut self, file: &File, len: usize) -> Result<usize, BtError> {
+        let mut sent = 0;
+        while sent < len {
+            let n = unsafe {
+                libc::sendfile(self.fd, file.as_raw_fd(), std::ptr::null_mut(), len - sent)
+            };
+            if n < 0 {
+                match nix::errno::Errno::last() {
+                    nix::errno::Errno::EINTR => continue,
+                    // `sendfile()` doesn't support this file or socket, copy through a buffer
+                    nix::errno::Errno::EINVAL | nix::errno::Errno::ENOSYS if sent == 0 => {
+                        let copied = std::io::copy(&mut Read::take(file, len as u64), self)?;
+                        return Ok(copied as usize);
+                    }
+                    _ => return Err(create_error_from_last("sendfile() failed")),
+                }
+            }
+            if n == 0 {
+                // End of file
+                break;
             }
+            self.counters.add_written(n as usize);
+            sent += n as usize;
         }
+        Ok(sent)
     }
-}
 
-impl<'a> mio::Evented for BtSocketConnect<'a> {
-    fn register(
-        &self,
-        poll: &Poll,
-        token: mio::Token,
-        interest: Ready,
-        opts: mio::PollOpt,
-    ) -> std::io::Result<()> {
-        EventedFd(&self.pollfd).register(poll, token, interest, opts)
+    pub fn set_cloexec(&self, cloexec: bool) -> Result<(), BtError> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(create_error_from_last("fcntl(F_GETFD) failed"));
+        }
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if unsafe { libc::fcntl(self.fd, libc::F_SETFD, flags) } < 0 {
+            return Err(create_error_from_last("fcntl(F_SETFD) failed"));
+        }
+        Ok(())
     }
 
-    fn reregister(
-        &self,
-        poll: &Poll,
-        token: mio::Token,
-        interest: Ready,
-        opts: mio::PollOpt,
-    ) -> std::io::Result<()> {
-        EventedFd(&self.pollfd).reregister(poll, token, interest, opts)
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), BtError> {
+        Ok(self.stream.shutdown(how)?)
     }
 
-    fn deregister(&self, poll: &Poll) -> std::io::Result<()> {
-        EventedFd(&self.pollfd).deregister(poll)
+    pub fn flow_info(&self) -> Result<FlowInfo, BtError> {
+        let queued = |request, message| {
+            let mut bytes: libc::c_int = 0;
+            if unsafe { libc::ioctl(self.fd, request, &mut bytes) } < 0 {
+                Err(create_error_from_last(message))
+            } else {
+                Ok(bytes as usize)
+            }
+        };
+        let unsent = queued(libc::TIOCOUTQ, "ioctl(TIOCOUTQ) failed")?;
+        let unread = queued(libc::FIONREAD, "ioctl(FIONREAD) failed")?;
+
+        let credits = match self.protocol {
+            BtProtocol::Rfcomm { .. } => self.rfcomm_credits(),
+            BtProtocol::L2cap { .. } => None,
+        };
+        Ok(FlowInfo {
+            unsent,
+            unread,
+            rx_credits: credits.map(|(rx, _)| rx),
+            tx_credits: credits.map(|(_, tx)| tx),
+        })
+    }
+
+    pub fn peer_class(&self) -> Result<DeviceClass, BtError> {
+        hci::cached_class(self.sys, self.peer_addr()?.addr)
+    }
+
+    pub fn local_addr(&self) -> Result<BtSocketAddr, BtError> {
+        self.socket_addr(libc::getsockname, "getsockname() failed")
+    }
+
+    pub fn peer_addr(&self) -> Result<BtSocketAddr, BtError> {
+        if let Some(peer) = self.peer {
+            return Ok(peer);
+        }
+        self.socket_addr(libc::getpeername, "getpeername() failed")
+    }
+
+    fn socket_addr(
+        &self,
+        get: unsafe extern "C" fn(RawFd, *mut libc::sockaddr, *mut libc::socklen_t) -> libc::c_int,
+        message: &str,
+    ) -> Result<BtSocketAddr, BtError> {
+        let mut full_address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut socklen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe {
+            get(
+                self.fd,
+                &mut full_address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut socklen,
+            )
+        } < 0
+        {
+            return Err(create_error_from_last(message));
+        }
+
+        Ok(match self.protocol {
+            BtProtocol::Rfcomm { .. } => {
+                let full_address = unsafe {
+                    *(&full_address as *const libc::sockaddr_storage as *const sockaddr_rc)
+                };
+                BtSocketAddr::new(
+                    full_address.rc_bdaddr.convert_host_byteorder(),
+                    Some(full_address.rc_channel),
+                )
+            }
+            BtProtocol::L2cap { .. } => {
+                let full_address = unsafe {
+                    *(&full_address as *const libc::sockaddr_storage as *const sockaddr_l2)
+                };
+                BtSocketAddr::new(full_address.l2_bdaddr.convert_host_byteorder(), None)
+            }
+        })
+    }
+
+    /// Look up the RFCOMM credits of the connection in debugfs, which is only readable for root
+    fn rfcomm_credits(&self) -> Option<(u8, u32)> {
+        let local = self.local_addr().ok()?;
+        let peer = self.peer_addr().ok()?;
+
+        let dlcs = std::fs::read_to_string("/sys/kernel/debug/bluetooth/rfcomm_dlc").ok()?;
+        dlc_credits(&dlcs, local.addr, peer.addr, peer.channel?)
+    }
+
+    /// Wait until the kernel has transmitted all queued outgoing data, the `deadline` expires or
+    /// the wait is interrupted through the eventfd
+    fn drain(&self, deadline: Option<Instant>) -> Result<(), BtError> {
+        loop {
+            let mut pending: libc::c_int = 0;
+            if unsafe { libc::ioctl(self.fd, libc::TIOCOUTQ, &mut pending) } < 0 {
+                return Err(create_error_from_last("ioctl(TIOCOUTQ) failed"));
+            }
+            if pending == 0 {
+                return Ok(());
+            }
+
+            let mut timeout = DRAIN_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(create_error_from_errno(
+                        "Transmitting the queued data timed out",
+                        libc::ETIMEDOUT,
+                    ));
+                }
+                timeout = timeout.min(remaining);
+            }
+
+            // The queue length can't be polled, so only an interrupt ends the wait early. poll()
+            // ignores the negative fd if there is no eventfd.
+            let interrupt = self.interrupt.get();
+            let mut pollfd = libc::pollfd {
+                fd: interrupt.map_or(-1, |interrupt| interrupt.as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout_ms = timeout.as_nanos().div_ceil(1_000_000) as libc::c_int;
+            if unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } < 0
+                && nix::errno::Errno::last() != nix::errno::Errno::EINTR
+            {
+                return Err(create_error_from_last("poll() failed"));
+            }
+            if let Some(interrupt) = interrupt.filter(|_| pollfd.revents & libc::POLLIN != 0) {
+                reset_interrupt(interrupt);
+                return Err(BtError::Interrupted);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<(), BtError> {
+        self.drain(None)?;
+        self.shutdown(Shutdown::Write)
+    }
+
+    pub fn finish_timeout(self, timeout: Duration) -> Result<(), BtError> {
+        self.drain(Some(Instant::now() + timeout))?;
+        self.shutdown(Shutdown::Write)
+    }
+
+    pub fn interrupt_handle(&self) -> Result<BtInterruptHandle, BtError> {
+        if let Some(eventfd) = self.interrupt.get() {
+            return Ok(BtInterruptHandle(eventfd.clone()));
+        }
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd < 0 {
+            return Err(create_error_from_last("eventfd() failed"));
+        }
+        let eventfd = Arc::new(unsafe { OwnedFd::from_raw_fd(eventfd) });
+        // Another thread may have won the race, use its eventfd then
+        Ok(BtInterruptHandle(
+            self.interrupt.get_or_init(|| eventfd).clone(),
+        ))
+    }
+
+    /// Wait until the socket is readable, unless the read is interrupted through the eventfd.
+    /// Non-blocking reads don't wait, they report `WouldBlock` as usual.
+    fn wait_readable(&self) -> std::io::Result<()> {
+        let interrupt = match self.interrupt.get() {
+            Some(interrupt) => interrupt,
+            None => return Ok(()),
+        };
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if flags & libc::O_NONBLOCK != 0 {
+            return Ok(());
+        }
+
+        let mut pollfds = [
+            libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: interrupt.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        while unsafe { libc::poll(pollfds.as_mut_ptr(), 2, -1) } < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+
+        if pollfds[1].revents & libc::POLLIN != 0 {
+            reset_interrupt(interrupt);
+            return Err(std::io::Error::other(BtError::Interrupted));
+        }
+        Ok(())
+    }
+
+    pub fn take_error(&self) -> Result<Option<BtError>, BtError> {
+        match getsockopt(self.fd, sockopt::SocketError)? {
+            0 => Ok(None),
+            errno => Ok(Some(create_error_from_errno("Socket error", errno))),
+        }
+    }
+}
+
+/// Reset the counter of the interrupt eventfd, so only the current blocking call is interrupted
+fn reset_interrupt(interrupt: &OwnedFd) {
+    let mut counter = 0u64;
+    unsafe {
+        libc::read(
+            interrupt.as_raw_fd(),
+            &mut counter as *mut u64 as *mut libc::c_void,
+            mem::size_of::<u64>(),
+        )
+    };
+}
+
+/// Find the RX and TX credits of the DLC to `channel` between `src` and `dst` in the content of
+/// the `rfcomm_dlc` debugfs file, whose lines are `<src> <dst> <state> <dlci> <mtu> <rx_credits>
+/// <tx_credits>`. A `src` of `00:00:00:00:00:00` (a socket bound to any adapter) matches any
+/// source.
+fn dlc_credits(dlcs: &str, src: BtAddr, dst: BtAddr, channel: u8) -> Option<(u8, u32)> {
+    dlcs.lines().find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [dlc_src, dlc_dst, _, dlci, _, rx_credits, tx_credits] => {
+                // The lowest bit of the DLCI is the direction, the rest the server channel
+                if (src == BtAddr::any() || dlc_src.parse() == Ok(src))
+                    && dlc_dst.parse() == Ok(dst)
+                    && dlci.parse::<u8>().ok()? >> 1 == channel
+                {
+                    Some((rx_credits.parse().ok()?, tx_credits.parse().ok()?))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Interrupts blocking reads of a `BtSocket` from other threads
+#[derive(Debug, Clone)]
+pub struct BtInterruptHandle(Arc<OwnedFd>);
+
+impl BtInterruptHandle {
+    pub fn interrupt(&self) -> Result<(), BtError> {
+        let increment = 1u64;
+        if unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &increment as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        } < 0
+        {
+            return Err(create_error_from_last("Writing to eventfd failed"));
+        }
+        Ok(())
+    }
+}
+
+/// A connected socket registered with the async-io reactor, for use in hand-written futures
+#[derive(Debug)]
+pub struct BtSocketPollable {
+    stream: Async<StdUnixStream>,
+}
+
+impl BtSocketPollable {
+    pub fn new(socket: BtSocket) -> Result<BtSocketPollable, BtError> {
+        Ok(BtSocketPollable {
+            stream: Async::new(socket.stream)?,
+        })
+    }
+
+    pub fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> task::Poll<std::io::Result<usize>> {
+        loop {
+            match self.stream.get_ref().read(buf) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    ready!(self.stream.poll_readable(cx))?
+                }
+                result => return task::Poll::Ready(result),
+            }
+        }
+    }
+
+    pub fn poll_write(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<std::io::Result<usize>> {
+        loop {
+            match self.stream.get_ref().write(buf) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    ready!(self.stream.poll_writable(cx))?
+                }
+                result => return task::Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl From<nix::Error> for BtError {
+    fn from(e: nix::Error) -> BtError {
+        BtError::Errno(e.as_errno().map(|x| x as u32).unwrap_or(0), e.to_string())
+    }
+}
+
+#[cfg(feature = "mio")]
+impl mio::Evented for BtSocket {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: mio::Token,
+        interest: Ready,
+        opts: mio::PollOpt,
+    ) -> std::io::Result<()> {
+        EventedFd(&self.get_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: mio::Token,
+        interest: Ready,
+        opts: mio::PollOpt,
+    ) -> std::io::Result<()> {
+        EventedFd(&self.get_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> std::io::Result<()> {
+        EventedFd(&self.get_fd()).deregister(poll)
+    }
+}
+
+impl BtSocket {
+    pub fn read_frame<F: Framer + ?Sized>(&mut self, framer: &mut F) -> Result<Vec<u8>, BtError> {
+        let mut buf = mem::take(&mut self.frame_buf);
+        loop {
+            // Keep the data received so far if the framer fails
+            match framer.frame_len(&buf) {
+                Ok(Some(len)) if len <= buf.len() => {
+                    self.frame_buf = buf.split_off(len);
+                    return Ok(buf);
+                }
+                Ok(Some(_)) => {
+                    self.frame_buf = buf;
+                    return Err(BtError::Desc(
+                        "Framer reported a frame beyond the data".to_string(),
+                    ));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.frame_buf = buf;
+                    return Err(e);
+                }
+            }
+
+            let start = buf.len();
+            buf.resize(start + 1024, 0);
+            let result = self.read(&mut buf[start..]);
+            buf.truncate(start + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => {
+                    self.frame_buf = buf;
+                    return Err(BtError::Desc(
+                        "Connection closed before a complete frame was received".to_string(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.frame_buf = buf;
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: BtLineMode) {
+        self.mode = mode;
+        self.after_cr = false;
+    }
+
+    /// Read from the socket without any line ending translation
+    fn read_raw(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.wait_readable()?;
+        let result = match self.protocol {
+            BtProtocol::Rfcomm { .. } => self.stream.read(buf),
+            // L2CAP sockets are packet based, read exactly one packet and don't cut it silently
+            BtProtocol::L2cap { .. } => {
+                let len = unsafe {
+                    libc::recv(
+                        self.fd,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        libc::MSG_TRUNC,
+                    )
+                };
+                if len < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let len = len as usize;
+                if len > buf.len() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "L2CAP packet of {} bytes truncated to the buffer size of {} bytes",
+                            len,
+                            buf.len()
+                        ),
+                    ));
+                }
+                Ok(len)
+            }
+        };
+        result.inspect(|&len| self.counters.add_read(len))
+    }
+}
+
+/// Translate the line endings of `buf` from CR LF and CR to LF in place, returning the new length
+fn cook_line_endings(buf: &mut [u8], after_cr: &mut bool) -> usize {
+    let mut len = 0;
+    for i in 0..buf.len() {
+        let byte = buf[i];
+        // The CR may have ended the previous read already
+        if mem::replace(after_cr, byte == b'\r') && byte == b'\n' {
+            continue;
+        }
+        buf[len] = if byte == b'\r' { b'\n' } else { byte };
+        len += 1;
+    }
+    len
+}
+
+impl Read for BtSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Hand out what `read_frame` received beyond its last frame first
+        if !self.frame_buf.is_empty() {
+            let len = buf.len().min(self.frame_buf.len());
+            buf[..len].copy_from_slice(&self.frame_buf[..len]);
+            self.frame_buf.drain(..len);
+            return Ok(len);
+        }
+
+        loop {
+            let len = self.read_raw(buf)?;
+            match self.mode {
+                BtLineMode::Raw => return Ok(len),
+                BtLineMode::Cooked => {
+                    let cooked = cook_line_endings(&mut buf[..len], &mut self.after_cr);
+                    // Only dropping the LF of a CR LF must not look like the end of the stream
+                    if cooked > 0 || len == 0 {
+                        return Ok(cooked);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Write for BtSocket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream
+            .write(buf)
+            .inspect(|&len| self.counters.add_written(len))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A duplicate of a socket's descriptor that counts the transferred bytes with the socket
+#[derive(Debug)]
+pub struct BtStreamHalf {
+    stream: StdUnixStream,
+    counters: Arc<IoCounters>,
+}
+
+impl BtStreamHalf {
+    pub fn get_ref(&self) -> &StdUnixStream {
+        &self.stream
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.counters.read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.counters.written.load(Ordering::Relaxed)
+    }
+}
+
+impl Read for BtStreamHalf {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream
+            .read(buf)
+            .inspect(|&len| self.counters.add_read(len))
+    }
+}
+
+impl Write for BtStreamHalf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream
+            .write(buf)
+            .inspect(|&len| self.counters.add_written(len))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Condition the connection process has to wait for before it can be advanced again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectWait {
+    Readable,
+    Writable,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BtSocketConnectState {
+    SDPSearch,
+    /// The channel is known from an earlier SDP search
+    Cached(u8),
+    /// The channel was given for this connect, see `connect_channel`
+    Channel(u8),
+    Direct,
+    Connect,
+    Done,
+}
+
+/// Manages the bluetooth connection process when used from an asynchronous client.
+#[derive(Debug)]
+pub struct BtSocketConnect<'a> {
+    addr: BtAddr,
+    /// Service class the SDP search looks for, the key of the channel cache
+    uuid: Uuid,
+    pollfd: RawFd,
+    state: BtSocketConnectState,
+    socket: &'a mut BtSocket,
+    query: Box<dyn SdpQuery>,
+    phase_start: Instant,
+    metrics: ConnectMetrics,
+    /// RFCOMM channel to connect to if the SDP search fails
+    fallback_channel: Option<u8>,
+    /// RFCOMM channel `connect()` was issued for
+    channel: Option<u8>,
+}
+impl<'a> BtSocketConnect<'a> {
+    fn new(socket: &'a mut BtSocket, addr: BtAddr, uuid: Uuid) -> Self {
+        let state = match socket.protocol {
+            BtProtocol::Rfcomm { channel: None } => {
+                let cache = socket.sys.channel_cache().lock().unwrap();
+                match cache.get(addr, uuid) {
+                    Some(channel) => BtSocketConnectState::Cached(channel),
+                    None => BtSocketConnectState::SDPSearch,
+                }
+            }
+            _ => BtSocketConnectState::Direct,
+        };
+
+        BtSocketConnect {
+            addr,
+            uuid,
+            pollfd: 0,
+            query: socket.sys.sdp_query(addr, uuid),
+            socket,
+            state,
+            phase_start: Instant::now(),
+            metrics: ConnectMetrics::default(),
+            fallback_channel: None,
+            channel: None,
+        }
+    }
+
+    pub fn metrics(&self) -> ConnectMetrics {
+        self.metrics
+    }
+
+    /// Issue the non-blocking `connect()` call for the given socket address
+    fn start_connect<T>(&mut self, full_address: &T) -> Result<ConnectWait, BtError> {
+        self.pollfd = self.socket.get_fd();
+        self.phase_start = Instant::now();
+        match self.socket.sys.connect(
+            self.pollfd,
+            full_address as *const T as *const libc::sockaddr,
+            mem::size_of::<T>() as libc::socklen_t,
+        ) {
+            Err(errno) => Err(create_error_from_errno(
+                "Failed to connect() to target device",
+                errno,
+            )),
+            Ok(()) => {
+                bt_log!(debug, "{:?}: connect() issued", self.addr);
+                self.state = BtSocketConnectState::Connect;
+                Ok(ConnectWait::Writable)
+            }
+        }
+    }
+
+    fn start_connect_rfcomm(&mut self, channel: u8) -> Result<ConnectWait, BtError> {
+        self.channel = Some(channel);
+        let full_address = sockaddr_rc {
+            rc_family: AF_BLUETOOTH as u16,
+            rc_bdaddr: self.addr,
+            rc_channel: channel,
+        };
+        self.start_connect(&full_address)
+    }
+
+    /// Advance the connection process to the next state
+    #[cfg(feature = "mio")]
+    pub fn advance(&mut self) -> Result<BtAsync<'_>, BtError> {
+        Ok(match self.step()? {
+            ConnectWait::Readable => BtAsync::WaitFor(self, Ready::readable()),
+            ConnectWait::Writable => BtAsync::WaitFor(self, Ready::writable()),
+            ConnectWait::Done => BtAsync::Done,
+        })
+    }
+
+    /// Drive the connection process to completion, waiting for `pollfd` with `poll()`. The SDP
+    /// search fails after `SDP_TIMEOUT`.
+    pub fn complete(mut self) -> Result<ConnectMetrics, BtError> {
+        self.complete_until(None)
+    }
+
+    /// Like `complete`, but fail with `ETIMEDOUT` if the connection isn't established within
+    /// `timeout`. A pending `connect()` is aborted by renewing the socket.
+    pub fn complete_within(mut self, timeout: Duration) -> Result<ConnectMetrics, BtError> {
+        self.complete_until(Some(Instant::now() + timeout))
+    }
+
+    fn complete_until(&mut self, deadline: Option<Instant>) -> Result<ConnectMetrics, BtError> {
+        loop {
+            let events = match self.step()? {
+                ConnectWait::Readable => libc::POLLIN,
+                ConnectWait::Writable => libc::POLLOUT,
+                ConnectWait::Done => return Ok(self.metrics),
+            };
+
+            let mut pollfd = libc::pollfd {
+                fd: self.pollfd,
+                events,
+                revents: 0,
+            };
+            loop {
+                match unsafe { libc::poll(&mut pollfd, 1, self.poll_timeout_ms(deadline)) } {
+                    0 if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                        return Err(self.timed_out());
+                    }
+                    0 if matches!(self.state, BtSocketConnectState::SDPSearch) => {
+                        self.sdp_timed_out()?;
+                        pollfd = libc::pollfd {
+                            fd: self.pollfd,
+                            events: libc::POLLOUT,
+                            revents: 0,
+                        };
+                    }
+                    // Only the SDP search has a timeout of its own, keep waiting otherwise
+                    0 => {}
+                    n if n > 0 => break,
+                    _ if nix::errno::Errno::last() == nix::errno::Errno::EINTR => {}
+                    _ => return Err(create_error_from_last("poll() failed")),
+                }
+            }
+        }
+    }
+
+    /// Time `complete_until` may wait for `pollfd`, until the SDP search or the `deadline`
+    /// expires. -1 if neither applies.
+    fn poll_timeout_ms(&self, deadline: Option<Instant>) -> libc::c_int {
+        let sdp_deadline = match self.state {
+            BtSocketConnectState::SDPSearch => Some(self.phase_start + SDP_TIMEOUT),
+            _ => None,
+        };
+        match sdp_deadline.into_iter().chain(deadline).min() {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // Round up, so the timeout doesn't expire early
+                let remaining_ms = remaining.as_nanos().div_ceil(1_000_000);
+                remaining_ms.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        }
+    }
+
+    /// Give up on the connection after the deadline of `complete_within` expired
+    fn timed_out(&mut self) -> BtError {
+        bt_log!(
+            debug,
+            "{:?}: connect timed out in {:?}",
+            self.addr,
+            self.state
+        );
+        // Closing the socket is the only way to abort the connection attempt
+        if matches!(self.state, BtSocketConnectState::Connect) {
+            if let Err(e) = self.socket.renew() {
+                return e;
+            }
+        }
+        create_error_from_errno("Connection timed out", libc::ETIMEDOUT)
+    }
+
+    /// Like `complete`, but waits for `pollfd` with the `async-io` reactor
+    async fn complete_async(&mut self) -> Result<ConnectMetrics, BtError> {
+        let mut wait = self.step()?;
+        loop {
+            if wait == ConnectWait::Done {
+                return Ok(self.metrics);
+            }
+            let fd = Async::new_nonblocking(unsafe { BorrowedFd::borrow_raw(self.pollfd) })?;
+            let mut timer = match self.state {
+                BtSocketConnectState::SDPSearch => Timer::at(self.phase_start + SDP_TIMEOUT),
+                _ => Timer::never(),
+            };
+            let ready = std::future::poll_fn(|cx| {
+                let ready = match wait {
+                    ConnectWait::Readable => fd.poll_readable(cx),
+                    _ => fd.poll_writable(cx),
+                };
+                match ready {
+                    task::Poll::Ready(result) => task::Poll::Ready(result.map(|()| true)),
+                    task::Poll::Pending => Pin::new(&mut timer).poll(cx).map(|_| Ok(false)),
+                }
+            })
+            .await?;
+            // Deregister before the next step, which may close the descriptor
+            drop(fd);
+
+            wait = if ready {
+                self.step()?
+            } else {
+                self.sdp_timed_out()?
+            };
+        }
+    }
+
+    /// Give up on the SDP search and connect to the fallback channel, if there is one
+    fn sdp_timed_out(&mut self) -> Result<ConnectWait, BtError> {
+        bt_log!(debug, "{:?}: SDP search timed out", self.addr);
+        let channel = self.fallback_channel.ok_or_else(|| {
+            create_error_from_errno("SDP search", libc::ETIMEDOUT).context("SDP search timed out")
+        })?;
+        self.metrics.sdp_duration = self.phase_start.elapsed();
+        self.start_connect_rfcomm(channel)
+    }
+
+    fn step(&mut self) -> Result<ConnectWait, BtError> {
+        let addr = self.addr;
+        let state = self.state;
+        bt_span!("bt_connect", addr = ?addr, state = ?state);
+        bt_log!(trace, "{:?}: advancing connect from {:?}", addr, state);
+        self.advance_state().map_err(|e| {
+            bt_log!(debug, "{:?}: connect failed in {:?}: {:?}", addr, state, e);
+            e
+        })
+    }
+
+    fn advance_state(&mut self) -> Result<ConnectWait, BtError> {
+        match self.state {
+            BtSocketConnectState::SDPSearch => {
+                let (status, found) = match (self.query.advance(), self.fallback_channel) {
+                    (Err(e), Some(channel)) => {
+                        bt_log!(
+                            debug,
+                            "{:?}: SDP search failed ({:?}), falling back to channel {}",
+                            self.addr,
+                            e,
+                            channel
+                        );
+                        (QueryRFCOMMChannelStatus::Done(channel), false)
+                    }
+                    (status, _) => (status?, true),
+                };
+                match status {
+                    // Forward SDP's pleas for another round
+                    QueryRFCOMMChannelStatus::WaitReadable(fd) => {
+                        self.pollfd = fd;
+                        Ok(ConnectWait::Readable)
+                    }
+
+                    QueryRFCOMMChannelStatus::WaitWritable(fd) => {
+                        self.pollfd = fd;
+                        Ok(ConnectWait::Writable)
+                    }
+
+                    // Received channel number, start actual connection
+                    QueryRFCOMMChannelStatus::Done(channel) => {
+                        bt_log!(
+                            debug,
+                            "{:?}: SDP found RFCOMM channel {}",
+                            self.addr,
+                            channel
+                        );
+                        self.metrics.sdp_duration = self.phase_start.elapsed();
+                        if found {
+                            let mut cache = self.socket.sys.channel_cache().lock().unwrap();
+                            cache.insert(self.addr, self.uuid, channel);
+                        }
+                        self.start_connect_rfcomm(channel)
+                    }
+                }
+            }
+
+            BtSocketConnectState::Channel(channel) => self.start_connect_rfcomm(channel),
+
+            BtSocketConnectState::Cached(channel) => {
+                bt_log!(
+                    debug,
+                    "{:?}: using cached RFCOMM channel {}",
+                    self.addr,
+                    channel
+                );
+                self.start_connect_rfcomm(channel)
+            }
+
+            // Connection parameters are already known, connect without SDP
+            BtSocketConnectState::Direct => match self.socket.protocol {
+                BtProtocol::Rfcomm { channel } => {
+                    self.start_connect_rfcomm(check_rfcomm_channel(channel.unwrap_or(0))?)
+                }
+                BtProtocol::L2cap { psm } => {
+                    let full_address = sockaddr_l2 {
+                        l2_family: AF_BLUETOOTH as u16,
+                        l2_psm: psm.to_le(),
+                        l2_bdaddr: self.addr,
+                        l2_cid: 0,
+                        l2_bdaddr_type: bdaddr_type(self.socket.transport),
+                    };
+                    self.start_connect(&full_address)
+                }
+            },
+
+            BtSocketConnectState::Connect => {
+                // First check if socket is actually connected using `getpeername()`
+                match self.socket.sys.getpeername(self.pollfd) {
+                    Err(libc::ENOTCONN) => {
+                        // Connection has failed – obtain actual error code using `read()`
+                        let mut buf = [0u8; 1];
+                        let errno = self.socket.sys.read(self.pollfd, &mut buf).unwrap_err();
+                        // The service may have moved to another channel
+                        let mut cache = self.socket.sys.channel_cache().lock().unwrap();
+                        if self.channel.is_some() && cache.get(self.addr, self.uuid) == self.channel
+                        {
+                            cache.remove(self.addr);
+                        }
+                        Err(create_error_from_errno(
+                            "Failed to connect() to target device",
+                            errno,
+                        ))
+                    }
+                    // Some unexpected error
+                    Err(errno) => Err(create_error_from_errno("getpeername() failed", errno)),
+                    Ok(()) => {
+                        bt_log!(debug, "{:?}: connected", self.addr);
+                        self.metrics.connect_duration = self.phase_start.elapsed();
+                        self.socket.last_peer = Some(BtSocketAddr::new(
+                            self.addr.convert_host_byteorder(),
+                            self.channel,
+                        ));
+                        self.state = BtSocketConnectState::Done;
+                        Ok(ConnectWait::Done)
+                    }
+                }
+            }
+
+            BtSocketConnectState::Done => {
+                panic!("Trying advance `BtSocketConnect` from `Done` state");
+            }
+        }
+    }
+}
+
+/// Aborts a pending `connect()` if the connection future is dropped before it completes
+struct AbortOnDrop<'a, 'b> {
+    connect: &'b mut BtSocketConnect<'a>,
+    armed: bool,
+}
+
+impl Drop for AbortOnDrop<'_, '_> {
+    fn drop(&mut self) {
+        // Closing the socket is the only way to abort the connection attempt
+        if self.armed && matches!(self.connect.state, BtSocketConnectState::Connect) {
+            let _ = self.connect.socket.renew();
+        }
+    }
+}
+
+#[cfg(feature = "mio")]
+impl<'a> mio::Evented for BtSocketConnect<'a> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: mio::Token,
+        interest: Ready,
+        opts: mio::PollOpt,
+    ) -> std::io::Result<()> {
+        EventedFd(&self.pollfd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: mio::Token,
+        interest: Ready,
+        opts: mio::PollOpt,
+    ) -> std::io::Result<()> {
+        EventedFd(&self.pollfd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> std::io::Result<()> {
+        EventedFd(&self.pollfd).deregister(poll)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::linux::sys::MockBt;
+
+    fn assert_waits_for(status: Result<ConnectWait, BtError>, wait: ConnectWait) {
+        match status.unwrap() {
+            ConnectWait::Done => panic!("Connection finished too early"),
+            status => assert_eq!(status, wait),
+        }
+    }
+
+    fn mock_socket(sys: MockBt, protocol: BtProtocol) -> BtSocket {
+        BtSocket::new_with(sys.install(), protocol).unwrap()
+    }
+
+    /// An RFCOMM socket adopting one end of a connected socket pair, and the other end
+    pub(crate) fn socket_pair() -> (BtSocket, StdUnixStream) {
+        use std::os::unix::io::IntoRawFd;
+
+        let (local, remote) = StdUnixStream::pair().unwrap();
+        let socket = unsafe { BtSocket::adopt(local.into_raw_fd(), BtProtocol::RFCOMM) };
+        (socket, remote)
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn sockaddr_rc_layout() {
+        let full_address = sockaddr_rc {
+            rc_family: AF_BLUETOOTH as u16,
+            rc_bdaddr: BtAddr([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]).convert_host_byteorder(),
+            rc_channel: 3,
+        };
+        assert_eq!(mem::size_of::<sockaddr_rc>(), 10);
+
+        // Same layout as `struct sockaddr_rc` of BlueZ, with the address stored reversed
+        let bytes: [u8; 9] = unsafe { *(&full_address as *const sockaddr_rc as *const [u8; 9]) };
+        assert_eq!(bytes, [31, 0, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 3]);
+    }
+
+    #[test]
+    fn connects_through_sdp_search() {
+        let sys = MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::RFCOMM);
+        let mut connect = socket.connect(BtAddr::any());
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+        assert_waits_for(connect.step(), ConnectWait::Readable);
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert!(matches!(connect.state, BtSocketConnectState::Connect));
+
+        assert!(matches!(connect.step().unwrap(), ConnectWait::Done));
+        assert!(matches!(connect.state, BtSocketConnectState::Done));
+    }
+
+    #[test]
+    fn reuses_cached_sdp_channel() {
+        let sys = MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        }
+        .install();
+        let mut socket = BtSocket::new_with(sys, BtProtocol::RFCOMM).unwrap();
+        let mut connect = socket.connect(BtAddr::any());
+        while !matches!(connect.step().unwrap(), ConnectWait::Done) {}
+
+        let mut socket = BtSocket::new_with(sys, BtProtocol::RFCOMM).unwrap();
+        let mut connect = socket.connect(BtAddr::any());
+        assert!(matches!(connect.state, BtSocketConnectState::Cached(3)));
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert!(matches!(connect.state, BtSocketConnectState::Connect));
+
+        // The channel of another profile is searched separately
+        let mut socket = BtSocket::new_with(sys, BtProtocol::RFCOMM).unwrap();
+        let connect = socket.connect_profile(BtAddr::any(), Profile::HandsFree);
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+
+        sys.channel_cache().lock().unwrap().set_ttl(None);
+        let mut socket = BtSocket::new_with(sys, BtProtocol::RFCOMM).unwrap();
+        let connect = socket.connect(BtAddr::any());
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+    }
+
+    #[test]
+    fn dropping_connect_future_releases_sdp_session() {
+        let mock: &'static MockBt = Box::leak(Box::new(MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        }));
+        let mut socket = BtSocket::new_with(mock, BtProtocol::RFCOMM).unwrap();
+        let fd = socket.fd;
+
+        let mut connect = Box::pin(socket.connect_future(BtAddr::any()));
+        // The future waits for the SDP response
+        async_io::block_on(std::future::poll_fn(|cx| {
+            assert!(connect.as_mut().poll(cx).is_pending());
+            task::Poll::Ready(())
+        }));
+        let mut peer = mock.sdp_peers.lock().unwrap().pop().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        assert_eq!(
+            peer.read(&mut [0]).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+
+        drop(connect);
+        assert_eq!(peer.read(&mut [0]).unwrap(), 0);
+        // No connect() was issued yet, the socket is kept
+        assert_eq!(socket.fd, fd);
+    }
+
+    #[test]
+    fn measures_connect_phases() {
+        let pause = Duration::from_millis(20);
+        let sys = MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::RFCOMM);
+        let mut connect = socket.connect(BtAddr::any());
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        std::thread::sleep(pause);
+        assert_waits_for(connect.step(), ConnectWait::Readable);
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert_eq!(connect.metrics().connect_duration, Default::default());
+        std::thread::sleep(pause);
+        assert!(matches!(connect.step().unwrap(), ConnectWait::Done));
+
+        let metrics = connect.metrics();
+        assert!(metrics.sdp_duration >= pause);
+        assert!(metrics.connect_duration >= pause);
+    }
+
+    #[test]
+    fn connects_directly_to_known_channel() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::Rfcomm { channel: Some(1) });
+        let mut connect = socket.connect(BtAddr::any());
+        assert!(matches!(connect.state, BtSocketConnectState::Direct));
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert!(matches!(connect.state, BtSocketConnectState::Connect));
+        assert!(matches!(connect.step().unwrap(), ConnectWait::Done));
+    }
+
+    #[test]
+    fn connect_times_out() {
+        let sys = MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::RFCOMM);
+        let fd = socket.fd;
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+
+        // The SDP response never arrives
+        match socket.connect(BtAddr::any()).complete_within(timeout) {
+            Err(BtError::Errno(errno, _)) => assert_eq!(errno, libc::ETIMEDOUT as u32),
+            result => panic!("Expected timeout, got {:?}", result),
+        }
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(socket.fd, fd);
+
+        socket
+            .connect_channel_timeout(BtAddr::any(), 1, timeout)
+            .unwrap();
+        assert!(socket
+            .connect_channel_timeout(BtAddr::any(), 31, timeout)
+            .is_err());
+    }
+
+    #[test]
+    fn reconnects_to_last_peer() {
+        let sys = MockBt {
+            sdp_channel: Some(3),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::RFCOMM);
+        assert!(socket.shutdown_and_reconnect().is_err());
+
+        let addr = BtAddr([1, 2, 3, 4, 5, 6]);
+        let mut connect = socket.connect(addr);
+        while !matches!(connect.step().unwrap(), ConnectWait::Done) {}
+        let fd = socket.fd;
+        socket.shutdown_and_reconnect().unwrap();
+        assert_ne!(socket.fd, fd);
+        assert_eq!(socket.last_peer, Some(BtSocketAddr::new(addr, Some(3))));
+        // The channel found by the first connect is used without changing the socket's protocol
+        assert_eq!(socket.protocol, BtProtocol::RFCOMM);
+    }
+
+    #[test]
+    fn renew_keeps_socket_options() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        let default_size = socket.recv_buffer_size().unwrap();
+        socket.set_recv_buffer_size(8192).unwrap();
+        socket.set_low_latency(true).unwrap();
+        socket.stream.set_nonblocking(true).unwrap();
+        socket.set_cloexec(false).unwrap();
+        let (recv_size, send_size) = (
+            socket.recv_buffer_size().unwrap(),
+            socket.send_buffer_size().unwrap(),
+        );
+        assert_ne!(recv_size, default_size);
+
+        socket.renew().unwrap();
+        assert_eq!(socket.recv_buffer_size().unwrap(), recv_size);
+        assert_eq!(socket.send_buffer_size().unwrap(), send_size);
+        assert!(socket.low_latency().unwrap());
+        assert!(socket.nonblocking().unwrap());
+        assert!(!socket.cloexec().unwrap());
+    }
+
+    #[test]
+    fn failed_renew_keeps_interrupt_handles() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        let handle = socket.interrupt_handle().unwrap();
+        socket.sys = MockBt {
+            socket_errno: Some(libc::EMFILE),
+            ..MockBt::default()
+        }
+        .install();
+
+        assert!(socket.renew().is_err());
+        handle.interrupt().unwrap();
+        let error = socket.read(&mut [0; 1]).unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref::<BtError>()),
+            Some(BtError::Interrupted)
+        ));
+    }
+
+    #[test]
+    fn reports_failed_sdp_search() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        let mut connect = socket.connect(BtAddr::any());
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert_waits_for(connect.step(), ConnectWait::Readable);
+        assert!(connect.step().is_err());
+        assert!(matches!(connect.state, BtSocketConnectState::SDPSearch));
+    }
+
+    #[test]
+    fn reports_failed_connect_call() {
+        let sys = MockBt {
+            connect_errno: Some(libc::EHOSTUNREACH),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::L2cap { psm: 0x1001 });
+        let mut connect = socket.connect(BtAddr::any());
+
+        match connect.step() {
+            Err(BtError::Errno(errno, _)) => assert_eq!(errno, libc::EHOSTUNREACH as u32),
+            _ => panic!("connect() error was not reported"),
+        }
+        assert!(matches!(connect.state, BtSocketConnectState::Direct));
+    }
+
+    #[test]
+    fn reports_refused_connection() {
+        let sys = MockBt {
+            peer_errno: Some(libc::ECONNREFUSED),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::Rfcomm { channel: Some(1) });
+        let mut connect = socket.connect(BtAddr::any());
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        match connect.step() {
+            Err(BtError::Errno(errno, _)) => assert_eq!(errno, libc::ECONNREFUSED as u32),
+            _ => panic!("Refused connection was not reported"),
+        }
+        assert!(matches!(connect.state, BtSocketConnectState::Connect));
+    }
+
+    #[test]
+    fn retries_refused_connection() {
+        let sys = MockBt {
+            peer_errno: Some(libc::ECONNREFUSED),
+            ..MockBt::default()
+        };
+        let mut socket = mock_socket(sys, BtProtocol::Rfcomm { channel: Some(1) });
+        let start = Instant::now();
+
+        match socket.connect_retry(BtAddr::any(), 3, Duration::from_millis(20)) {
+            Err(BtError::Errno(errno, _)) => assert_eq!(errno, libc::ECONNREFUSED as u32),
+            _ => panic!("Refused connection was not reported"),
+        }
+        // Two retries, each after waiting for the backoff
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    #[should_panic]
+    fn advancing_finished_connection_panics() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::Rfcomm { channel: Some(1) });
+        let mut connect = socket.connect(BtAddr::any());
+        connect.step().unwrap();
+        connect.step().unwrap();
+        let _ = connect.step();
+    }
+
+    #[test]
+    fn buffer_sizes_are_applied() {
+        let socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        socket.set_recv_buffer_size(32 * 1024).unwrap();
+        socket.set_send_buffer_size(32 * 1024).unwrap();
+        // The kernel at least doubles the requested value
+        assert!(socket.recv_buffer_size().unwrap() >= 32 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 32 * 1024);
+    }
+
+    #[test]
+    fn switches_low_latency() {
+        let socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        assert!(!socket.low_latency().unwrap());
+        socket.set_low_latency(true).unwrap();
+        assert!(socket.low_latency().unwrap());
+        socket.set_low_latency(false).unwrap();
+        assert!(!socket.low_latency().unwrap());
+    }
+
+    #[test]
+    fn no_pending_error_on_healthy_socket() {
+        let socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        assert!(socket.take_error().unwrap().is_none());
+    }
+
+    #[test]
+    fn falls_back_to_channel_if_sdp_fails() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        assert!(socket.connect_with_fallback(BtAddr::any(), 0).is_err());
+        let mut connect = socket.connect_with_fallback(BtAddr::any(), 1).unwrap();
+
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert_waits_for(connect.step(), ConnectWait::Readable);
+        assert_waits_for(connect.step(), ConnectWait::Writable);
+        assert!(matches!(connect.state, BtSocketConnectState::Connect));
+        assert!(matches!(connect.step().unwrap(), ConnectWait::Done));
+    }
+
+    #[test]
+    fn pollable_reads_and_writes() {
+        let (socket, mut remote) = socket_pair();
+        let pollable = BtSocketPollable::new(socket).unwrap();
+
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).unwrap();
+            remote.write_all(b"pong").unwrap();
+            buf
+        });
+
+        let written =
+            async_io::block_on(std::future::poll_fn(|cx| pollable.poll_write(cx, b"ping")));
+        assert_eq!(written.unwrap(), 4);
+        assert_eq!(&reader.join().unwrap(), b"ping");
+
+        let mut buf = [0u8; 4];
+        let read = async_io::block_on(std::future::poll_fn(|cx| pollable.poll_read(cx, &mut buf)));
+        assert_eq!(read.unwrap(), 4);
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn rfcomm_role_requires_rfcomm_socket() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::L2cap { psm: 1 });
+        assert!(socket.set_rfcomm_role(BtRole::Master).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_rfcomm_channels() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        assert!(socket.connect_channel(BtAddr::any(), 0).is_err());
+        assert!(socket.connect_channel(BtAddr::any(), 31).is_err());
+        assert!(socket.connect_channel(BtAddr::any(), 30).is_ok());
+        // The channel only applies to this connect
+        assert_eq!(socket.protocol, BtProtocol::RFCOMM);
+
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::Rfcomm { channel: Some(0) });
+        assert!(socket.connect(BtAddr::any()).step().is_err());
+    }
+
+    #[test]
+    fn write_all_timeout_reports_sent_bytes() {
+        let (mut socket, mut remote) = socket_pair();
+
+        socket
+            .write_all_timeout(b"ping", Duration::from_millis(100))
+            .unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Nobody reads on the remote end, so the send buffer fills up eventually
+        let data = vec![0u8; 16 * 1024 * 1024];
+        match socket.write_all_timeout(&data, Duration::from_millis(50)) {
+            Err(BtError::Timeout { transferred }) => assert!(transferred < data.len()),
+            result => panic!("Expected timeout, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rfcomm_is_not_available_over_le() {
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::RFCOMM);
+        assert!(socket.set_transport(BtTransport::LePublic).is_err());
+        assert!(socket.set_transport(BtTransport::BrEdr).is_ok());
+
+        let mut socket = mock_socket(MockBt::default(), BtProtocol::L2cap { psm: 0x80 });
+        assert!(socket.set_transport(BtTransport::LeRandom).is_ok());
+    }
+
+    #[test]
+    fn send_file_sends_up_to_len_bytes() {
+        let mut file = unsafe {
+            File::from_raw_fd(libc::memfd_create(
+                b"firmware\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            ))
+        };
+        file.write_all(b"firmware image").unwrap();
+
+        let (mut socket, mut remote) = socket_pair();
+
+        // Sends from the current position of the file
+        nix::unistd::lseek(file.as_raw_fd(), 0, nix::unistd::Whence::SeekSet).unwrap();
+        assert_eq!(socket.send_file(&file, 8).unwrap(), 8);
+        assert_eq!(socket.send_file(&file, 100).unwrap(), 6);
+        drop(socket);
+
+        let mut data = Vec::new();
+        remote.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"firmware image");
+    }
+
+    #[test]
+    fn interrupts_blocking_read() {
+        let (mut socket, mut remote) = socket_pair();
+        let handle = socket.interrupt_handle().unwrap();
+
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0u8; 4];
+            let error = socket.read(&mut buf).unwrap_err();
+            // Only the interrupted read fails
+            remote.write_all(b"data").unwrap();
+            (error, socket.read(&mut buf).unwrap())
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        handle.interrupt().unwrap();
+
+        let (error, read) = reader.join().unwrap();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref::<BtError>()),
+            Some(BtError::Interrupted)
+        ));
+        assert_eq!(read, 4);
+
+        // Non-blocking reads don't wait for the socket to become readable
+        let (mut socket, _remote) = socket_pair();
+        socket.interrupt_handle().unwrap();
+        socket.get_stream_std().set_nonblocking(true).unwrap();
+        assert_eq!(
+            socket.read(&mut [0; 4]).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn counts_transferred_bytes() {
+        let (mut socket, mut remote) = socket_pair();
+        socket.set_mode(BtLineMode::Cooked);
+        socket.write_all(b"AT\r\n").unwrap();
+        socket
+            .write_all_timeout(b"ATZ\r\n", Duration::from_secs(1))
+            .unwrap();
+        remote.write_all(b"OK\r\n").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(socket.read(&mut buf).unwrap(), 3);
+
+        // The bytes as received count, not the translated line endings
+        assert_eq!(socket.bytes_read(), 4);
+        assert_eq!(socket.bytes_written(), 9);
+        let mut request = [0; 9];
+        remote.read_exact(&mut request).unwrap();
+    }
+
+    #[test]
+    fn detects_dead_link() {
+        let (socket, mut remote) = socket_pair();
+        assert!(socket.is_alive().unwrap());
+
+        // Pending data doesn't count as a hang-up
+        remote.write_all(b"OK").unwrap();
+        assert!(socket.is_alive().unwrap());
+        drop(remote);
+        assert!(!socket.is_alive().unwrap());
+    }
+
+    #[test]
+    fn cooked_mode_translates_line_endings() {
+        let (mut socket, mut remote) = socket_pair();
+        socket.set_mode(BtLineMode::Cooked);
+
+        // The CR LF is split across two reads
+        remote.write_all(b"OK\r").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(socket.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"OK\n");
+
+        remote.write_all(b"\n").unwrap();
+        remote.write_all(b"A\rB\r\n").unwrap();
+        drop(remote);
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"A\nB\n");
+    }
+
+    #[test]
+    fn finds_dlc_credits() {
+        let dlcs = "00:1a:7d:da:71:13 00:11:22:33:44:55 1 2 127 7 0\n\
+                    00:1a:7d:da:71:13 00:11:22:33:44:55 1 6 127 5 3\n";
+        let src = "00:1A:7D:DA:71:13".parse().unwrap();
+        let dst = "00:11:22:33:44:55".parse().unwrap();
+
+        assert_eq!(dlc_credits(dlcs, src, dst, 3), Some((5, 3)));
+        assert_eq!(dlc_credits(dlcs, src, dst, 1), Some((7, 0)));
+        assert_eq!(dlc_credits(dlcs, src, dst, 2), None);
+        assert_eq!(dlc_credits(dlcs, BtAddr::any(), dst, 3), Some((5, 3)));
+        assert_eq!(dlc_credits(dlcs, dst, dst, 3), None);
+
+        let dlcs = "00:1a:7d:da:71:13 00:11:22:33:44:55 1 6 127 5 300\n";
+        assert_eq!(dlc_credits(dlcs, src, dst, 3), Some((5, 300)));
+    }
+
+    #[test]
+    fn reads_frames() {
+        use crate::framing::{Delimiter, LengthPrefixed};
+        let (mut socket, mut remote) = socket_pair();
+
+        remote.write_all(b"\x00\x03abcOK\r\nrest").unwrap();
+        drop(remote);
+        assert_eq!(
+            socket.read_frame(&mut LengthPrefixed::new(2)).unwrap(),
+            b"\x00\x03abc"
+        );
+        assert_eq!(
+            socket.read_frame(&mut Delimiter::new(b"\r\n")).unwrap(),
+            b"OK\r\n"
+        );
+        assert!(socket.read_frame(&mut Delimiter::new(b"\r\n")).is_err());
+
+        // Plain reads get the data received beyond the frames
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"rest");
+    }
+
+    #[test]
+    fn keeps_data_of_broken_framer() {
+        /// Claims a frame longer than the data once some data was received
+        struct Broken;
+        impl Framer for Broken {
+            fn frame_len(&mut self, buf: &[u8]) -> Result<Option<usize>, BtError> {
+                Ok(Some(buf.len() + 1).filter(|_| !buf.is_empty()))
+            }
+        }
+
+        let (mut socket, mut remote) = socket_pair();
+        remote.write_all(b"data").unwrap();
+        drop(remote);
+        assert!(matches!(
+            socket.read_frame(&mut Broken),
+            Err(BtError::Desc(ref message)) if message == "Framer reported a frame beyond the data"
+        ));
+
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+    }
+
+    #[test]
+    fn finish_delivers_data_and_eof() {
+        let (mut socket, mut remote) = socket_pair();
+        socket.write_all(b"bye").unwrap();
+
+        let reader = std::thread::spawn(move || {
+            let mut data = Vec::new();
+            remote.read_to_end(&mut data).unwrap();
+            data
+        });
+        socket.finish().unwrap();
+        assert_eq!(reader.join().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn finish_gives_up_on_stalled_peer() {
+        // A peer that never reads
+        let stalled = || {
+            let (mut socket, remote) = socket_pair();
+            socket.write_all(b"stuck").unwrap();
+            (socket, remote)
+        };
+
+        let (socket, _remote) = stalled();
+        let start = Instant::now();
+        match socket.finish_timeout(Duration::from_millis(50)) {
+            Err(BtError::Errno(errno, _)) => assert_eq!(errno, libc::ETIMEDOUT as u32),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let (socket, _remote) = stalled();
+        let handle = socket.interrupt_handle().unwrap();
+        let finisher = std::thread::spawn(move || socket.finish());
+        std::thread::sleep(Duration::from_millis(50));
+        handle.interrupt().unwrap();
+        assert!(matches!(
+            finisher.join().unwrap(),
+            Err(BtError::Interrupted)
+        ));
+    }
+
+    #[test]
+    fn from_fd_rejects_other_sockets() {
+        use std::os::unix::io::IntoRawFd;
+
+        let (local, _remote) = StdUnixStream::pair().unwrap();
+        let fd = local.into_raw_fd();
+        assert!(BtSocket::from_fd(fd).is_err());
+        assert!(BtSocket::from_fd(-1).is_err());
+        assert!(BtSocket::from_connected_fd(fd, BtAddr::any(), 1).is_err());
+        assert!(BtSocket::from_connected_fd(fd, BtAddr::any(), 31).is_err());
+        unsafe { libc::close(fd) };
+    }
+
+    /// Whether a child process inherits `fd`
+    fn inherited(fd: RawFd) -> bool {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("test -e /proc/self/fd/{}", fd))
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[cfg(not(feature = "test_without_hardware"))]
+    #[test]
+    fn socket_is_not_inherited_by_default() {
+        let socket = BtSocket::new(BtProtocol::RFCOMM).unwrap();
+        assert!(!inherited(socket.fd));
+    }
+
+    #[test]
+    fn cloexec_controls_inheritance() {
+        let (socket, _remote) = socket_pair();
+        socket.set_cloexec(false).unwrap();
+        assert!(inherited(socket.fd));
+        socket.set_cloexec(true).unwrap();
+        assert!(!inherited(socket.fd));
+    }
+
+    #[test]
+    fn l2cap_reads_preserve_packets() {
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) },
+            0
+        );
+        let mut local = unsafe { BtSocket::adopt(fds[0], BtProtocol::L2cap { psm: 0x1001 }) };
+        let mut remote = unsafe { BtSocket::adopt(fds[1], BtProtocol::L2cap { psm: 0x1001 }) };
+
+        remote.write_all(b"first").unwrap();
+        remote.write_all(b"second").unwrap();
+        remote.write_all(b"too long").unwrap();
+
+        let mut buf = [0u8; 6];
+        assert_eq!(local.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(local.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf, b"second");
+        let error = local.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
     }
 }
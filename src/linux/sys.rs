@@ -0,0 +1,541 @@
+//! Indirection over the system calls used while connecting and scanning, so the connection
+//! state machine and the device scan can be exercised without Bluetooth hardware.
+use super::{
+    hci::{self, HciConnInfoReq, HciDevInfo, HciDevListReq, HciDevReq, InquiryInfo},
+    sdp::{self, ChannelCache, QueryRFCOMMChannel, QueryRFCOMMChannelStatus},
+    socket::{AF_BLUETOOTH, BTPROTO_HCI},
+};
+use crate::bluetooth::{BtAddr, BtError};
+use crate::sdp::Uuid;
+
+use std::{
+    fmt::Debug,
+    mem,
+    os::raw::*,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Mutex,
+};
+
+/// An in-progress SDP lookup of the RFCOMM channel of a remote device.
+///
+/// Queries are `Send`, so a `BtSocketConnect` can be moved to another thread.
+pub trait SdpQuery: Debug + Send {
+    fn advance(&mut self) -> Result<QueryRFCOMMChannelStatus, BtError>;
+}
+
+impl SdpQuery for QueryRFCOMMChannel {
+    fn advance(&mut self) -> Result<QueryRFCOMMChannelStatus, BtError> {
+        QueryRFCOMMChannel::advance(self)
+    }
+}
+
+/// The system calls `BtSocket` and `scan_devices` are built on.
+///
+/// Failing calls return the `errno` value as error.
+pub trait BtSys: Debug + Sync {
+    fn socket(&self, domain: c_int, kind: c_int, protocol: c_int) -> Result<RawFd, c_int>;
+    fn connect(
+        &self,
+        fd: RawFd,
+        addr: *const libc::sockaddr,
+        len: libc::socklen_t,
+    ) -> Result<(), c_int>;
+    fn getpeername(&self, fd: RawFd) -> Result<(), c_int>;
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> Result<usize, c_int>;
+    /// Search the RFCOMM channel of the service class `uuid`
+    fn sdp_query(&self, addr: BtAddr, uuid: Uuid) -> Box<dyn SdpQuery>;
+    /// RFCOMM channels found by earlier SDP queries
+    fn channel_cache(&self) -> &Mutex<ChannelCache>;
+
+    fn hci_get_route(&self) -> Result<c_int, c_int>;
+    fn hci_open_dev(&self, device_id: c_int) -> Result<RawFd, c_int>;
+    fn hci_inquiry(
+        &self,
+        device_id: c_int,
+        timeout: c_int,
+        lap: Option<&[u8; 3]>,
+        inquiry_infos: &mut [InquiryInfo],
+        flags: c_long,
+    ) -> Result<usize, c_int>;
+    fn hci_send_cmd(
+        &self,
+        socket: RawFd,
+        ogf: u16,
+        ocf: u16,
+        params: &mut [u8],
+    ) -> Result<(), c_int>;
+    fn hci_read_remote_name(
+        &self,
+        socket: RawFd,
+        addr: &BtAddr,
+        name: &mut [c_char],
+    ) -> Result<(), c_int>;
+    fn hci_read_local_name(&self, socket: RawFd, name: &mut [c_char]) -> Result<(), c_int>;
+    /// Send a command and wait for its "Command Complete" event, whose return parameters are
+    /// copied to `response`. Returns their length.
+    fn hci_send_req(
+        &self,
+        socket: RawFd,
+        ogf: u16,
+        ocf: u16,
+        params: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, c_int>;
+    /// Send Create Connection with `params` and wait for its "Connection Complete" event
+    fn hci_create_connection(&self, socket: RawFd, params: &mut [u8]) -> Result<(), c_int>;
+    /// Information about all local adapters
+    fn hci_devices(&self) -> Result<Vec<HciDevInfo>, c_int>;
+    /// Handle of the ACL connection to `addr`
+    fn hci_conn_handle(&self, socket: RawFd, addr: &BtAddr) -> Result<u16, c_int>;
+    fn hci_read_remote_features(
+        &self,
+        socket: RawFd,
+        handle: u16,
+        features: &mut [u8; 8],
+    ) -> Result<(), c_int>;
+}
+
+fn check(result: c_int) -> Result<c_int, c_int> {
+    if result < 0 {
+        Err(nix::errno::errno())
+    } else {
+        Ok(result)
+    }
+}
+
+/// The real system calls, backed by libc and libbluetooth
+#[derive(Debug, Clone, Copy)]
+pub struct LibBluetooth;
+
+static LIBBLUETOOTH: LibBluetooth = LibBluetooth;
+
+/// The system call implementation used outside of tests
+pub fn default_sys() -> &'static dyn BtSys {
+    &LIBBLUETOOTH
+}
+
+impl BtSys for LibBluetooth {
+    fn socket(&self, domain: c_int, kind: c_int, protocol: c_int) -> Result<RawFd, c_int> {
+        check(unsafe { libc::socket(domain, kind, protocol) })
+    }
+
+    fn connect(
+        &self,
+        fd: RawFd,
+        addr: *const libc::sockaddr,
+        len: libc::socklen_t,
+    ) -> Result<(), c_int> {
+        check(unsafe { libc::connect(fd, addr, len) }).map(|_| ())
+    }
+
+    fn getpeername(&self, fd: RawFd) -> Result<(), c_int> {
+        let mut full_address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut socklen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        check(unsafe {
+            libc::getpeername(
+                fd,
+                &mut full_address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut socklen,
+            )
+        })
+        .map(|_| ())
+    }
+
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> Result<usize, c_int> {
+        nix::unistd::read(fd, buf).map_err(|e| e.as_errno().map(|x| x as c_int).unwrap_or(0))
+    }
+
+    fn sdp_query(&self, addr: BtAddr, uuid: Uuid) -> Box<dyn SdpQuery> {
+        Box::new(QueryRFCOMMChannel::new(addr, uuid))
+    }
+
+    fn channel_cache(&self) -> &Mutex<ChannelCache> {
+        sdp::channel_cache()
+    }
+
+    fn hci_get_route(&self) -> Result<c_int, c_int> {
+        check(unsafe { hci::hci_get_route(std::ptr::null_mut()) })
+    }
+
+    fn hci_open_dev(&self, device_id: c_int) -> Result<RawFd, c_int> {
+        check(unsafe { hci::hci_open_dev(device_id) })
+    }
+
+    fn hci_inquiry(
+        &self,
+        device_id: c_int,
+        timeout: c_int,
+        lap: Option<&[u8; 3]>,
+        inquiry_infos: &mut [InquiryInfo],
+        flags: c_long,
+    ) -> Result<usize, c_int> {
+        let mut inquiry_info = inquiry_infos.as_mut_ptr();
+        check(unsafe {
+            hci::hci_inquiry(
+                device_id,
+                timeout,
+                inquiry_infos.len() as c_int,
+                lap.map_or(std::ptr::null(), |lap| lap.as_ptr()),
+                &mut inquiry_info,
+                flags,
+            )
+        })
+        .map(|n| n as usize)
+    }
+
+    fn hci_send_cmd(
+        &self,
+        socket: RawFd,
+        ogf: u16,
+        ocf: u16,
+        params: &mut [u8],
+    ) -> Result<(), c_int> {
+        check(unsafe {
+            hci::hci_send_cmd(
+                socket,
+                ogf,
+                ocf,
+                params.len() as u8,
+                params.as_mut_ptr() as *mut c_void,
+            )
+        })
+        .map(|_| ())
+    }
+
+    fn hci_read_remote_name(
+        &self,
+        socket: RawFd,
+        addr: &BtAddr,
+        name: &mut [c_char],
+    ) -> Result<(), c_int> {
+        check(unsafe {
+            hci::hci_read_remote_name(socket, addr, name.len() as c_int, name.as_mut_ptr(), 0)
+        })
+        .map(|_| ())
+    }
+
+    fn hci_read_local_name(&self, socket: RawFd, name: &mut [c_char]) -> Result<(), c_int> {
+        check(unsafe {
+            hci::hci_read_local_name(
+                socket,
+                name.len() as c_int,
+                name.as_mut_ptr(),
+                hci::HCI_COMMAND_TIMEOUT_MS,
+            )
+        })
+        .map(|_| ())
+    }
+
+    fn hci_send_req(
+        &self,
+        socket: RawFd,
+        ogf: u16,
+        ocf: u16,
+        params: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, c_int> {
+        let mut request = hci::HciRequest {
+            ogf,
+            ocf,
+            event: 0,
+            cparam: params.as_mut_ptr() as *mut c_void,
+            clen: params.len() as c_int,
+            rparam: response.as_mut_ptr() as *mut c_void,
+            rlen: response.len() as c_int,
+        };
+        check(unsafe { hci::hci_send_req(socket, &mut request, hci::HCI_COMMAND_TIMEOUT_MS) })?;
+        Ok(request.rlen as usize)
+    }
+
+    fn hci_create_connection(&self, socket: RawFd, params: &mut [u8]) -> Result<(), c_int> {
+        // Status, handle, address, link type and encryption mode
+        let mut complete = [0u8; 11];
+        let mut request = hci::HciRequest {
+            ogf: hci::OGF_LINK_CTL,
+            ocf: hci::OCF_CREATE_CONN,
+            event: hci::EVT_CONN_COMPLETE,
+            cparam: params.as_mut_ptr() as *mut c_void,
+            clen: params.len() as c_int,
+            rparam: complete.as_mut_ptr() as *mut c_void,
+            rlen: complete.len() as c_int,
+        };
+        check(unsafe { hci::hci_send_req(socket, &mut request, hci::CREATE_CONN_TIMEOUT_MS) })?;
+        // Like `hci_create_connection()` of libbluetooth
+        if complete[0] != 0 {
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    fn hci_devices(&self) -> Result<Vec<HciDevInfo>, c_int> {
+        let socket = check(unsafe {
+            libc::socket(
+                AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                BTPROTO_HCI as c_int,
+            )
+        })?;
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        let mut list = HciDevListReq {
+            dev_num: hci::HCI_MAX_DEV as u16,
+            dev_req: [HciDevReq::default(); hci::HCI_MAX_DEV],
+        };
+        check(unsafe { libc::ioctl(socket.as_raw_fd(), hci::HCIGETDEVLIST, &mut list) })?;
+
+        let mut infos = Vec::with_capacity(list.dev_num as usize);
+        for req in &list.dev_req[..list.dev_num as usize] {
+            let mut info = HciDevInfo {
+                dev_id: req.dev_id,
+                ..HciDevInfo::default()
+            };
+            match check(unsafe { libc::ioctl(socket.as_raw_fd(), hci::HCIGETDEVINFO, &mut info) }) {
+                Ok(_) => infos.push(info),
+                // The adapter was removed in the meantime
+                Err(libc::ENODEV) => {}
+                Err(errno) => return Err(errno),
+            }
+        }
+        Ok(infos)
+    }
+
+    fn hci_conn_handle(&self, socket: RawFd, addr: &BtAddr) -> Result<u16, c_int> {
+        let mut request = HciConnInfoReq {
+            bdaddr: *addr,
+            link_type: hci::ACL_LINK,
+            ..HciConnInfoReq::default()
+        };
+        check(unsafe { libc::ioctl(socket, hci::HCIGETCONNINFO, &mut request) })?;
+        Ok(request.conn_info.handle)
+    }
+
+    fn hci_read_remote_features(
+        &self,
+        socket: RawFd,
+        handle: u16,
+        features: &mut [u8; 8],
+    ) -> Result<(), c_int> {
+        check(unsafe {
+            hci::hci_read_remote_features(
+                socket,
+                handle,
+                features.as_mut_ptr(),
+                hci::HCI_COMMAND_TIMEOUT_MS,
+            )
+        })
+        .map(|_| ())
+    }
+}
+
+/// Scripted stand-in for `LibBluetooth` used by the unit tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockBt {
+    /// Channel found by the SDP search, `None` lets the search fail
+    pub sdp_channel: Option<u8>,
+    /// Error returned by `socket()`
+    pub socket_errno: Option<c_int>,
+    /// Error returned by `connect()`
+    pub connect_errno: Option<c_int>,
+    /// Error the connection fails with after `connect()` was issued
+    pub peer_errno: Option<c_int>,
+    /// Devices found by an inquiry (in host byte order) and their names
+    pub devices: Vec<(BtAddr, &'static str)>,
+    /// Addresses of the local adapters `hci0`, `hci1`, ... (in host byte order)
+    pub adapters: Vec<BtAddr>,
+    /// Separate from the process wide cache, so the tests don't influence each other
+    pub channel_cache: Mutex<ChannelCache>,
+    /// The remote ends of the sockets of the SDP searches, to observe when they are closed
+    pub sdp_peers: Mutex<Vec<std::os::unix::net::UnixStream>>,
+    /// LMP features of the connected device, `None` if there is no connection
+    pub remote_features: Option<[u8; 8]>,
+    /// Whether the kernel's inquiry cache is empty or outdated, so every inquiry has to ask the
+    /// controller
+    pub stale_cache: bool,
+    /// Length and flags of the inquiries run so far
+    pub inquiries: std::sync::Arc<Mutex<Vec<(c_int, c_long)>>>,
+}
+
+#[cfg(test)]
+impl MockBt {
+    /// Leak the mock so it can be used like the static `LibBluetooth`
+    pub fn install(self) -> &'static dyn BtSys {
+        Box::leak(Box::new(self))
+    }
+
+    /// A real, but otherwise useless, file descriptor
+    fn fd() -> RawFd {
+        use std::os::unix::{io::IntoRawFd, net::UnixStream};
+        UnixStream::pair().unwrap().0.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockSdpQuery {
+    step: u8,
+    channel: Option<u8>,
+    socket: std::os::unix::net::UnixStream,
+}
+
+#[cfg(test)]
+impl SdpQuery for MockSdpQuery {
+    fn advance(&mut self) -> Result<QueryRFCOMMChannelStatus, BtError> {
+        use std::os::unix::io::AsRawFd;
+
+        self.step += 1;
+        match (self.step, self.channel) {
+            (1, _) => Ok(QueryRFCOMMChannelStatus::WaitWritable(
+                self.socket.as_raw_fd(),
+            )),
+            (2, _) => Ok(QueryRFCOMMChannelStatus::WaitReadable(
+                self.socket.as_raw_fd(),
+            )),
+            (_, Some(channel)) => Ok(QueryRFCOMMChannelStatus::Done(channel)),
+            (_, None) => Err(BtError::Desc(
+                "No RFCOMM service on remote device".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+impl BtSys for MockBt {
+    fn socket(&self, _: c_int, _: c_int, _: c_int) -> Result<RawFd, c_int> {
+        self.socket_errno.map_or_else(|| Ok(Self::fd()), Err)
+    }
+
+    fn connect(&self, _: RawFd, _: *const libc::sockaddr, _: libc::socklen_t) -> Result<(), c_int> {
+        self.connect_errno.map_or(Ok(()), Err)
+    }
+
+    fn getpeername(&self, _: RawFd) -> Result<(), c_int> {
+        self.peer_errno.map_or(Ok(()), |_| Err(libc::ENOTCONN))
+    }
+
+    fn read(&self, _: RawFd, _: &mut [u8]) -> Result<usize, c_int> {
+        self.peer_errno.map_or(Ok(0), Err)
+    }
+
+    fn sdp_query(&self, _: BtAddr, _: Uuid) -> Box<dyn SdpQuery> {
+        let (socket, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        self.sdp_peers.lock().unwrap().push(peer);
+        Box::new(MockSdpQuery {
+            step: 0,
+            channel: self.sdp_channel,
+            socket,
+        })
+    }
+
+    fn channel_cache(&self) -> &Mutex<ChannelCache> {
+        &self.channel_cache
+    }
+
+    fn hci_get_route(&self) -> Result<c_int, c_int> {
+        Ok(0)
+    }
+
+    fn hci_open_dev(&self, _: c_int) -> Result<RawFd, c_int> {
+        Ok(Self::fd())
+    }
+
+    fn hci_inquiry(
+        &self,
+        _: c_int,
+        length: c_int,
+        _: Option<&[u8; 3]>,
+        inquiry_infos: &mut [InquiryInfo],
+        flags: c_long,
+    ) -> Result<usize, c_int> {
+        self.inquiries.lock().unwrap().push((length, flags));
+        // Like the controller, which rejects an inquiry of length zero
+        if (self.stale_cache || flags != 0) && length == 0 {
+            return Err(libc::EINVAL);
+        }
+        for (info, (addr, _)) in inquiry_infos.iter_mut().zip(&self.devices) {
+            info.bdaddr = *addr;
+        }
+        Ok(self.devices.len())
+    }
+
+    fn hci_send_cmd(&self, _: RawFd, _: u16, _: u16, _: &mut [u8]) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    fn hci_read_remote_name(
+        &self,
+        _: RawFd,
+        addr: &BtAddr,
+        name: &mut [c_char],
+    ) -> Result<(), c_int> {
+        let (_, device_name) = self
+            .devices
+            .iter()
+            .find(|(a, _)| a == addr)
+            .ok_or(libc::EHOSTDOWN)?;
+        for (dst, src) in name.iter_mut().zip(device_name.bytes()) {
+            *dst = src as c_char;
+        }
+        Ok(())
+    }
+
+    fn hci_read_local_name(&self, _: RawFd, name: &mut [c_char]) -> Result<(), c_int> {
+        for (dst, src) in name.iter_mut().zip(b"Mock adapter\0") {
+            *dst = *src as c_char;
+        }
+        Ok(())
+    }
+
+    /// Answers with status 0 (success), followed by the parameters
+    fn hci_send_req(
+        &self,
+        _: RawFd,
+        _: u16,
+        _: u16,
+        params: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, c_int> {
+        let len = (params.len() + 1).min(response.len());
+        response[0] = 0;
+        response[1..len].copy_from_slice(&params[..len - 1]);
+        Ok(len)
+    }
+
+    fn hci_create_connection(&self, _: RawFd, _: &mut [u8]) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    fn hci_devices(&self) -> Result<Vec<HciDevInfo>, c_int> {
+        Ok(self
+            .adapters
+            .iter()
+            .enumerate()
+            .map(|(id, addr)| {
+                let mut info = HciDevInfo {
+                    dev_id: id as u16,
+                    bdaddr: *addr,
+                    flags: 1,
+                    ..HciDevInfo::default()
+                };
+                for (dst, src) in info.name.iter_mut().zip(format!("hci{}", id).bytes()) {
+                    *dst = src as c_char;
+                }
+                info
+            })
+            .collect())
+    }
+
+    fn hci_conn_handle(&self, _: RawFd, _: &BtAddr) -> Result<u16, c_int> {
+        self.remote_features.map(|_| 1).ok_or(libc::ENOENT)
+    }
+
+    fn hci_read_remote_features(
+        &self,
+        _: RawFd,
+        _: u16,
+        features: &mut [u8; 8],
+    ) -> Result<(), c_int> {
+        *features = self.remote_features.ok_or(libc::ENOTCONN)?;
+        Ok(())
+    }
+}
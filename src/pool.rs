@@ -0,0 +1,360 @@
+//! Several connections kept alive in the background, see `BtPool`.
+use crate::bluetooth::{BtAddr, BtError, BtInterruptHandle, BtSocket};
+
+use futures_core::Stream;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    io::{Read, Write},
+    os::unix::{io::BorrowedFd, net::UnixStream},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread, time,
+};
+
+/// Connections to several devices, each (re)connected by a background thread.
+///
+/// The data received from all devices is yielded as `Stream` of `(BtAddr, Vec<u8>)`, in the
+/// chunks it was read in. The stream doesn't end. At most 1024 chunks are kept until they are
+/// consumed (see `with_max_queued`), the oldest ones are dropped to make room for new ones.
+///
+/// Connections are RFCOMM connections to the serial port service, like
+/// `BtSocket::connect_rfcomm`, unless another connector is set with `with_connector`.
+///
+/// Dropping the pool closes all connections, the background threads finish on their own.
+#[derive(Debug)]
+pub struct BtPool {
+    shared: Arc<Shared>,
+    links: BTreeMap<BtAddr, Arc<Link>>,
+    reconnect_delay: time::Duration,
+    connector: Connector,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    max_queued: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    received: VecDeque<(BtAddr, Vec<u8>)>,
+    waker: Option<Waker>,
+}
+
+/// Establishes the connections of the pool
+#[derive(Clone)]
+struct Connector(Arc<dyn Fn(BtAddr) -> Result<BtSocket, BtError> + Send + Sync>);
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+/// A single connection of the pool
+#[derive(Debug, Default)]
+struct Link {
+    stopped: AtomicBool,
+    /// Duplicate of the socket of the current connection, for sending
+    writer: Mutex<Option<UnixStream>>,
+    interrupt: Mutex<Option<BtInterruptHandle>>,
+}
+
+impl BtPool {
+    /// Creates an empty pool.
+    pub fn new() -> BtPool {
+        BtPool {
+            shared: Arc::new(Shared {
+                state: Mutex::default(),
+                max_queued: 1024,
+            }),
+            links: BTreeMap::new(),
+            reconnect_delay: time::Duration::from_secs(1),
+            connector: Connector(Arc::new(BtSocket::connect_rfcomm)),
+        }
+    }
+
+    /// Sets how long to wait before reconnecting after a connection failed or was closed, one
+    /// second by default. Applies to devices added afterwards.
+    pub fn with_reconnect_delay(mut self, delay: time::Duration) -> BtPool {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// Sets how (re)connections to a device are established, e.g. to connect to a fixed RFCOMM
+    /// channel. Applies to devices added afterwards.
+    pub fn with_connector<F>(mut self, connector: F) -> BtPool
+    where
+        F: Fn(BtAddr) -> Result<BtSocket, BtError> + Send + Sync + 'static,
+    {
+        self.connector = Connector(Arc::new(connector));
+        self
+    }
+
+    /// Sets how many received chunks are kept until they are consumed, 1024 by default. Must be
+    /// called before adding devices, panics otherwise or if `max_queued` is 0.
+    pub fn with_max_queued(mut self, max_queued: usize) -> BtPool {
+        assert!(
+            max_queued > 0,
+            "The pool must be able to queue received data"
+        );
+        Arc::get_mut(&mut self.shared)
+            .expect("The queue size must be set before adding devices")
+            .max_queued = max_queued;
+        self
+    }
+
+    /// Adds the device with address `addr`, which is connected (and reconnected whenever the
+    /// connection is lost) in the background. Does nothing if the device is part of the pool
+    /// already.
+    pub fn add(&mut self, addr: BtAddr) {
+        if self.links.contains_key(&addr) {
+            return;
+        }
+        let link = Arc::new(Link::default());
+        let worker = Worker {
+            addr,
+            link: Arc::clone(&link),
+            shared: Arc::clone(&self.shared),
+            reconnect_delay: self.reconnect_delay,
+            connector: self.connector.clone(),
+        };
+        thread::spawn(move || worker.run());
+        self.links.insert(addr, link);
+    }
+
+    /// Removes the device with address `addr` and closes its connection.
+    ///
+    /// Data received from it before is still yielded by the stream.
+    pub fn remove(&mut self, addr: BtAddr) {
+        if let Some(link) = self.links.remove(&addr) {
+            link.stop();
+        }
+    }
+
+    /// Checks whether the device with address `addr` is currently connected.
+    pub fn is_connected(&self, addr: BtAddr) -> bool {
+        self.links
+            .get(&addr)
+            .is_some_and(|link| link.writer.lock().unwrap().is_some())
+    }
+
+    /// Sends `bytes` to the device with address `addr`. Fails if the device isn't part of the
+    /// pool or not connected at the moment.
+    pub fn send(&self, addr: BtAddr, bytes: &[u8]) -> Result<(), BtError> {
+        let link = self
+            .links
+            .get(&addr)
+            .ok_or_else(|| BtError::Desc(format!("{:?} is not part of the pool", addr)))?;
+        let mut writer = link.writer.lock().unwrap();
+        let writer = writer
+            .as_mut()
+            .ok_or_else(|| BtError::Desc(format!("{:?} is not connected", addr)))?;
+        Ok(writer.write_all(bytes)?)
+    }
+}
+
+impl Default for BtPool {
+    fn default() -> Self {
+        BtPool::new()
+    }
+}
+
+impl Link {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(interrupt) = &*self.interrupt.lock().unwrap() {
+            let _ = interrupt.interrupt();
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Background thread keeping up the connection of one `Link`
+struct Worker {
+    addr: BtAddr,
+    link: Arc<Link>,
+    shared: Arc<Shared>,
+    reconnect_delay: time::Duration,
+    connector: Connector,
+}
+
+impl Worker {
+    fn run(self) {
+        while !self.link.stopped() {
+            match self.connect() {
+                Ok(socket) => {
+                    self.receive(socket);
+                    *self.link.writer.lock().unwrap() = None;
+                    *self.link.interrupt.lock().unwrap() = None;
+                }
+                Err(e) => {
+                    bt_log!(
+                        debug,
+                        "{:?}: connecting pool member failed: {}",
+                        self.addr,
+                        e
+                    );
+                }
+            }
+            if !self.link.stopped() {
+                thread::sleep(self.reconnect_delay);
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<BtSocket, BtError> {
+        let socket = (self.connector.0)(self.addr)?;
+        let writer = unsafe { BorrowedFd::borrow_raw(socket.get_fd()) }.try_clone_to_owned()?;
+        *self.link.interrupt.lock().unwrap() = Some(socket.interrupt_handle()?);
+        *self.link.writer.lock().unwrap() = Some(UnixStream::from(writer));
+        Ok(socket)
+    }
+
+    /// Forward the received data until the connection is closed or the link is stopped
+    fn receive(&self, mut socket: BtSocket) {
+        let mut buf = [0u8; 1024];
+        // The link could have been stopped before its interrupt handle was set
+        while !self.link.stopped() {
+            match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    let mut state = self.shared.state.lock().unwrap();
+                    if state.received.len() == self.shared.max_queued {
+                        bt_log!(warn, "Pool queue is full, dropping the oldest data");
+                        state.received.pop_front();
+                    }
+                    state.received.push_back((self.addr, buf[..len].to_vec()));
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                // Stopping the link interrupts the read
+                Err(e)
+                    if matches!(
+                        e.get_ref().and_then(|e| e.downcast_ref::<BtError>()),
+                        Some(BtError::Interrupted)
+                    ) => {}
+                Err(e) => {
+                    bt_log!(debug, "{:?}: pool connection failed: {}", self.addr, e);
+                    break;
+                }
+            }
+        }
+        bt_log!(debug, "{:?}: pool connection closed", self.addr);
+        // Make sure the writer's duplicate doesn't keep the connection open
+        let _ = socket.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl Stream for BtPool {
+    type Item = (BtAddr, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.received.pop_front() {
+            Some(received) => Poll::Ready(Some(received)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for BtPool {
+    fn drop(&mut self) {
+        for link in self.links.values() {
+            link.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform;
+    use std::sync::mpsc;
+
+    const ADDR: BtAddr = BtAddr([1, 2, 3, 4, 5, 6]);
+
+    /// A pool connecting to socket pairs, whose other ends are sent to the returned receiver
+    fn mock_pool() -> (BtPool, mpsc::Receiver<UnixStream>) {
+        let (remotes, connections) = mpsc::channel();
+        let pool = BtPool::new()
+            .with_reconnect_delay(time::Duration::from_millis(10))
+            .with_connector(move |_| {
+                let (socket, remote) = platform::socket_pair();
+                remotes
+                    .send(remote)
+                    .map_err(|_| BtError::Desc("Test finished".to_string()))?;
+                Ok(BtSocket::from(socket))
+            });
+        (pool, connections)
+    }
+
+    fn next(pool: &mut BtPool) -> (BtAddr, Vec<u8>) {
+        async_std::task::block_on(std::future::poll_fn(|cx| {
+            Pin::new(&mut *pool).poll_next(cx)
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reconnects_closed_connections() {
+        let (mut pool, connections) = mock_pool();
+        pool.add(ADDR);
+
+        let mut remote = connections.recv().unwrap();
+        remote.write_all(b"first").unwrap();
+        assert_eq!(next(&mut pool), (ADDR, b"first".to_vec()));
+        assert!(pool.is_connected(ADDR));
+        pool.send(ADDR, b"AT").unwrap();
+        let mut request = [0; 2];
+        remote.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"AT");
+
+        drop(remote);
+        let mut remote = connections.recv().unwrap();
+        remote.write_all(b"second").unwrap();
+        assert_eq!(next(&mut pool), (ADDR, b"second".to_vec()));
+    }
+
+    #[test]
+    fn stops_removed_devices() {
+        let (mut pool, connections) = mock_pool();
+        pool.add(ADDR);
+        let mut remote = connections.recv().unwrap();
+
+        pool.remove(ADDR);
+        assert_eq!(remote.read(&mut [0; 4]).unwrap(), 0);
+        assert!(connections
+            .recv_timeout(time::Duration::from_millis(100))
+            .is_err());
+        assert!(pool.send(ADDR, b"AT").is_err());
+    }
+
+    #[test]
+    fn drops_oldest_data_when_full() {
+        let (pool, connections) = mock_pool();
+        let mut pool = pool.with_max_queued(2);
+        pool.add(ADDR);
+
+        let mut remote = connections.recv().unwrap();
+        for chunk in [b"a", b"b", b"c"] {
+            remote.write_all(chunk).unwrap();
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        assert_eq!(next(&mut pool), (ADDR, b"b".to_vec()));
+        assert_eq!(next(&mut pool), (ADDR, b"c".to_vec()));
+    }
+}
@@ -0,0 +1,221 @@
+//! Relaying data between two connections, see `copy_bidirectional`.
+use crate::bluetooth::{BtError, BtSocket};
+
+use async_io::Async;
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::{raw::c_int, unix::io::RawFd, unix::net::UnixStream},
+    pin::pin,
+    task::Poll,
+    thread,
+};
+
+/// Size of the buffer of each direction
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Copies the data received by `a` to `b` and the data received by `b` to `a`, until both
+/// directions reached EOF. Returns the number of bytes copied from `a` to `b` and from `b` to `a`.
+///
+/// Once one side closes its sending direction, the other side's sending direction is shut down
+/// as well. Bluetooth sockets can't be half-closed though: shutting down the sending direction
+/// of an RFCOMM or L2CAP connection closes the whole connection. So the first EOF of either side
+/// ends both directions, data the other side sends afterwards is lost. If either direction fails,
+/// both sockets are shut down and its error is returned.
+///
+/// The data is relayed unchanged, regardless of `BtSocket::set_mode`. This function blocks until
+/// the copy is done, using a thread for one of the directions.
+pub fn copy_bidirectional(a: &mut BtSocket, b: &mut BtSocket) -> Result<(u64, u64), BtError> {
+    let (a, b) = (a.try_get_stream_std()?, b.try_get_stream_std()?);
+    thread::scope(|scope| {
+        let b_to_a = scope.spawn(|| pump(&b, &a));
+        let a_to_b = pump(&a, &b);
+        let b_to_a = b_to_a.join().expect("Relay thread panicked");
+        Ok((a_to_b?, b_to_a?))
+    })
+}
+
+/// Like `copy_bidirectional`, but waits for the sockets with the `async-io` reactor instead of
+/// blocking.
+///
+/// The sockets are switched to non-blocking mode while the copy runs, their previous mode is
+/// restored afterwards (also if the copy fails or the future is dropped).
+pub async fn copy_bidirectional_async(
+    a: &mut BtSocket,
+    b: &mut BtSocket,
+) -> Result<(u64, u64), BtError> {
+    let _a_mode = RestoreBlocking::save(a)?;
+    let _b_mode = RestoreBlocking::save(b)?;
+    let a = Async::new(a.try_get_stream_std()?)?;
+    let b = Async::new(b.try_get_stream_std()?)?;
+    let mut a_to_b = pin!(pump_async(&a, &b));
+    let mut b_to_a = pin!(pump_async(&b, &a));
+    let (mut a_to_b_result, mut b_to_a_result) = (None, None);
+    std::future::poll_fn(|cx| {
+        if a_to_b_result.is_none() {
+            if let Poll::Ready(result) = a_to_b.as_mut().poll(cx) {
+                a_to_b_result = Some(result);
+            }
+        }
+        if b_to_a_result.is_none() {
+            if let Poll::Ready(result) = b_to_a.as_mut().poll(cx) {
+                b_to_a_result = Some(result);
+            }
+        }
+        if a_to_b_result.is_some() && b_to_a_result.is_some() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+
+    Ok((a_to_b_result.unwrap()?, b_to_a_result.unwrap()?))
+}
+
+/// Restores the file status flags of a socket, including its blocking mode, when dropped. The
+/// duplicates of the descriptor share the flags with the socket.
+struct RestoreBlocking {
+    fd: RawFd,
+    flags: c_int,
+}
+
+impl RestoreBlocking {
+    fn save(socket: &BtSocket) -> Result<RestoreBlocking, BtError> {
+        let fd = socket.get_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(RestoreBlocking { fd, flags })
+    }
+}
+
+impl Drop for RestoreBlocking {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(self.fd, libc::F_SETFL, self.flags) };
+    }
+}
+
+/// Copy from `from` to `to` until EOF, then shut down the sending direction of `to`
+fn pump(mut from: &UnixStream, mut to: &UnixStream) -> Result<u64, BtError> {
+    let mut buf = [0u8; BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let result = match from.read(&mut buf) {
+            Ok(0) => return finish(from, to, copied),
+            Ok(len) => to.write_all(&buf[..len]).map(|()| len),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(len) => copied += len as u64,
+            Err(e) => return Err(abort(from, to, e)),
+        }
+    }
+}
+
+async fn pump_async(from: &Async<UnixStream>, to: &Async<UnixStream>) -> Result<u64, BtError> {
+    let mut buf = [0u8; BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let len = match from.read_with(|mut stream| stream.read(&mut buf)).await {
+            Ok(0) => return finish(from.get_ref(), to.get_ref(), copied),
+            Ok(len) => len,
+            Err(e) => return Err(abort(from.get_ref(), to.get_ref(), e)),
+        };
+        let mut sent = 0;
+        while sent < len {
+            match to
+                .write_with(|mut stream| stream.write(&buf[sent..len]))
+                .await
+            {
+                Ok(0) => {
+                    let e = io::Error::from(io::ErrorKind::WriteZero);
+                    return Err(abort(from.get_ref(), to.get_ref(), e));
+                }
+                Ok(n) => sent += n,
+                Err(e) => return Err(abort(from.get_ref(), to.get_ref(), e)),
+            }
+        }
+        copied += len as u64;
+    }
+}
+
+fn finish(from: &UnixStream, to: &UnixStream, copied: u64) -> Result<u64, BtError> {
+    match to.shutdown(Shutdown::Write) {
+        // The other direction may have failed and shut down both sockets already
+        Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(abort(from, to, e)),
+        _ => Ok(copied),
+    }
+}
+
+/// Shut down both sockets, so the other direction stops as well
+fn abort(from: &UnixStream, to: &UnixStream, error: io::Error) -> BtError {
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+    error.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform;
+
+    fn socket_pair() -> (BtSocket, UnixStream) {
+        let (socket, remote) = platform::socket_pair();
+        (BtSocket::from(socket), remote)
+    }
+
+    fn is_nonblocking(socket: &BtSocket) -> bool {
+        unsafe { libc::fcntl(socket.get_fd(), libc::F_GETFL) & libc::O_NONBLOCK != 0 }
+    }
+
+    #[test]
+    fn relays_both_directions() {
+        let relay = |copy: &dyn Fn(&mut BtSocket, &mut BtSocket) -> (u64, u64)| {
+            let (mut a, mut a_remote) = socket_pair();
+            let (mut b, mut b_remote) = socket_pair();
+
+            a_remote.write_all(b"ping!").unwrap();
+            a_remote.shutdown(Shutdown::Write).unwrap();
+            b_remote.write_all(b"pong").unwrap();
+            b_remote.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(copy(&mut a, &mut b), (5, 4));
+
+            let mut received = Vec::new();
+            b_remote.read_to_end(&mut received).unwrap();
+            assert_eq!(received, b"ping!");
+            received.clear();
+            a_remote.read_to_end(&mut received).unwrap();
+            assert_eq!(received, b"pong");
+        };
+
+        relay(&|a, b| copy_bidirectional(a, b).unwrap());
+        relay(&|a, b| async_std::task::block_on(copy_bidirectional_async(a, b)).unwrap());
+    }
+
+    #[test]
+    fn restores_blocking_mode() {
+        let (mut a, a_remote) = socket_pair();
+        let (mut b, b_remote) = socket_pair();
+        a.get_stream_std().set_nonblocking(true).unwrap();
+
+        // Dropped while waiting for data
+        let mut copy = Box::pin(copy_bidirectional_async(&mut a, &mut b));
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(copy.as_mut().poll(&mut cx).is_pending());
+        drop(copy);
+        assert!(is_nonblocking(&a));
+        assert!(!is_nonblocking(&b));
+
+        drop((a_remote, b_remote));
+        assert_eq!(
+            async_std::task::block_on(copy_bidirectional_async(&mut a, &mut b)).unwrap(),
+            (0, 0)
+        );
+        assert!(is_nonblocking(&a));
+        assert!(!is_nonblocking(&b));
+    }
+}
@@ -0,0 +1,213 @@
+//! Continuous device discovery in the background, consumed as a `Stream`.
+use crate::{
+    bluetooth::{BtAddr, BtDevice, BtError, ScanOptions},
+    platform,
+};
+
+use futures_core::Stream;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+/// Starts background scans, see `Scanner::start`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Scanner {
+    options: ScanOptions,
+}
+
+impl Scanner {
+    /// Creates a scanner whose inquiry rounds use `options`.
+    pub fn new(options: ScanOptions) -> Scanner {
+        Scanner { options }
+    }
+
+    /// Starts scanning on a background thread.
+    ///
+    /// Inquiries are repeated until the scan is stopped, every device is reported once, when it
+    /// is found for the first time.
+    pub fn start(&self) -> ScannerHandle {
+        self.start_with(platform::scan_devices_until, platform::cancel_inquiry)
+    }
+
+    fn start_with<S>(&self, scan: S, cancel: fn() -> Result<(), BtError>) -> ScannerHandle
+    where
+        S: Fn(&ScanOptions, &AtomicBool) -> Result<Vec<BtDevice>, BtError> + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let options = self.options;
+        let worker = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.run(&options, scan))
+        };
+
+        ScannerHandle {
+            shared,
+            worker: Some(worker),
+            cancel,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    stopped: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    found: VecDeque<Result<BtDevice, BtError>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn run<S>(&self, options: &ScanOptions, scan: S)
+    where
+        S: Fn(&ScanOptions, &AtomicBool) -> Result<Vec<BtDevice>, BtError>,
+    {
+        let mut seen = BTreeSet::<BtAddr>::new();
+        while !self.stopped.load(Ordering::SeqCst) {
+            // Stopping interrupts the scan before the inquiry or between name requests
+            let result = scan(options, &self.stopped);
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let failed = result.is_err();
+            self.push(match result {
+                Ok(devices) => devices
+                    .into_iter()
+                    .filter(|device| seen.insert(device.addr))
+                    .map(Ok)
+                    .collect(),
+                Err(error) => vec![Err(error)],
+            });
+            if failed {
+                break;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn push(&self, results: Vec<Result<BtDevice, BtError>>) {
+        if results.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.found.extend(results);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A running background scan, yielding the found devices as `Stream`.
+///
+/// The stream ends once the scan has been stopped or an inquiry failed (after yielding the
+/// error). Dropping the handle stops the scan without waiting for the background thread, which
+/// exits on its own once the remote name request in progress (if any) returns.
+#[derive(Debug)]
+pub struct ScannerHandle {
+    shared: Arc<Shared>,
+    worker: Option<thread::JoinHandle<()>>,
+    cancel: fn() -> Result<(), BtError>,
+}
+
+impl ScannerHandle {
+    /// Stops the scan, aborting the current inquiry, and waits for the background thread to
+    /// exit. This blocks until the remote name request in progress (if any) returns.
+    ///
+    /// Devices found before are still yielded by the stream.
+    pub fn stop(&mut self) -> Result<(), BtError> {
+        let result = self.signal_stop();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        result
+    }
+
+    fn signal_stop(&mut self) -> Result<(), BtError> {
+        if self.shared.stopped.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        (self.cancel)()
+    }
+}
+
+impl Stream for ScannerHandle {
+    type Item = Result<BtDevice, BtError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.found.pop_front() {
+            Some(result) => Poll::Ready(Some(result)),
+            None if state.finished => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ScannerHandle {
+    fn drop(&mut self) {
+        // The worker is detached, it only holds its own reference to the shared state
+        let _ = self.signal_stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::MockBt;
+
+    #[test]
+    fn yields_new_devices_until_stopped() {
+        let sys = MockBt {
+            devices: vec![
+                (BtAddr([6, 5, 4, 3, 2, 1]), "Headset"),
+                (BtAddr([1, 1, 1, 1, 1, 1]), "GPS"),
+            ],
+            ..MockBt::default()
+        }
+        .install();
+
+        let mut handle = Scanner::default().start_with(
+            move |options, stop| platform::scan_until(sys, options, stop),
+            || Ok(()),
+        );
+        let mut next = || {
+            async_std::task::block_on(std::future::poll_fn(|cx| {
+                Pin::new(&mut handle).poll_next(cx)
+            }))
+        };
+        let mut found = [next().unwrap().unwrap(), next().unwrap().unwrap()];
+        found.sort_by_key(|device| device.addr);
+        assert_eq!(found[0].name, "GPS");
+        assert_eq!(found[1].name, "Headset");
+
+        // Later rounds find the same devices again, which aren't reported twice
+        handle.stop().unwrap();
+        assert!(handle.worker.is_none());
+        let mut next = || {
+            async_std::task::block_on(std::future::poll_fn(|cx| {
+                Pin::new(&mut handle).poll_next(cx)
+            }))
+        };
+        assert!(next().is_none());
+    }
+}
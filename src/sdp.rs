@@ -0,0 +1,563 @@
+use crate::bluetooth::BtError;
+
+use std::collections::BTreeMap;
+
+/// A 128-bit UUID identifying a service class or protocol, stored in network byte-order.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid(pub [u8; 16]);
+
+/// The Bluetooth base UUID `00000000-0000-1000-8000-00805F9B34FB`, which 16 and 32-bit UUIDs
+/// are aliases into.
+const BASE_UUID: [u8; 16] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B, 0x34, 0xFB,
+];
+
+impl Uuid {
+    /// Returns the full UUID of a 16-bit UUID like `0x1101` (serial port).
+    pub fn from_u16(short: u16) -> Uuid {
+        Uuid::from_u32(u32::from(short))
+    }
+
+    /// Returns the full UUID of a 32-bit UUID.
+    pub fn from_u32(short: u32) -> Uuid {
+        let mut uuid = BASE_UUID;
+        uuid[..4].copy_from_slice(&short.to_be_bytes());
+        Uuid(uuid)
+    }
+
+    /// Returns the 16-bit alias of this UUID, if it has one.
+    pub fn as_u16(&self) -> Option<u16> {
+        if self.0[..2] == [0, 0] && self.0[4..] == BASE_UUID[4..] {
+            Some(u16::from_be_bytes([self.0[2], self.0[3]]))
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for Uuid {
+    /// Formats the UUID as `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Well-known RFCOMM profiles to connect to, see `BtSocket::connect_profile`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Profile {
+    /// Serial Port Profile (SPP), `0x1101`.
+    SerialPort,
+    /// Dial-up Networking Profile (DUN), `0x1103`.
+    DialupNetworking,
+    /// Headset Profile (HSP), `0x1108`.
+    Headset,
+    /// Hands-Free Profile (HFP) of the hands-free unit, `0x111E`.
+    HandsFree,
+    /// Any other service class.
+    Custom(Uuid),
+}
+
+impl Profile {
+    /// Returns the service class UUID the SDP search looks for.
+    pub fn uuid(&self) -> Uuid {
+        match *self {
+            Profile::SerialPort => Uuid::from_u16(0x1101),
+            Profile::DialupNetworking => Uuid::from_u16(0x1103),
+            Profile::Headset => Uuid::from_u16(0x1108),
+            Profile::HandsFree => Uuid::from_u16(0x111E),
+            Profile::Custom(uuid) => uuid,
+        }
+    }
+}
+
+impl From<Profile> for Uuid {
+    fn from(profile: Profile) -> Uuid {
+        profile.uuid()
+    }
+}
+
+/// A service offered by a remote device, as found through SDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdpService {
+    /// The service classes this service is an instance of.
+    pub service_class_ids: Vec<Uuid>,
+
+    /// The RFCOMM channel the service is reachable on, if it uses RFCOMM.
+    pub channel: Option<u8>,
+
+    /// The human-readable name of the service.
+    pub name: Option<String>,
+
+    /// The name of the person or organization providing the service.
+    pub provider: Option<String>,
+}
+
+const ATTR_SERVICE_CLASS_ID_LIST: u16 = 0x0001;
+const ATTR_PROTOCOL_DESCRIPTOR_LIST: u16 = 0x0004;
+const ATTR_LANGUAGE_BASE_ATTRIBUTE_ID_LIST: u16 = 0x0006;
+
+/// Attribute base of the primary language, which the text attributes are offset from
+const PRIMARY_LANGUAGE_BASE: u16 = 0x0100;
+const ATTR_OFFSET_SERVICE_NAME: u16 = 0x0000;
+const ATTR_OFFSET_PROVIDER_NAME: u16 = 0x0002;
+
+pub(crate) const PROTO_RFCOMM: u16 = 0x0003;
+
+// IANA MIBenum values of the character encodings used for SDP text attributes
+const MIB_ISO_8859_1: u16 = 4;
+const MIB_UTF_8: u16 = 106;
+const MIB_UTF_16BE: u16 = 1013;
+const MIB_UTF_16LE: u16 = 1014;
+const MIB_UTF_16: u16 = 1015;
+
+/// A decoded SDP data element, the value of an attribute of an `SdpRecord`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdpValue {
+    /// The null value.
+    Nil,
+    /// An unsigned integer of 1 to 16 bytes.
+    Uint(u128),
+    /// A signed integer of 1 to 16 bytes.
+    Int(i128),
+    /// A UUID, 16 and 32-bit UUIDs are expanded to 128 bits.
+    Uuid(Uuid),
+    /// A text string in the encoding of the record's language base, as transmitted.
+    Text(Vec<u8>),
+    /// A boolean.
+    Bool(bool),
+    /// A sequence of values.
+    Sequence(Vec<SdpValue>),
+    /// A list of alternative values, of which one is to be selected.
+    Alternative(Vec<SdpValue>),
+    /// A URL, as transmitted.
+    Url(Vec<u8>),
+}
+
+/// Maximum nesting of sequences and alternatives. Records use a handful of levels, the limit
+/// protects the recursion of the parser from data sent by the remote device.
+const MAX_NESTING: usize = 32;
+
+fn malformed() -> BtError {
+    BtError::Desc("Malformed SDP data element".to_string())
+}
+
+fn be_uint(bytes: &[u8]) -> u128 {
+    bytes
+        .iter()
+        .fold(0u128, |value, &byte| (value << 8) | u128::from(byte))
+}
+
+impl SdpValue {
+    /// Parse the data element at the start of `data`, returning it and its encoded length
+    pub(crate) fn parse(data: &[u8]) -> Result<(SdpValue, usize), BtError> {
+        Self::parse_nested(data, 0)
+    }
+
+    /// Parse a data element nested in `depth` sequences or alternatives
+    fn parse_nested(data: &[u8], depth: usize) -> Result<(SdpValue, usize), BtError> {
+        let &descriptor = data.first().ok_or_else(malformed)?;
+        let (kind, size_index) = (descriptor >> 3, descriptor & 0x07);
+
+        let (header, len) = match size_index {
+            0..=4 if kind == 0 => (1, 0),
+            0..=4 => (1, 1usize << size_index),
+            5 => (2, be_uint(data.get(1..2).ok_or_else(malformed)?) as usize),
+            6 => (3, be_uint(data.get(1..3).ok_or_else(malformed)?) as usize),
+            _ => (5, be_uint(data.get(1..5).ok_or_else(malformed)?) as usize),
+        };
+        let body = data.get(header..header + len).ok_or_else(malformed)?;
+
+        let value = match kind {
+            0 => SdpValue::Nil,
+            1 if len <= 16 => SdpValue::Uint(be_uint(body)),
+            2 if (1..=16).contains(&len) => {
+                let shift = 128 - 8 * len as u32;
+                SdpValue::Int(((be_uint(body) << shift) as i128) >> shift)
+            }
+            3 => SdpValue::Uuid(match len {
+                2 => Uuid::from_u16(be_uint(body) as u16),
+                4 => Uuid::from_u32(be_uint(body) as u32),
+                16 => {
+                    let mut uuid = [0; 16];
+                    uuid.copy_from_slice(body);
+                    Uuid(uuid)
+                }
+                _ => return Err(malformed()),
+            }),
+            4 => SdpValue::Text(body.to_vec()),
+            5 if len == 1 => SdpValue::Bool(body[0] != 0),
+            6 | 7 if depth >= MAX_NESTING => {
+                return Err(BtError::Desc(
+                    "SDP data elements are nested too deeply".to_string(),
+                ))
+            }
+            6 => SdpValue::Sequence(Self::parse_all(body, depth + 1)?),
+            7 => SdpValue::Alternative(Self::parse_all(body, depth + 1)?),
+            8 => SdpValue::Url(body.to_vec()),
+            _ => return Err(malformed()),
+        };
+
+        Ok((value, header + len))
+    }
+
+    /// Parse a series of consecutive data elements
+    fn parse_all(mut data: &[u8], depth: usize) -> Result<Vec<SdpValue>, BtError> {
+        let mut values = Vec::new();
+        while !data.is_empty() {
+            let (value, len) = Self::parse_nested(data, depth)?;
+            values.push(value);
+            data = &data[len..];
+        }
+        Ok(values)
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match *self {
+            SdpValue::Uint(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_sequence(&self) -> Option<&[SdpValue]> {
+        match *self {
+            SdpValue::Sequence(ref values) | SdpValue::Alternative(ref values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// The attributes of a single service record
+type AttributeList = Vec<(u16, SdpValue)>;
+
+/// Split the attribute lists of a ServiceSearchAttribute response into records
+pub(crate) fn parse_attribute_lists(response: &[u8]) -> Result<Vec<AttributeList>, BtError> {
+    let (lists, _) = SdpValue::parse(response)?;
+    let lists = lists.as_sequence().ok_or_else(malformed)?;
+
+    lists
+        .iter()
+        .map(|list| {
+            let list = list.as_sequence().ok_or_else(malformed)?;
+            list.chunks(2)
+                .map(|pair| match pair {
+                    [SdpValue::Uint(id), value] => Ok((*id as u16, value.clone())),
+                    _ => Err(malformed()),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn attribute(record: &[(u16, SdpValue)], id: u16) -> Option<&SdpValue> {
+    record
+        .iter()
+        .find(|&&(attr_id, _)| attr_id == id)
+        .map(|(_, value)| value)
+}
+
+/// Decode a text attribute given the MIBenum of its character encoding
+fn decode_text(text: &[u8], encoding: u16) -> String {
+    let text = match encoding {
+        MIB_ISO_8859_1 => text.iter().map(|&b| char::from(b)).collect(),
+        MIB_UTF_16BE | MIB_UTF_16LE | MIB_UTF_16 => {
+            let (little_endian, text) = match text {
+                [0xFF, 0xFE, rest @ ..] if encoding == MIB_UTF_16 => (true, rest),
+                [0xFE, 0xFF, rest @ ..] if encoding == MIB_UTF_16 => (false, rest),
+                _ => (encoding == MIB_UTF_16LE, text),
+            };
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| {
+                    if little_endian {
+                        u16::from_le_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_be_bytes([c[0], c[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        // US-ASCII is a subset of UTF-8; unknown encodings are decoded as UTF-8 as well
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+
+    // Some devices include the terminating NUL
+    text.trim_end_matches('\0').to_string()
+}
+
+impl SdpService {
+    pub(crate) fn from_attributes(record: &[(u16, SdpValue)]) -> SdpService {
+        let service_class_ids = attribute(record, ATTR_SERVICE_CLASS_ID_LIST)
+            .and_then(SdpValue::as_sequence)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|value| match *value {
+                SdpValue::Uuid(uuid) => Some(uuid),
+                _ => None,
+            })
+            .collect();
+
+        // Each protocol descriptor is a sequence of the protocol's UUID and its parameters
+        let channel = attribute(record, ATTR_PROTOCOL_DESCRIPTOR_LIST)
+            .and_then(SdpValue::as_sequence)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(SdpValue::as_sequence)
+            .find_map(|descriptor| match descriptor {
+                [SdpValue::Uuid(proto), SdpValue::Uint(channel), ..]
+                    if *proto == Uuid::from_u16(PROTO_RFCOMM) =>
+                {
+                    Some(*channel as u8)
+                }
+                _ => None,
+            });
+
+        // The language base list consists of (language, encoding, attribute base) triplets
+        let encoding = attribute(record, ATTR_LANGUAGE_BASE_ATTRIBUTE_ID_LIST)
+            .and_then(SdpValue::as_sequence)
+            .unwrap_or(&[])
+            .chunks(3)
+            .find(|triplet| {
+                triplet.get(2).and_then(SdpValue::as_uint)
+                    == Some(u128::from(PRIMARY_LANGUAGE_BASE))
+            })
+            .and_then(|triplet| triplet[1].as_uint())
+            .map_or(MIB_UTF_8, |encoding| encoding as u16);
+
+        let text = |offset: u16| match attribute(record, PRIMARY_LANGUAGE_BASE + offset) {
+            Some(SdpValue::Text(text)) => Some(decode_text(text, encoding)),
+            _ => None,
+        };
+
+        SdpService {
+            service_class_ids,
+            channel,
+            name: text(ATTR_OFFSET_SERVICE_NAME),
+            provider: text(ATTR_OFFSET_PROVIDER_NAME),
+        }
+    }
+}
+
+/// A service record of a remote device with all its attributes, as found through SDP.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SdpRecord {
+    attributes: AttributeList,
+}
+
+impl SdpRecord {
+    /// Parses the records of the raw response returned by `query_sdp_raw`.
+    pub fn parse_response(response: &[u8]) -> Result<Vec<SdpRecord>, BtError> {
+        Ok(parse_attribute_lists(response)?
+            .into_iter()
+            .map(|attributes| SdpRecord { attributes })
+            .collect())
+    }
+
+    /// Iterates over the ids and values of all attributes of the record, in ascending id order
+    /// (as sent by the device).
+    pub fn attributes(&self) -> impl Iterator<Item = (u16, &SdpValue)> {
+        self.attributes.iter().map(|(id, value)| (*id, value))
+    }
+
+    /// Returns the value of the attribute with the given id.
+    pub fn attribute(&self, id: u16) -> Option<&SdpValue> {
+        attribute(&self.attributes, id)
+    }
+
+    /// Extracts the well-known attributes of the record.
+    pub fn service(&self) -> SdpService {
+        SdpService::from_attributes(&self.attributes)
+    }
+}
+
+impl IntoIterator for SdpRecord {
+    type Item = (u16, SdpValue);
+    type IntoIter = std::vec::IntoIter<(u16, SdpValue)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.attributes.into_iter()
+    }
+}
+
+/// Extract the services from the attribute lists of a ServiceSearchAttribute response
+pub(crate) fn parse_services(response: &[u8]) -> Result<Vec<SdpService>, BtError> {
+    Ok(parse_attribute_lists(response)?
+        .iter()
+        .map(|record| SdpService::from_attributes(record))
+        .collect())
+}
+
+/// Map the service classes of the RFCOMM based `services` to their channels, sorted and without
+/// duplicates
+pub(crate) fn rfcomm_channels(services: &[SdpService]) -> BTreeMap<Uuid, Vec<u8>> {
+    let mut channels: BTreeMap<Uuid, Vec<u8>> = BTreeMap::new();
+    for service in services {
+        if let Some(channel) = service.channel {
+            for class in &service.service_class_ids {
+                channels.entry(*class).or_default().push(channel);
+            }
+        }
+    }
+    for class_channels in channels.values_mut() {
+        class_channels.sort_unstable();
+        class_channels.dedup();
+    }
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid_short_forms() {
+        let spp = Uuid::from_u16(0x1101);
+        assert_eq!(spp.to_string(), "00001101-0000-1000-8000-00805F9B34FB");
+        assert_eq!(spp.as_u16(), Some(0x1101));
+        assert_eq!(Uuid([0xAA; 16]).as_u16(), None);
+    }
+
+    #[test]
+    fn parses_service_record() {
+        #[rustfmt::skip]
+        let response = [
+            0x35, 0x3D, // attribute lists
+            0x35, 0x3B, // attribute list of one record
+            0x09, 0x00, 0x01, 0x35, 0x03, 0x19, 0x11, 0x01, // service class: serial port
+            0x09, 0x00, 0x04, 0x35, 0x0C, // protocol descriptors
+                0x35, 0x03, 0x19, 0x01, 0x00, // L2CAP
+                0x35, 0x05, 0x19, 0x00, 0x03, 0x08, 0x05, // RFCOMM channel 5
+            0x09, 0x00, 0x06, 0x35, 0x09, // language base: "en", ISO-8859-1, 0x0100
+                0x09, 0x65, 0x6E, 0x09, 0x00, 0x04, 0x09, 0x01, 0x00,
+            0x09, 0x01, 0x00, 0x25, 0x06, b'P', b'o', b'r', b't', 0xE9, 0x00, // name "Porté\0"
+            0x09, 0x01, 0x02, 0x25, 0x04, b'A', b'c', b'm', b'e', // provider "Acme"
+        ];
+
+        let services = parse_services(&response).unwrap();
+        assert_eq!(
+            services,
+            vec![SdpService {
+                service_class_ids: vec![Uuid::from_u16(0x1101)],
+                channel: Some(5),
+                name: Some("Porté".to_string()),
+                provider: Some("Acme".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn maps_classes_to_rfcomm_channels() {
+        let service = |classes: &[u16], channel| SdpService {
+            service_class_ids: classes.iter().map(|&class| Uuid::from_u16(class)).collect(),
+            channel,
+            name: None,
+            provider: None,
+        };
+        let services = [
+            service(&[0x1101], Some(5)),
+            service(&[0x111F, 0x1203], Some(3)),
+            service(&[0x1101], Some(2)),
+            service(&[0x1101], Some(5)),
+            service(&[0x1115], None),
+        ];
+
+        let channels = rfcomm_channels(&services);
+        assert_eq!(
+            channels.into_iter().collect::<Vec<_>>(),
+            vec![
+                (Uuid::from_u16(0x1101), vec![2, 5]),
+                (Uuid::from_u16(0x111F), vec![3]),
+                (Uuid::from_u16(0x1203), vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_large_responses() {
+        // More records than fit into one response PDU, in a sequence with a 32 bit length
+        #[rustfmt::skip]
+        let record = [
+            0x35, 0x14, // attribute list of one record
+            0x09, 0x00, 0x01, 0x35, 0x03, 0x19, 0x11, 0x01, // service class: serial port
+            0x09, 0x00, 0x04, 0x35, 0x07, // protocol descriptors
+                0x35, 0x05, 0x19, 0x00, 0x03, 0x08, 0x05, // RFCOMM channel 5
+        ];
+        let count = 5000;
+        let len = (record.len() * count) as u32;
+        let mut response = vec![0x37];
+        response.extend_from_slice(&len.to_be_bytes());
+        for _ in 0..count {
+            response.extend_from_slice(&record);
+        }
+
+        let services = parse_services(&response).unwrap();
+        assert_eq!(services.len(), count);
+        assert_eq!(services[count - 1].channel, Some(5));
+        assert!(parse_services(&response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn iterates_record_attributes() {
+        #[rustfmt::skip]
+        let response = [
+            0x35, 0x0F, // attribute lists
+            0x35, 0x0D, // attribute list of one record
+            0x09, 0x00, 0x01, 0x35, 0x03, 0x19, 0x11, 0x01, // service class: serial port
+            0x09, 0xFF, 0x01, 0x28, 0x01, // vendor specific: true
+        ];
+
+        let records = SdpRecord::parse_response(&response).unwrap();
+        assert_eq!(records.len(), 1);
+        let attributes: Vec<_> = records[0].attributes().collect();
+        assert_eq!(
+            attributes,
+            vec![
+                (
+                    0x0001,
+                    &SdpValue::Sequence(vec![SdpValue::Uuid(Uuid::from_u16(0x1101))])
+                ),
+                (0xFF01, &SdpValue::Bool(true)),
+            ]
+        );
+        assert_eq!(records[0].attribute(0xFF01), Some(&SdpValue::Bool(true)));
+    }
+
+    #[test]
+    fn rejects_truncated_data_element() {
+        assert!(SdpValue::parse(&[0x35, 0x05, 0x08, 0x01]).is_err());
+        assert!(SdpValue::parse(&[]).is_err());
+        // A signed integer without any bytes
+        assert!(SdpValue::parse(&[0x15, 0x00]).is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        // Sequences with a 16 bit length, each containing the next one
+        let nested = |depth: usize| {
+            let mut data = vec![0x08, 0x01];
+            for _ in 0..depth {
+                let len = data.len() as u16;
+                let mut outer = vec![0x36];
+                outer.extend_from_slice(&len.to_be_bytes());
+                outer.append(&mut data);
+                data = outer;
+            }
+            data
+        };
+        assert!(SdpValue::parse(&nested(MAX_NESTING)).is_ok());
+        assert!(SdpValue::parse(&nested(MAX_NESTING + 1)).is_err());
+        assert!(SdpValue::parse(&nested(10_000)).is_err());
+    }
+}
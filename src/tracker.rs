@@ -0,0 +1,117 @@
+//! Changes between repeated scans, see `DeviceTracker`.
+use crate::{
+    bluetooth::{BtAddr, BtDevice, BtError, ScanOptions},
+    platform,
+};
+
+use std::collections::BTreeMap;
+
+/// Keeps the devices found by the previous scan and reports the changes of each new one, e.g.
+/// for presence detection.
+///
+/// Devices are matched by address. A device whose name couldn't be looked up this time keeps the
+/// name it was found with before.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceTracker {
+    options: ScanOptions,
+    devices: BTreeMap<BtAddr, BtDevice>,
+}
+
+/// The changes between two scans, see `DeviceTracker::scan`. All lists are sorted by address.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScanDiff {
+    /// The devices found by this scan, but not by the previous one.
+    pub appeared: Vec<BtDevice>,
+
+    /// The devices found by the previous scan, but not by this one, as they were found then.
+    pub disappeared: Vec<BtDevice>,
+
+    /// All devices found by this scan, including the ones that appeared.
+    pub present: Vec<BtDevice>,
+}
+
+impl DeviceTracker {
+    /// Creates a tracker that hasn't seen any devices yet, whose scans use `options`.
+    pub fn new(options: ScanOptions) -> DeviceTracker {
+        DeviceTracker {
+            options,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Scans like `scan_devices_with` and returns the changes since the previous scan. The
+    /// first scan reports all devices as appeared.
+    ///
+    /// If the scan fails, the devices of the previous scan are kept.
+    ///
+    /// This function blocks for the duration of the scan.
+    pub fn scan(&mut self) -> Result<ScanDiff, BtError> {
+        Ok(self.update(platform::scan_devices_with(&self.options)?))
+    }
+
+    /// Returns the changes between the previous scan and `devices`, found by a scan done
+    /// elsewhere (e.g. `discover_devices`), and keeps them for the next comparison.
+    pub fn update(&mut self, devices: Vec<BtDevice>) -> ScanDiff {
+        let previous = std::mem::take(&mut self.devices);
+        let mut diff = ScanDiff::default();
+        for mut device in devices {
+            // Scans may report a device more than once
+            let known = self
+                .devices
+                .get(&device.addr)
+                .or_else(|| previous.get(&device.addr));
+            match known {
+                None => diff.appeared.push(device.clone()),
+                Some(known) if device.name == platform::UNKNOWN_NAME => {
+                    device.name = known.name.clone();
+                    device.name_raw = known.name_raw.clone();
+                }
+                Some(_) => {}
+            }
+            self.devices.insert(device.addr, device);
+        }
+
+        diff.appeared.sort_by_key(|device| device.addr);
+        diff.disappeared = previous
+            .into_values()
+            .filter(|device| !self.devices.contains_key(&device.addr))
+            .collect();
+        diff.present = self.devices.values().cloned().collect();
+        diff
+    }
+
+    /// Returns the devices found by the previous scan, sorted by address.
+    pub fn devices(&self) -> impl Iterator<Item = &BtDevice> {
+        self.devices.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_scans() {
+        let device =
+            |name: &str, last: u8| BtDevice::new(name.to_string(), BtAddr([0, 0, 0, 0, 0, last]));
+        let mut tracker = DeviceTracker::default();
+
+        let diff = tracker.update(vec![device("B", 2), device("A", 1), device("A", 1)]);
+        assert_eq!(diff.appeared, vec![device("A", 1), device("B", 2)]);
+        assert!(diff.disappeared.is_empty());
+        assert_eq!(diff.present, diff.appeared);
+
+        // C appears, B disappears, A is renamed or its name lookup failed
+        let unknown = device(platform::UNKNOWN_NAME, 1);
+        let diff = tracker.update(vec![unknown.clone(), device("C", 3), unknown]);
+        assert_eq!(diff.appeared, vec![device("C", 3)]);
+        assert_eq!(diff.disappeared, vec![device("B", 2)]);
+        assert_eq!(diff.present, vec![device("A", 1), device("C", 3)]);
+
+        let diff = tracker.update(vec![device("A2", 1)]);
+        assert!(diff.appeared.is_empty());
+        assert_eq!(diff.disappeared, vec![device("C", 3)]);
+        assert_eq!(diff.present, vec![device("A2", 1)]);
+        assert_eq!(tracker.devices().count(), 1);
+    }
+}